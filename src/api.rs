@@ -6,17 +6,26 @@ use std::future::Future;
 use std::pin::Pin;
 use tide::{log, Request};
 
-use crate::model::{Feed, FeedGroup, Group, Item, ModelExt};
+use crate::model::{Favicon, Feed, FeedGroup, Group, Item, ItemQuery, Link, ModelExt, User};
 use crate::state::State;
 use crate::utils::comma_join_vec;
 
 const API_VERSION: &'static str = "2";
 
+/// The user authenticated by `api_key`, or `None` for the administrator, who sees all feeds and
+/// the global read and saved state.
+fn current_user(request: &Request<State>) -> Option<User> {
+    request.ext::<User>().cloned()
+}
+
 fn handle_groups(request: Request<State>) -> Result<impl Into<tide::Response>, tide::Error> {
     log::info!("requesting groups");
     let (groups, feed_groups) = {
         let conn = request.state().db.get()?;
-        (Group::all(&conn)?, FeedGroup::all(&conn)?)
+        match current_user(&request) {
+            Some(user) => (user.groups(&conn)?, user.feed_groups(&conn)?),
+            None => (Group::all(&conn)?, FeedGroup::all(&conn)?),
+        }
     };
 
     Ok(json!({
@@ -31,7 +40,10 @@ fn handle_feeds(request: Request<State>) -> Result<impl Into<tide::Response>, ti
     log::info!("requesting feeds");
     let (feeds, feed_groups) = {
         let conn = request.state().db.get()?;
-        (Feed::all(&conn)?, FeedGroup::all(&conn)?)
+        match current_user(&request) {
+            Some(user) => (user.feeds(&conn)?, user.feed_groups(&conn)?),
+            None => (Feed::all(&conn)?, FeedGroup::all(&conn)?),
+        }
     };
 
     Ok(json!({
@@ -42,14 +54,31 @@ fn handle_feeds(request: Request<State>) -> Result<impl Into<tide::Response>, ti
     }))
 }
 
+fn handle_favicons(request: Request<State>) -> Result<impl Into<tide::Response>, tide::Error> {
+    log::info!("requesting favicons");
+    let favicons = {
+        let conn = request.state().db.get()?;
+        Favicon::all(&conn)?
+    };
+
+    Ok(json!({
+        "api_version": API_VERSION,
+        "auth": 1,
+        "favicons": favicons,
+    }))
+}
+
 fn handle_items(
     request: Request<State>,
-    since_id: Option<u32>,
+    query: ItemQuery,
 ) -> Result<impl Into<tide::Response>, tide::Error> {
-    log::info!("requesting items (since = {:?})", since_id);
+    log::info!("requesting items ({:?})", query);
     let (count, items) = {
         let conn = request.state().db.get()?;
-        (Item::count(&conn)?, Item::select(&conn, since_id, false)?)
+        match current_user(&request) {
+            Some(user) => (user.item_count(&conn)?, query.user(&user).fetch(&conn)?),
+            None => (Item::count(&conn)?, query.fetch(&conn)?),
+        }
     };
     Ok(json!({
         "api_version": API_VERSION,
@@ -59,13 +88,42 @@ fn handle_items(
     }))
 }
 
+fn handle_links(
+    request: Request<State>,
+    offset: u32,
+    range: u32,
+    page: u32,
+) -> Result<impl Into<tide::Response>, tide::Error> {
+    log::info!(
+        "requesting links (offset = {}, range = {}, page = {})",
+        offset,
+        range,
+        page
+    );
+    let links = {
+        let conn = request.state().db.get()?;
+        match current_user(&request) {
+            Some(user) => user.hot_links(&conn, offset, range, page)?,
+            None => Link::hot(&conn, offset, range, page)?,
+        }
+    };
+    Ok(json!({
+        "api_version": API_VERSION,
+        "auth": 1,
+        "links": links,
+    }))
+}
+
 fn handle_unread_item_ids(
     request: Request<State>,
 ) -> Result<impl Into<tide::Response>, tide::Error> {
     log::info!("requesting unread item ids");
     let item_ids = {
         let conn = request.state().db.get()?;
-        Item::unread(&conn)?
+        match current_user(&request) {
+            Some(user) => user.unread_ids(&conn)?,
+            None => Item::unread_ids(&conn)?,
+        }
     };
     Ok(json!({
         "api_version": API_VERSION,
@@ -80,7 +138,10 @@ fn handle_saved_item_ids(
     log::info!("requesting saved item ids");
     let item_ids = {
         let conn = request.state().db.get()?;
-        Item::saved(&conn)?
+        match current_user(&request) {
+            Some(user) => user.saved_ids(&conn)?,
+            None => Item::saved_ids(&conn)?,
+        }
     };
     Ok(json!({
         "api_version": API_VERSION,
@@ -94,6 +155,12 @@ fn handle_write_form(
     form: WriteForm,
 ) -> Result<impl Into<tide::Response>, tide::Error> {
     log::info!("writing form: {:?}", form);
+    if let Some(user) = current_user(&request) {
+        let conn = request.state().db.get()?;
+        write_user_form(&conn, &user, form)?;
+        return handle_ok(request);
+    }
+
     match form.mark {
         MarkType::Item => {
             let item = {
@@ -106,6 +173,9 @@ fn handle_write_form(
                 Action::Read => {
                     item.read(&conn)?;
                 }
+                Action::Unread => {
+                    item.unread(&conn)?;
+                }
                 Action::Saved => {
                     item.save(&conn)?;
                 }
@@ -131,17 +201,41 @@ fn handle_write_form(
                     }
                     handle_ok(request)
                 }
+                Action::Unread => {
+                    log::debug!("marking items in feed {} as unread", form.id);
+                    {
+                        let conn = request.state().db.get()?;
+                        feed.unread(&conn, form.before)?;
+                    }
+                    handle_ok(request)
+                }
             }
         }
-        MarkType::Group => {
-            if form.id == 0 {
-                // TODO: Kindling
-                bail!(400, "unimplemented");
-            } else if form.id == -1 {
-                // TODO: Sparks
-                bail!(400, "unimplemented");
-            };
+        MarkType::Group if form.id == 0 || form.id == -1 => {
+            // Group 0 is "Kindling" (all feeds but sparks), and group -1 is "Sparks".
+            let is_spark = form.id == -1;
 
+            match form.r#as {
+                Action::Saved | Action::Unsaved => bail!(400, "invalid request"),
+                Action::Read => {
+                    log::debug!("marking items in group {} as read", form.id);
+                    {
+                        let conn = request.state().db.get()?;
+                        Feed::read_by_spark(&conn, is_spark, form.before)?;
+                    }
+                    handle_ok(request)
+                }
+                Action::Unread => {
+                    log::debug!("marking items in group {} as unread", form.id);
+                    {
+                        let conn = request.state().db.get()?;
+                        Feed::unread_by_spark(&conn, is_spark, form.before)?;
+                    }
+                    handle_ok(request)
+                }
+            }
+        }
+        MarkType::Group => {
             let group = {
                 let conn = request.state().db.get()?;
                 Group::get(&conn, form.id as u32)?
@@ -157,11 +251,46 @@ fn handle_write_form(
                     }
                     handle_ok(request)
                 }
+                Action::Unread => {
+                    log::debug!("marking items in group {} as unread", form.id);
+                    {
+                        let conn = request.state().db.get()?;
+                        group.unread(&conn, form.before)?;
+                    }
+                    handle_ok(request)
+                }
             }
         }
     }
 }
 
+/// Applies a write form to the state of `user`.
+fn write_user_form(
+    conn: &rusqlite::Connection,
+    user: &User,
+    form: WriteForm,
+) -> Result<(), tide::Error> {
+    let id = form.id as u32;
+    match (form.mark, form.r#as) {
+        (MarkType::Item, Action::Read) => user.set_item_read(conn, id, true)?,
+        (MarkType::Item, Action::Unread) => user.set_item_read(conn, id, false)?,
+        (MarkType::Item, Action::Saved) => user.set_item_saved(conn, id, true)?,
+        (MarkType::Item, Action::Unsaved) => user.set_item_saved(conn, id, false)?,
+        (_, Action::Saved) | (_, Action::Unsaved) => bail!(400, "invalid request"),
+        (MarkType::Feed, action) => {
+            user.set_feed_read(conn, id, action == Action::Read, form.before)?
+        }
+        // Group 0 is "Kindling" (all feeds but sparks), and group -1 is "Sparks".
+        (MarkType::Group, action) if form.id == 0 || form.id == -1 => {
+            user.set_spark_read(conn, form.id == -1, action == Action::Read, form.before)?
+        }
+        (MarkType::Group, action) => {
+            user.set_group_read(conn, id, action == Action::Read, form.before)?
+        }
+    }
+    Ok(())
+}
+
 fn handle_ok(_request: Request<State>) -> Result<impl Into<tide::Response>, tide::Error> {
     Ok(json!({
         "api_version": API_VERSION,
@@ -177,10 +306,11 @@ enum MarkType {
     Group,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "lowercase")]
 enum Action {
     Read,
+    Unread,
     Saved,
     Unsaved,
 }
@@ -220,27 +350,40 @@ fn auth(
     next: tide::Next<'_, State>,
 ) -> Pin<Box<dyn Future<Output = tide::Result> + Send + '_>> {
     Box::pin(async move {
-        let credential = unwrap_or_return!(
-            request.state().credential.clone(),
-            Ok(next.run(request).await)
-        );
+        if !request.state().requires_auth()? {
+            return Ok(next.run(request).await);
+        }
+        let credential = request.state().credential.clone();
+
         let body = unwrap_or_return!(request.take_body().into_string().await.ok(), auth_error());
         // We need this to avoid taking away body from subsequent processing.
         request.set_body(body.clone());
 
         let auth = unwrap_or_return!(serde_urlencoded::from_str::<Auth>(&body).ok(), auth_error());
-        if auth.api_key == credential {
-            Ok(next.run(request).await)
-        } else {
-            auth_error()
+        if Some(&auth.api_key) == credential.as_ref() {
+            return Ok(next.run(request).await);
+        }
+
+        let user = {
+            let conn = request.state().db.get()?;
+            User::get_by_api_key(&conn, &auth.api_key)?
+        };
+        match user {
+            Some(user) => {
+                request.set_ext(user);
+                Ok(next.run(request).await)
+            }
+            None => auth_error(),
         }
     })
 }
 
 pub fn make_app(state: State) -> tide::Server<State> {
     let mut app = tide::with_state(state);
-    app.with(auth)
-        .at("/")
+    crate::reader::mount(&mut app);
+    crate::rest::mount(&mut app);
+    app.at("/")
+        .with(auth)
         .get(|mut request: Request<State>| async move {
             let _ = request.body_string().await;
             Ok("")
@@ -253,9 +396,36 @@ pub fn make_app(state: State) -> tide::Server<State> {
                 handle_groups(request)?.into()
             } else if query.contains_key("feeds") {
                 handle_feeds(request)?.into()
+            } else if query.contains_key("favicons") {
+                handle_favicons(request)?.into()
             } else if query.contains_key("items") {
-                let since_id = query.get("since_id").and_then(|x| x.parse().ok());
-                handle_items(request, since_id)?.into()
+                let mut item_query = ItemQuery::new();
+                if let Some(since_id) = query.get("since_id").and_then(|x| x.parse().ok()) {
+                    item_query = item_query.since_id(since_id);
+                }
+                if let Some(max_id) = query.get("max_id").and_then(|x| x.parse().ok()) {
+                    item_query = item_query.max_id(max_id);
+                }
+                if let Some(with_ids) = query.get("with_ids") {
+                    item_query = item_query.with_ids(
+                        with_ids
+                            .split(',')
+                            .filter_map(|x| x.trim().parse().ok())
+                            .collect(),
+                    );
+                }
+                handle_items(request, item_query)?.into()
+            } else if query.contains_key("links") {
+                let offset = query.get("offset").and_then(|x| x.parse().ok());
+                let range = query.get("range").and_then(|x| x.parse().ok());
+                let page = query.get("page").and_then(|x| x.parse().ok());
+                handle_links(
+                    request,
+                    offset.unwrap_or(0),
+                    range.unwrap_or(7),
+                    page.unwrap_or(1),
+                )?
+                .into()
             } else if query.contains_key("unread_item_ids") {
                 handle_unread_item_ids(request)?.into()
             } else if query.contains_key("saved_item_ids") {
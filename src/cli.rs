@@ -1,14 +1,19 @@
 use anyhow::{anyhow, Context, Result};
 use async_std::prelude::FutureExt;
+use chrono::{DateTime, Utc};
 use either::Either;
-use futures::stream::{self, StreamExt};
-use log::{info, warn};
+use log::warn;
 use prettytable::{cell, format, row, Table};
 use std::io::{self, BufRead, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
-use crate::model::{Feed, FeedGroup, Group, Item, ModelExt};
+use crate::client::RequestHeaders;
+use crate::config::{self, Config};
+use crate::migration;
+use crate::model::{
+    Feed, FeedGroup, Group, ItemSearch, ModelExt, Retention, Rule, RuleAction, RuleField, User,
+};
 use crate::opml;
 use crate::remote::RemoteFeed;
 use crate::state::State;
@@ -16,7 +21,11 @@ use crate::state::State;
 #[derive(Debug, StructOpt)]
 pub enum FeedCommand {
     /// Lists all feeds
-    List,
+    List {
+        /// Only lists feeds that failed to crawl, along with the errors
+        #[structopt(long)]
+        errors: bool,
+    },
 
     /// Adds a new feed
     Add {
@@ -32,11 +41,92 @@ pub enum FeedCommand {
     Crawl { id: u32 },
 
     /// Imports OPML file
-    Import { file: PathBuf },
+    Import {
+        file: PathBuf,
+        /// Maps nested outlines to groups: `flatten` (`Tech/Rust`), `top` (`Tech`) or `leaf`
+        /// (`Rust`)
+        #[structopt(long = "nesting", default_value = "flatten")]
+        nesting: opml::Nesting,
+        /// Prints what would be imported without changing the database
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+        /// Fetches missing titles and site URLs in dry run
+        #[structopt(long = "fetch", requires = "dry-run")]
+        fetch: bool,
+    },
+
+    /// Exports feeds as OPML file, or to stdout when file is not specified
+    Export { file: Option<PathBuf> },
+
+    /// Sets crawl interval of a feed
+    SetInterval {
+        id: u32,
+        /// Crawl interval (unit: minutes), or `default` to follow the server
+        interval: CrawlInterval,
+    },
+
+    /// Changes settings of a feed
+    Set {
+        id: u32,
+        #[structopt(flatten)]
+        settings: FeedSettings,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct FeedSettings {
+    /// Updates stored items when their content changes in the feed
+    #[structopt(long = "update-items")]
+    update_items: bool,
+    /// Keeps stored items as first seen
+    #[structopt(long = "no-update-items", conflicts_with = "update-items")]
+    no_update_items: bool,
+    /// Fetches the full content of new items from their web pages
+    #[structopt(long = "full-content")]
+    full_content: bool,
+    /// Stores new items with the content found in the feed
+    #[structopt(long = "no-full-content", conflicts_with = "full-content")]
+    no_full_content: bool,
+    /// Replaces the `User-Agent` sent when crawling the feed
+    #[structopt(long = "user-agent")]
+    user_agent: Option<String>,
+    /// Sends the default `User-Agent` when crawling the feed
+    #[structopt(long = "no-user-agent", conflicts_with = "user-agent")]
+    no_user_agent: bool,
+    /// Adds a header sent when crawling the feed, as `Name: Value`. Can be repeated
+    #[structopt(long = "header", number_of_values = 1)]
+    headers: Vec<String>,
+    /// Removes the headers previously added to the feed
+    #[structopt(long = "clear-headers")]
+    clear_headers: bool,
+}
+
+#[derive(Debug)]
+pub enum CrawlInterval {
+    Default,
+    Minutes(u32),
+}
+
+impl std::str::FromStr for CrawlInterval {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "default" => Ok(Self::Default),
+            _ => match s.parse()? {
+                0 => Err(anyhow!("Crawl interval must be positive")),
+                minutes => Ok(Self::Minutes(minutes)),
+            },
+        }
+    }
 }
 
 impl FeedCommand {
-    fn list(state: State) -> Result<()> {
+    fn list(state: State, errors: bool) -> Result<()> {
+        if errors {
+            return Self::list_errors(state);
+        }
+
         let feeds = {
             let conn = state.db.get()?;
             Feed::all(&conn)?
@@ -53,6 +143,33 @@ impl FeedCommand {
         Ok(())
     }
 
+    fn list_errors(state: State) -> Result<()> {
+        let feeds = {
+            let conn = state.db.get()?;
+            Feed::all_failing(&conn)?
+        };
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.set_titles(row!["id", "name", "errors", "next crawl", "last error"]);
+
+        for feed in feeds.into_iter() {
+            let next_crawl_at = feed
+                .next_crawl_at
+                .map(|at| at.to_rfc3339())
+                .unwrap_or_default();
+            table.add_row(row![
+                feed.id,
+                feed.title,
+                feed.error_count,
+                next_crawl_at,
+                feed.last_error.unwrap_or_default()
+            ]);
+        }
+
+        table.printstd();
+        Ok(())
+    }
+
     async fn select_remotes(state: &State, candidates: Vec<String>) -> Result<RemoteFeed> {
         if candidates.is_empty() {
             return Err(anyhow!(
@@ -164,10 +281,7 @@ impl FeedCommand {
 
     fn delete(state: State, id: u32) -> Result<()> {
         let conn = state.db.get()?;
-        let feed = Feed::get(&conn, id)?;
-        FeedGroup::delete_by_feed(&conn, feed.id)?;
-        Item::delete_by_feed(&conn, feed.id)?;
-        let feed = feed.delete(&conn)?;
+        let feed = Feed::get(&conn, id)?.remove(&conn)?;
         println!("Feed deleted!\n{}", feed);
         Ok(())
     }
@@ -178,85 +292,244 @@ impl FeedCommand {
             Feed::get(&conn, id)?
         };
 
-        feed.crawl(state).await?;
+        crate::crawler::Crawler::new(state).crawl_feed(feed).await?;
         Ok(())
     }
 
-    async fn import(state: State, file: PathBuf) -> Result<()> {
-        let imports = opml::from_file(&file)?;
-
-        let imports: Vec<_> = stream::iter(imports)
-            .then(|(group, feeds)| async move {
-                // normalize feeds
-                let feeds = stream::iter(feeds)
-                    .filter_map(|mut feed| async move {
-                        if let Err(e) = feed.update().await {
-                            warn!("failed to update feed {}: {:?}", feed, e);
-                        }
-
-                        if let Err(e) = feed.validate() {
-                            warn!("invalid feed ({}): {:?}", feed, e);
-                            None
-                        } else {
-                            Some(feed)
-                        }
-                    })
-                    .map(Feed::from)
-                    .collect::<Vec<Feed>>()
-                    .await;
-
-                (group, feeds)
-            })
-            .collect()
-            .await;
+    /// Previews an import. The database is only read, and is not created when it is missing.
+    async fn import_dry_run(
+        database: &Path,
+        file: PathBuf,
+        nesting: opml::Nesting,
+        fetch: bool,
+    ) -> Result<()> {
+        let mut imports = opml::from_file(&file, nesting)?;
+
+        if fetch {
+            for feed in imports.iter_mut() {
+                if let Err(e) = feed.update().await {
+                    warn!("failed to update feed {}: {:?}", feed, e);
+                }
+            }
+        }
 
-        let conn = state.db.get()?;
-        for (group, feeds) in imports.into_iter() {
-            let group = group.and_then(|title| {
-                if let Ok(group) = Group::get_by_name(&conn, &title) {
-                    Some(group)
-                } else {
-                    let group = Group::new(title.clone());
-                    match group.insert(&conn) {
-                        Ok(group) => Some(group),
-                        Err(e) => {
-                            warn!("unable to create group {}: {:?}", title, e);
-                            None
-                        }
-                    }
+        let pool = if database.exists() {
+            Some(crate::model::open_pool(database)?)
+        } else {
+            None
+        };
+        let conn = match pool.as_ref() {
+            Some(pool) => {
+                let conn = pool.get()?;
+                if !migration::pending(&conn)?.is_empty() {
+                    return Err(anyhow!(
+                        "Database schema is outdated, run `lares db migrate` first"
+                    ));
                 }
-            });
+                Some(conn)
+            }
+            None => None,
+        };
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.set_titles(row!["action", "feed url", "name", "groups"]);
 
-            for feed in feeds {
-                let feed = match feed.insert(&conn) {
-                    Err(e) => {
-                        warn!("unable to create feed: {:?}", e);
-                        continue;
-                    }
-                    Ok(feed) => feed,
-                };
+        let (mut added, mut skipped, mut regrouped, mut invalid) = (0, 0, 0, 0);
+        for feed in imports.iter() {
+            let title = feed.title().unwrap_or_default();
+            let groups = feed.groups().join(", ");
 
-                if let Some(group) = group.as_ref() {
-                    if let Err(e) = group.add_feed(&conn, feed) {
-                        warn!("unable to add feed to group {:?}: {:?}", group, e);
-                        continue;
-                    }
+            if let Err(e) = feed.validate() {
+                invalid += 1;
+                table.add_row(row!["invalid", feed.url(), title, e]);
+                continue;
+            }
+
+            let existing = match conn.as_ref() {
+                Some(conn) => Feed::get_by_url(conn, feed.url())?.map(|existing| (conn, existing)),
+                None => None,
+            };
+            let (conn, existing) = match existing {
+                Some(existing) => existing,
+                None => {
+                    added += 1;
+                    table.add_row(row!["add", feed.url(), title, groups]);
+                    continue;
                 }
+            };
+
+            let current = Group::get_by_feed(conn, existing.id)?
+                .into_iter()
+                .map(|group| group.title)
+                .collect::<Vec<_>>();
+            let new_groups = feed
+                .groups()
+                .iter()
+                .filter(|&group| !current.contains(group))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if new_groups.is_empty() {
+                skipped += 1;
+                table.add_row(row!["skip", feed.url(), existing.title, groups]);
+            } else {
+                regrouped += 1;
+                table.add_row(row![
+                    "regroup",
+                    feed.url(),
+                    existing.title,
+                    format!("+ {}", new_groups.join(", "))
+                ]);
+            }
+        }
+
+        table.printstd();
+        println!(
+            "\n{} to add, {} to skip, {} to regroup, {} invalid.",
+            added, skipped, regrouped, invalid
+        );
+        Ok(())
+    }
+
+    async fn import(state: State, file: PathBuf, nesting: opml::Nesting) -> Result<()> {
+        let imports = opml::normalize(opml::from_file(&file, nesting)?).await;
+
+        let conn = state.db.get()?;
+        opml::import(&conn, imports)?;
+        Ok(())
+    }
+
+    fn export(state: State, file: Option<PathBuf>) -> Result<()> {
+        let conn = state.db.get()?;
+        match file {
+            Some(file) => {
+                let output = std::fs::File::create(&file)
+                    .with_context(|| anyhow!("Unable to create file {}", file.display()))?;
+                opml::export(&conn, io::BufWriter::new(output))?;
             }
+            None => opml::export(&conn, io::stdout().lock())?,
         }
 
-        info!("import completed.");
+        Ok(())
+    }
+
+    fn set_interval(state: State, id: u32, interval: CrawlInterval) -> Result<()> {
+        let conn = state.db.get()?;
+        let feed = Feed::get(&conn, id)?;
+        match interval {
+            CrawlInterval::Default => {
+                Feed::set_crawl_interval(&conn, feed.id, None)?;
+                println!(
+                    "Feed '{}' will be crawled at the default interval.",
+                    feed.title
+                );
+            }
+            CrawlInterval::Minutes(minutes) => {
+                Feed::set_crawl_interval(&conn, feed.id, Some(minutes))?;
+                println!(
+                    "Feed '{}' will be crawled every {} minutes.",
+                    feed.title, minutes
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn set(state: State, id: u32, settings: FeedSettings) -> Result<()> {
+        let FeedSettings {
+            update_items,
+            no_update_items,
+            full_content,
+            no_full_content,
+            user_agent,
+            no_user_agent,
+            headers,
+            clear_headers,
+        } = settings;
+        if !update_items
+            && !no_update_items
+            && !full_content
+            && !no_full_content
+            && user_agent.is_none()
+            && !no_user_agent
+            && headers.is_empty()
+            && !clear_headers
+        {
+            return Err(anyhow!("Nothing to set, see `lares feed set --help`"));
+        }
+
+        let conn = state.db.get()?;
+        let feed = Feed::get(&conn, id)?;
+        if update_items || no_update_items {
+            Feed::set_update_items(&conn, feed.id, update_items)?;
+            if update_items {
+                println!(
+                    "Items of feed '{}' will be updated when they change.",
+                    feed.title
+                );
+            } else {
+                println!("Items of feed '{}' will be kept as first seen.", feed.title);
+            }
+        }
+
+        if full_content || no_full_content {
+            Feed::set_full_content(&conn, feed.id, full_content)?;
+            if full_content {
+                println!(
+                    "Full content of new items of feed '{}' will be fetched.",
+                    feed.title
+                );
+            } else {
+                println!(
+                    "New items of feed '{}' will be stored as found in the feed.",
+                    feed.title
+                );
+            }
+        }
+
+        if let Some(user_agent) = user_agent.as_ref() {
+            Feed::set_user_agent(&conn, feed.id, Some(user_agent))?;
+            println!("Feed '{}' will be crawled as '{}'.", feed.title, user_agent);
+        } else if no_user_agent {
+            Feed::set_user_agent(&conn, feed.id, None)?;
+            println!(
+                "Feed '{}' will be crawled with the default user agent.",
+                feed.title
+            );
+        }
 
+        if clear_headers || !headers.is_empty() {
+            let mut lines = Vec::new();
+            if !clear_headers {
+                lines.extend(feed.headers.iter().cloned());
+            }
+            lines.extend(headers);
+            let lines = lines.join("\n");
+            Feed::set_headers(
+                &conn,
+                feed.id,
+                Some(lines.as_str()).filter(|lines| !lines.is_empty()),
+            )
+            .with_context(|| format!("Unable to set headers of feed '{}'", feed.title))?;
+            let count = RequestHeaders::parse_extra(&lines)?.len();
+            println!(
+                "Feed '{}' will be crawled with {} extra header(s).",
+                feed.title, count
+            );
+        }
         Ok(())
     }
 
     async fn run(self, state: State) -> Result<()> {
         match self {
-            Self::List => Self::list(state),
+            Self::List { errors } => Self::list(state, errors),
             Self::Add { url, group } => Self::add(state, url, group).await,
             Self::Delete { id } => Self::delete(state, id),
             Self::Crawl { id } => Self::crawl(state, id).await,
-            Self::Import { file } => Self::import(state, file).await,
+            Self::Import { file, nesting, .. } => Self::import(state, file, nesting).await,
+            Self::Export { file } => Self::export(state, file),
+            Self::SetInterval { id, interval } => Self::set_interval(state, id, interval),
+            Self::Set { id, settings } => Self::set(state, id, settings),
         }
     }
 }
@@ -356,47 +629,671 @@ impl GroupCommand {
     }
 }
 
+fn parse_date(s: &str) -> Result<DateTime<Utc>> {
+    crate::utils::parse_date(s)
+        .ok_or_else(|| anyhow!("Invalid date '{}' (expected YYYY-MM-DD or RFC 3339)", s))
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ItemCommand {
+    /// Searches titles, authors and contents of items
+    Search {
+        /// Words that items must contain; append `*` to match prefixes
+        query: String,
+        /// Only searches items of this feed
+        #[structopt(long = "feed")]
+        feed: Option<u32>,
+        /// Only searches items of feeds in this group
+        #[structopt(short = "g", long = "group")]
+        group: Option<String>,
+        /// Only searches items published on or after this date
+        #[structopt(long = "since", parse(try_from_str = parse_date))]
+        since: Option<DateTime<Utc>>,
+        /// Only searches items published before this date
+        #[structopt(long = "until", parse(try_from_str = parse_date))]
+        until: Option<DateTime<Utc>>,
+        /// Specifies maximum number of results
+        #[structopt(short = "n", long = "limit", default_value = "20")]
+        limit: u32,
+    },
+}
+
+impl ItemCommand {
+    fn search(
+        state: State,
+        query: String,
+        feed: Option<u32>,
+        group: Option<String>,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        limit: u32,
+    ) -> Result<()> {
+        let conn = state.db.get()?;
+        let mut search = ItemSearch::new(&query).limit(limit);
+        if let Some(feed) = feed {
+            search = search.feed_id(feed);
+        }
+        if let Some(group) = group {
+            let group = Group::get_by_name(&conn, &group)
+                .with_context(|| anyhow!("Unable to find group '{}'", group))?;
+            search = search.group_id(group.id);
+        }
+        if let Some(since) = since {
+            search = search.since(since);
+        }
+        if let Some(until) = until {
+            search = search.until(until);
+        }
+
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.set_titles(row!["id", "feed", "date", "title", "excerpt"]);
+
+        for hit in search.fetch(&conn)?.into_iter() {
+            table.add_row(row![
+                hit.item.id,
+                hit.item.feed_id,
+                hit.item.created_on_time.format("%Y-%m-%d"),
+                hit.item.title,
+                hit.snippet
+            ]);
+        }
+
+        table.printstd();
+        Ok(())
+    }
+
+    async fn run(self, state: State) -> Result<()> {
+        match self {
+            Self::Search {
+                query,
+                feed,
+                group,
+                since,
+                until,
+                limit,
+            } => Self::search(state, query, feed, group, since, until, limit),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum RuleCommand {
+    /// Lists all rules
+    List,
+
+    /// Adds a rule applied to new items
+    Add {
+        /// Only applies to items of this feed
+        #[structopt(long = "feed")]
+        feed: Option<u32>,
+        /// Only applies to items of feeds in this group
+        #[structopt(short = "g", long = "group")]
+        group: Option<String>,
+        /// Part of items to match: title, author, url, content or any
+        #[structopt(long = "field", default_value = "any")]
+        field: RuleField,
+        /// Matches the pattern as a regular expression instead of a case-insensitive substring
+        #[structopt(long = "regex")]
+        regex: bool,
+        /// What to do with matching items: read, save, drop or tag:<name>
+        action: RuleAction,
+        pattern: String,
+    },
+
+    /// Deletes a rule
+    Delete { id: u32 },
+}
+
+impl RuleCommand {
+    fn list(state: State) -> Result<()> {
+        let (rules, groups) = {
+            let conn = state.db.get()?;
+            (Rule::all(&conn)?, Group::all(&conn)?)
+        };
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.set_titles(row![
+            "id", "feed", "group", "field", "pattern", "regex", "action"
+        ]);
+
+        for rule in rules.into_iter() {
+            let group = rule
+                .group_id
+                .and_then(|id| groups.iter().find(|group| group.id == id))
+                .map(|group| group.title.clone());
+            table.add_row(row![
+                rule.id,
+                rule.feed_id.map(|id| id.to_string()).unwrap_or_default(),
+                group.unwrap_or_default(),
+                rule.field.as_str(),
+                rule.pattern,
+                rule.is_regex,
+                rule.action
+            ]);
+        }
+
+        table.printstd();
+        Ok(())
+    }
+
+    fn add(
+        state: State,
+        feed: Option<u32>,
+        group: Option<String>,
+        field: RuleField,
+        regex: bool,
+        action: RuleAction,
+        pattern: String,
+    ) -> Result<()> {
+        let conn = state.db.get()?;
+        let mut rule = Rule::new(field, pattern, regex, action);
+        if let Some(feed_id) = feed {
+            Feed::get(&conn, feed_id)
+                .with_context(|| anyhow!("Unable to find feed with id = {}", feed_id))?;
+            rule = rule.feed_id(feed_id);
+        }
+        if let Some(group) = group {
+            let group = Group::get_by_name(&conn, &group)
+                .with_context(|| anyhow!("Unable to find group '{}'", group))?;
+            rule = rule.group_id(group.id);
+        }
+        let rule = rule
+            .insert(&conn)
+            .with_context(|| anyhow!("Unable to create rule"))?;
+        println!("Rule {} added.", rule.id);
+        Ok(())
+    }
+
+    fn delete(state: State, id: u32) -> Result<()> {
+        let conn = state.db.get()?;
+        let rule = Rule::get(&conn, id)
+            .with_context(|| anyhow!("Unable to find rule with id = {}", id))?;
+        rule.delete(&conn)?;
+        println!("Rule {} deleted", id);
+        Ok(())
+    }
+
+    async fn run(self, state: State) -> Result<()> {
+        match self {
+            Self::List => Self::list(state),
+            Self::Add {
+                feed,
+                group,
+                field,
+                regex,
+                action,
+                pattern,
+            } => Self::add(state, feed, group, field, regex, action, pattern),
+            Self::Delete { id } => Self::delete(state, id),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum UserCommand {
+    /// Lists all users
+    List,
+
+    /// Adds a user of the Fever API
+    Add {
+        username: String,
+        /// Reads the password from stdin if omitted
+        #[structopt(long = "password")]
+        password: Option<String>,
+    },
+
+    /// Deletes a user along with its subscriptions and read state
+    Delete { username: String },
+
+    /// Changes the password of a user
+    Passwd {
+        username: String,
+        /// Reads the password from stdin if omitted
+        #[structopt(long = "password")]
+        password: Option<String>,
+    },
+
+    /// Subscribes a user to feeds
+    Subscribe {
+        username: String,
+        /// Subscribes to all feeds of this group
+        #[structopt(short = "g", long = "group")]
+        group: Option<String>,
+        feed_ids: Vec<u32>,
+    },
+
+    /// Unsubscribes a user from feeds
+    Unsubscribe {
+        username: String,
+        feed_ids: Vec<u32>,
+    },
+}
+
+impl UserCommand {
+    fn read_password(password: Option<String>) -> Result<String> {
+        if let Some(password) = password {
+            return Ok(password);
+        }
+
+        print!("password: ");
+        io::stdout().flush()?;
+        let mut password = String::new();
+        io::stdin().lock().read_line(&mut password)?;
+        let password = password.trim_end_matches(&['\r', '\n'][..]).to_owned();
+        if password.is_empty() {
+            return Err(anyhow!("Password must not be empty"));
+        }
+        Ok(password)
+    }
+
+    fn get_user(conn: &rusqlite::Connection, username: &str) -> Result<User> {
+        User::get_by_name(conn, username)
+            .with_context(|| anyhow!("Unable to find user '{}'", username))
+    }
+
+    fn list(state: State) -> Result<()> {
+        let conn = state.db.get()?;
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.set_titles(row!["id", "username", "feeds", "created"]);
+
+        for user in User::all(&conn)?.into_iter() {
+            table.add_row(row![
+                user.id,
+                user.username,
+                user.feeds(&conn)?.len(),
+                user.created.format("%Y-%m-%d")
+            ]);
+        }
+
+        table.printstd();
+        Ok(())
+    }
+
+    fn add(state: State, username: String, password: Option<String>) -> Result<()> {
+        let password = Self::read_password(password)?;
+        let conn = state.db.get()?;
+        User::new(username.clone(), &password)
+            .insert(&conn)
+            .with_context(|| anyhow!("Unable to create user '{}'.", username))?;
+        println!("User '{}' added.", username);
+        Ok(())
+    }
+
+    fn delete(state: State, username: String) -> Result<()> {
+        let conn = state.db.get()?;
+        let user = Self::get_user(&conn, &username)?;
+        user.remove(&conn)?;
+        println!("User '{}' deleted", username);
+        Ok(())
+    }
+
+    fn passwd(state: State, username: String, password: Option<String>) -> Result<()> {
+        let conn = state.db.get()?;
+        let mut user = Self::get_user(&conn, &username)?;
+        let password = Self::read_password(password)?;
+        user.set_password(&conn, &password)?;
+        println!("Password of '{}' changed.", username);
+        Ok(())
+    }
+
+    fn subscribe(
+        state: State,
+        username: String,
+        group: Option<String>,
+        mut feed_ids: Vec<u32>,
+    ) -> Result<()> {
+        let conn = state.db.get()?;
+        let user = Self::get_user(&conn, &username)?;
+        if let Some(group) = group {
+            let group = Group::get_by_name(&conn, &group)
+                .with_context(|| anyhow!("Unable to find group '{}'", group))?;
+            feed_ids.extend(group.get_feeds(&conn)?.into_iter().map(|feed| feed.id));
+        }
+        if feed_ids.is_empty() {
+            return Err(anyhow!("No feeds to subscribe to"));
+        }
+
+        for feed_id in feed_ids.iter() {
+            Feed::get(&conn, *feed_id)
+                .with_context(|| anyhow!("Unable to find feed with id = {}", feed_id))?;
+            user.subscribe(&conn, *feed_id)?;
+        }
+        println!("Subscribed '{}' to {} feed(s).", username, feed_ids.len());
+        Ok(())
+    }
+
+    fn unsubscribe(state: State, username: String, feed_ids: Vec<u32>) -> Result<()> {
+        let conn = state.db.get()?;
+        let user = Self::get_user(&conn, &username)?;
+        for feed_id in feed_ids.iter() {
+            user.unsubscribe(&conn, *feed_id)?;
+        }
+        println!(
+            "Unsubscribed '{}' from {} feed(s).",
+            username,
+            feed_ids.len()
+        );
+        Ok(())
+    }
+
+    async fn run(self, state: State) -> Result<()> {
+        match self {
+            Self::List => Self::list(state),
+            Self::Add { username, password } => Self::add(state, username, password),
+            Self::Delete { username } => Self::delete(state, username),
+            Self::Passwd { username, password } => Self::passwd(state, username, password),
+            Self::Subscribe {
+                username,
+                group,
+                feed_ids,
+            } => Self::subscribe(state, username, group, feed_ids),
+            Self::Unsubscribe { username, feed_ids } => {
+                Self::unsubscribe(state, username, feed_ids)
+            }
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RetentionConfig {
+    #[structopt(long = "keep-read-days", env = "LARES_KEEP_READ_DAYS")]
+    /// Deletes read items published more than this many days ago. Saved items are never deleted
+    keep_read_days: Option<u32>,
+
+    #[structopt(long = "keep-per-feed", env = "LARES_KEEP_PER_FEED")]
+    /// Deletes all but this many most recent items of each feed. Saved items are never deleted
+    keep_per_feed: Option<u32>,
+}
+
+impl RetentionConfig {
+    /// Overrides the retention rules of the configuration file.
+    fn apply(&self, database: &mut config::Database) {
+        if self.keep_read_days.is_some() {
+            database.keep_read_days = self.keep_read_days;
+        }
+        if self.keep_per_feed.is_some() {
+            database.keep_per_feed = self.keep_per_feed;
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct HttpConfig {
+    #[structopt(long = "connect-timeout", env = "LARES_CONNECT_TIMEOUT")]
+    /// Specifies maximum time to establish a connection (unit: seconds, 0 to disable) [default: 10]
+    connect_timeout: Option<u64>,
+
+    #[structopt(long = "read-timeout", env = "LARES_READ_TIMEOUT")]
+    /// Specifies maximum time waiting for a server to send data (unit: seconds, 0 to disable)
+    /// [default: 30]
+    read_timeout: Option<u64>,
+
+    #[structopt(long = "proxy", env = "LARES_PROXY")]
+    /// Sends requests through an HTTP, HTTPS or SOCKS proxy, e.g. `socks5h://127.0.0.1:1080`
+    proxy: Option<String>,
+
+    #[structopt(long = "user-agent", env = "LARES_USER_AGENT")]
+    /// Replaces the default `User-Agent` of requests
+    user_agent: Option<String>,
+
+    #[structopt(long = "max-body-size", env = "LARES_MAX_BODY_SIZE")]
+    /// Rejects responses larger than this (unit: bytes) [default: 16777216]
+    max_body_size: Option<u64>,
+}
+
+impl HttpConfig {
+    /// Overrides the HTTP client settings of the configuration file.
+    fn apply(self, http: &mut config::Http) -> Result<()> {
+        if let Some(connect_timeout) = self.connect_timeout {
+            http.connect_timeout = connect_timeout;
+        }
+        if let Some(read_timeout) = self.read_timeout {
+            http.read_timeout = read_timeout;
+        }
+        if let Some(proxy) = self.proxy {
+            proxy
+                .parse::<http::Uri>()
+                .with_context(|| format!("Invalid proxy '{}'", proxy))?;
+            http.proxy = Some(proxy);
+        }
+        if self.user_agent.is_some() {
+            http.user_agent = self.user_agent;
+        }
+        if let Some(max_body_size) = self.max_body_size {
+            http.max_body_size = max_body_size;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum DbCommand {
+    /// Migrates database schema to the latest version
+    Migrate,
+
+    /// Prints schema version and pending migrations
+    Status,
+
+    /// Deletes expired items
+    Prune {
+        #[structopt(flatten)]
+        retention: RetentionConfig,
+        /// Prints how many items would be deleted without changing the database
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+        /// Reclaims unused space after pruning
+        #[structopt(long = "vacuum", conflicts_with = "dry-run")]
+        vacuum: bool,
+    },
+}
+
+impl DbCommand {
+    fn migrate(state: State) -> Result<()> {
+        let conn = state.db.get()?;
+        let applied = migration::migrate(&conn)?;
+
+        if applied.is_empty() {
+            println!("Database is up to date.");
+        }
+        for migration in applied.iter() {
+            println!(
+                "Migrated to version {}: {}",
+                migration.version, migration.description
+            );
+        }
+        Ok(())
+    }
+
+    fn status(state: State) -> Result<()> {
+        let conn = state.db.get()?;
+        let version = migration::current_version(&conn)?;
+        println!("Database version: {}", version);
+        println!("Latest version: {}", migration::latest_version());
+
+        let pending = migration::pending(&conn)?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.set_titles(row!["version", "pending migration"]);
+
+        for migration in pending.into_iter() {
+            table.add_row(row![migration.version, migration.description]);
+        }
+
+        table.printstd();
+        Ok(())
+    }
+
+    fn prune(state: State, retention: Retention, dry_run: bool, vacuum: bool) -> Result<()> {
+        if retention.is_empty() {
+            return Err(anyhow!(
+                "No retention rule given, specify --keep-read-days or --keep-per-feed"
+            ));
+        }
+
+        let conn = state.db.get()?;
+        if !migration::pending(&conn)?.is_empty() {
+            return Err(anyhow!(
+                "Database is not up to date, run `lares db migrate` first"
+            ));
+        }
+
+        if dry_run {
+            let mut expired: Vec<(u32, usize)> = Vec::new();
+            for (feed_id, _) in retention.expired(&conn, Utc::now())? {
+                match expired.last_mut() {
+                    Some((last, count)) if *last == feed_id => *count += 1,
+                    _ => expired.push((feed_id, 1)),
+                }
+            }
+
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+            table.set_titles(row!["id", "name", "items to delete"]);
+
+            let mut total = 0;
+            for (feed_id, count) in expired.into_iter() {
+                let title = Feed::get(&conn, feed_id)
+                    .map(|feed| feed.title)
+                    .unwrap_or_default();
+                table.add_row(row![feed_id, title, count]);
+                total += count;
+            }
+
+            table.printstd();
+            println!("\n{} items would be deleted.", total);
+            return Ok(());
+        }
+
+        let deleted = retention.prune(&conn, Utc::now())?;
+        println!("Deleted {} items.", deleted);
+
+        if vacuum {
+            conn.execute_batch("VACUUM")?;
+            println!("Database vacuumed.");
+        }
+        Ok(())
+    }
+
+    async fn run(self, state: State, mut database: config::Database) -> Result<()> {
+        match self {
+            Self::Migrate => Self::migrate(state),
+            Self::Status => Self::status(state),
+            Self::Prune {
+                retention,
+                dry_run,
+                vacuum,
+            } => {
+                retention.apply(&mut database);
+                Self::prune(state, database.retention(), dry_run, vacuum)
+            }
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 pub struct ServerConfig {
-    #[structopt(
-        short = "H",
-        long = "host",
-        default_value = "127.0.0.1",
-        env = "LARES_HOST"
-    )]
-    /// Specifies server host
-    host: String,
-
-    #[structopt(short = "p", long = "port", default_value = "4000", env = "LARES_PORT")]
-    /// Specifies alternate port
-    port: u32,
-
-    #[structopt(
-        short = "u",
-        long = "username",
-        requires = "password",
-        env = "LARES_USERNAME"
-    )]
+    #[structopt(short = "H", long = "host", env = "LARES_HOST")]
+    /// Specifies server host [default: 127.0.0.1]
+    host: Option<String>,
+
+    #[structopt(short = "p", long = "port", env = "LARES_PORT")]
+    /// Specifies alternate port [default: 4000]
+    port: Option<u32>,
+
+    #[structopt(short = "u", long = "username", env = "LARES_USERNAME")]
     /// Specifies authentication username
     username: Option<String>,
 
-    #[structopt(
-        short = "P",
-        long = "password",
-        requires = "username",
-        env = "LARES_PASSWORD"
-    )]
+    #[structopt(short = "P", long = "password", env = "LARES_PASSWORD")]
     /// Specifies authentication password
     password: Option<String>,
 
-    #[structopt(
-        short = "i",
-        long = "interval",
-        default_value = "30",
-        env = "LARES_INTERVAL"
-    )]
-    /// Specifies crawl interval (unit: minutes)
-    interval: u32,
+    #[structopt(short = "i", long = "interval", env = "LARES_INTERVAL")]
+    /// Specifies crawl interval (unit: minutes) [default: 30]
+    interval: Option<u32>,
+
+    #[structopt(long = "adaptive", env = "LARES_ADAPTIVE", parse(try_from_str))]
+    /// Schedules crawls of each feed based on how often it is updated (`true` or `false`)
+    /// [default: false]
+    adaptive: Option<bool>,
+
+    #[structopt(long = "concurrency", env = "LARES_CONCURRENCY")]
+    /// Specifies maximum number of feeds crawled at the same time [default: 8]
+    concurrency: Option<usize>,
+
+    #[structopt(long = "per-host-concurrency", env = "LARES_PER_HOST_CONCURRENCY")]
+    /// Specifies maximum number of feeds from the same host crawled at the same time [default: 1]
+    per_host_concurrency: Option<usize>,
+
+    #[structopt(long = "per-host-delay", env = "LARES_PER_HOST_DELAY")]
+    /// Specifies delay between requests to the same host (unit: milliseconds) [default: 1000]
+    per_host_delay: Option<u64>,
+
+    #[structopt(flatten)]
+    retention: RetentionConfig,
+}
+
+impl ServerConfig {
+    /// Overrides settings of the configuration file with flags and environment variables.
+    fn apply(self, config: &mut Config) -> Result<()> {
+        if let Some(host) = self.host {
+            config.server.host = host;
+        }
+        if let Some(port) = self.port {
+            config.server.port = port;
+        }
+        if self.username.is_some() {
+            config.server.username = self.username;
+        }
+        if self.password.is_some() {
+            config.server.password = self.password;
+        }
+        if let Some(interval) = self.interval {
+            config.crawler.interval = interval;
+        }
+        if let Some(adaptive) = self.adaptive {
+            config.crawler.adaptive = adaptive;
+        }
+        if let Some(concurrency) = self.concurrency {
+            config.crawler.concurrency = concurrency;
+        }
+        if let Some(per_host_concurrency) = self.per_host_concurrency {
+            config.crawler.per_host_concurrency = per_host_concurrency;
+        }
+        if let Some(per_host_delay) = self.per_host_delay {
+            config.crawler.per_host_delay = per_host_delay;
+        }
+        self.retention.apply(&mut config.database);
+        config.validate()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ConfigCommand {
+    /// Validates the configuration and prints the effective settings
+    Check {
+        #[structopt(flatten)]
+        server: ServerConfig,
+    },
+}
+
+impl ConfigCommand {
+    fn run(self, mut config: Config) -> Result<()> {
+        match self {
+            Self::Check { server } => {
+                server.apply(&mut config)?;
+                print!("{}", config.to_toml()?);
+                Ok(())
+            }
+        }
+    }
 }
 
 #[derive(Debug, StructOpt)]
@@ -405,41 +1302,56 @@ pub enum SubCommand {
     Feed(FeedCommand),
     /// Manages group
     Group(GroupCommand),
+    /// Manages items
+    Item(ItemCommand),
+    /// Manages filter rules
+    Rule(RuleCommand),
+    /// Manages users of the Fever API
+    User(UserCommand),
+    /// Manages database
+    Db(DbCommand),
     /// Starts web server
     Server(ServerConfig),
+    /// Manages configuration
+    Config(ConfigCommand),
 }
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "lares", about = "Minimal RSS service")]
 pub struct Options {
-    #[structopt(
-        short = "d",
-        long = "database",
-        env = "LARES_DATABASE",
-        default_value = "lares.db"
-    )]
-    database: PathBuf,
+    #[structopt(short = "d", long = "database", env = "LARES_DATABASE")]
+    /// Path of the database [default: lares.db]
+    database: Option<PathBuf>,
+
+    #[structopt(short = "c", long = "config", env = "LARES_CONFIG")]
+    /// Path of a TOML configuration file
+    config: Option<PathBuf>,
 
     #[structopt(long)]
     debug: bool,
 
+    #[structopt(flatten)]
+    http: HttpConfig,
+
     #[structopt(subcommand)]
     command: SubCommand,
 }
 
 impl Options {
-    async fn server(mut state: State, config: ServerConfig) -> Result<()> {
-        if let Some(username) = config.username {
-            if let Some(password) = config.password {
+    async fn server(mut state: State, config: Config) -> Result<()> {
+        if let Some(username) = config.server.username {
+            if let Some(password) = config.server.password {
                 state = state.set_credential(username, password);
             }
         }
+        // flags of the server may have changed the crawler settings
+        state = state.set_crawler(config.crawler.clone());
 
         let app = crate::api::make_app(state.clone());
-        let crawl_interval = ((config.interval) * 60) as u64;
-        let crwaler = crate::crawler::Crawler::new(state, crawl_interval);
+        let crwaler =
+            crate::crawler::Crawler::new(state).set_retention(config.database.retention());
         let (web, crawl) = app
-            .listen(format!("{}:{}", config.host, config.port))
+            .listen(format!("{}:{}", config.server.host, config.server.port))
             .join(crwaler.runloop())
             .await;
         (web?, crawl?);
@@ -447,8 +1359,19 @@ impl Options {
     }
 
     pub async fn run(self) -> Result<()> {
-        let pool = crate::model::get_pool(&self.database)?;
-        let state = crate::state::State::new(pool);
+        let mut config = match self.config.as_ref() {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if let Some(database) = self.database {
+            config.database.path = database;
+        }
+        self.http.apply(&mut config.http)?;
+        crate::client::HttpClient::configure(config.http.clone());
+
+        if let SubCommand::Config(cmd) = self.command {
+            return cmd.run(config);
+        }
 
         if self.debug {
             femme::with_level(log::LevelFilter::Debug);
@@ -456,10 +1379,36 @@ impl Options {
             femme::with_level(log::LevelFilter::Info);
         }
 
+        // a dry run must neither create nor migrate the database
+        if let SubCommand::Feed(FeedCommand::Import {
+            file,
+            nesting,
+            dry_run: true,
+            fetch,
+        }) = self.command
+        {
+            return FeedCommand::import_dry_run(&config.database.path, file, nesting, fetch).await;
+        }
+
+        let pool = match self.command {
+            // leave migrating to the command itself
+            SubCommand::Db(_) => crate::model::open_pool(&config.database.path)?,
+            _ => crate::model::get_pool(&config.database.path)?,
+        };
+        let state = crate::state::State::new(pool).set_crawler(config.crawler.clone());
+
         match self.command {
             SubCommand::Feed(cmd) => cmd.run(state).await,
             SubCommand::Group(cmd) => cmd.run(state).await,
-            SubCommand::Server(config) => Self::server(state, config).await,
+            SubCommand::Item(cmd) => cmd.run(state).await,
+            SubCommand::Rule(cmd) => cmd.run(state).await,
+            SubCommand::User(cmd) => cmd.run(state).await,
+            SubCommand::Db(cmd) => cmd.run(state, config.database).await,
+            SubCommand::Server(server) => {
+                server.apply(&mut config)?;
+                Self::server(state, config).await
+            }
+            SubCommand::Config(_) => unreachable!(),
         }
     }
 }
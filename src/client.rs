@@ -1,10 +1,24 @@
+use async_std::future::timeout;
+use futures::future::BoxFuture;
+use futures_preview::io::AsyncReadExt;
+use isahc::RequestBuilderExt;
+use std::sync::OnceLock;
+use std::time::Duration;
+use surf::middleware::{HttpClient as Backend, Middleware, Next, Request, Response};
 use thiserror::Error;
 use url::{ParseError, Url};
 
-use crate::error::Result;
+use crate::config;
+use crate::error::{Error, Result};
 
 const MAXIMUM_REDIRECTION: u8 = 5;
 
+/// Size of the chunks response bodies are read in.
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Settings shared by all requests, see `HttpClient::configure`.
+static SETTINGS: OnceLock<config::Http> = OnceLock::new();
+
 /// User-Agent string (`LaresBot/<version> (+https://github.com/fanzeyi/lares)`)
 const USER_AGENT: &'static str = concat!(
     "LaresBot/",
@@ -14,6 +28,70 @@ const USER_AGENT: &'static str = concat!(
 
 pub struct HttpClient;
 
+/// Headers sent with the requests of a feed, in addition to or instead of the defaults.
+#[derive(Debug, Default, Clone)]
+pub struct RequestHeaders {
+    /// Replaces the `User-Agent` of the settings
+    pub user_agent: Option<String>,
+    pub extra: Vec<(String, String)>,
+}
+
+impl RequestHeaders {
+    /// Parses headers written one per line as `Name: Value`.
+    pub fn parse_extra(s: &str) -> Result<Vec<(String, String)>> {
+        s.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let (name, value) = line.split_once(':').ok_or_else(|| {
+                    Error::message(format!("invalid header '{}' (expected Name: Value)", line))
+                })?;
+                let (name, value) = (name.trim(), value.trim());
+                http::header::HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| Error::message(format!("invalid header name '{}'", name)))?;
+                http::header::HeaderValue::from_str(value)
+                    .map_err(|_| Error::message(format!("invalid value of header '{}'", name)))?;
+                Ok((name.to_owned(), value.to_owned()))
+            })
+            .collect()
+    }
+
+    /// Headers to send to `url` when they were set for `origin`. Extra headers often carry
+    /// credentials, so only the user agent is kept for other origins.
+    pub fn for_url(&self, origin: &Url, url: &str) -> RequestHeaders {
+        let same_origin = Url::parse(url)
+            .map(|url| url.origin() == origin.origin())
+            .unwrap_or(false);
+        RequestHeaders {
+            user_agent: self.user_agent.clone(),
+            extra: if same_origin {
+                self.extra.clone()
+            } else {
+                Vec::new()
+            },
+        }
+    }
+}
+
+/// Validators from a previous response, used to make conditional requests.
+#[derive(Debug, Default, Clone)]
+pub struct CacheValidators {
+    /// Value of the `ETag` header
+    pub etag: Option<String>,
+    /// Value of the `Last-Modified` header
+    pub last_modified: Option<String>,
+}
+
+/// Result of a conditional request.
+pub enum Fetched {
+    /// The server responded with `304 Not Modified`.
+    NotModified,
+    Modified {
+        body: Vec<u8>,
+        validators: CacheValidators,
+    },
+}
+
 #[derive(Debug, Error)]
 pub enum HttpClientError {
     #[error("Too many redirections")]
@@ -25,51 +103,312 @@ pub enum HttpClientError {
     #[error("Unexpected status code: {}", _0)]
     UnexpectedStatusCode(http::status::StatusCode),
 
+    #[error("Timed out after {} seconds", _0)]
+    Timeout(u64),
+
+    #[error("Response body is larger than {} bytes", _0)]
+    BodyTooLarge(u64),
+
     #[error("Surf error")]
     SurfError(#[from] surf::Exception),
 }
 
+/// Applies the connection settings and headers to requests before they are sent.
+#[derive(Clone)]
+struct RequestOptions {
+    connect_timeout: Option<Duration>,
+    proxy: Option<http::Uri>,
+    headers: Vec<(http::header::HeaderName, http::header::HeaderValue)>,
+}
+
+impl<C: Backend> Middleware<C> for RequestOptions {
+    fn handle<'a>(
+        &'a self,
+        req: Request,
+        client: C,
+        next: Next<'a, C>,
+    ) -> BoxFuture<'a, Result<Response, surf::Exception>> {
+        Box::pin(async move {
+            // isahc reads its per-request settings from extensions, which can only be set through
+            // a builder
+            let (parts, body) = req.into_parts();
+            let mut builder = http::Request::builder();
+            builder
+                .method(parts.method)
+                .uri(parts.uri)
+                .version(parts.version);
+            if let Some(connect_timeout) = self.connect_timeout {
+                builder.connect_timeout(connect_timeout);
+            }
+            if let Some(proxy) = self.proxy.as_ref() {
+                builder.proxy(proxy.clone());
+            }
+            let mut req = builder.body(body)?;
+
+            *req.headers_mut() = parts.headers;
+            for (name, value) in self.headers.iter() {
+                req.headers_mut().insert(name.clone(), value.clone());
+            }
+            next.run(req, client).await
+        })
+    }
+}
+
+fn seconds(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    }
+}
+
 impl HttpClient {
+    /// Sets the timeouts, proxy, user agent and size limit of all requests. Only the first call
+    /// has an effect, and requests sent before use the defaults.
+    pub fn configure(settings: config::Http) {
+        let _ = SETTINGS.set(settings);
+    }
+
+    fn settings() -> &'static config::Http {
+        SETTINGS.get_or_init(config::Http::default)
+    }
+
     pub async fn get(url: &str) -> Result<Vec<u8>> {
+        Self::get_with_headers(url, &RequestHeaders::default()).await
+    }
+
+    pub async fn get_with_headers(url: &str, headers: &RequestHeaders) -> Result<Vec<u8>> {
+        let mut response = Self::request(url, headers, &[]).await?;
+        let status = response.status();
+
+        if status.is_success() {
+            Self::read_body(&mut response).await
+        } else {
+            Err(HttpClientError::UnexpectedStatusCode(status).into())
+        }
+    }
+
+    /// Sends a conditional request with `If-None-Match` and `If-Modified-Since` built from
+    /// `validators`.
+    pub async fn get_conditional(
+        url: &str,
+        validators: &CacheValidators,
+        headers: &RequestHeaders,
+    ) -> Result<Fetched> {
+        let mut conditions = Vec::new();
+        if let Some(etag) = validators.etag.as_ref() {
+            conditions.push(("If-None-Match", etag.clone()));
+        }
+        if let Some(last_modified) = validators.last_modified.as_ref() {
+            conditions.push(("If-Modified-Since", last_modified.clone()));
+        }
+
+        let mut response = Self::request(url, headers, &conditions).await?;
+        let status = response.status();
+
+        if status == http::status::StatusCode::NOT_MODIFIED {
+            return Ok(Fetched::NotModified);
+        }
+
+        if !status.is_success() {
+            return Err(HttpClientError::UnexpectedStatusCode(status).into());
+        }
+
+        let validators = CacheValidators {
+            etag: response.header("ETag").map(ToOwned::to_owned),
+            last_modified: response.header("Last-Modified").map(ToOwned::to_owned),
+        };
+        Ok(Fetched::Modified {
+            body: Self::read_body(&mut response).await?,
+            validators,
+        })
+    }
+
+    /// Reads the body of a response, giving up when the server stops sending data for longer
+    /// than the read timeout, or sends more than the maximum body size.
+    async fn read_body(response: &mut surf::Response) -> Result<Vec<u8>> {
+        let settings = Self::settings();
+        let max_body_size = settings.max_body_size;
+        let content_length = response
+            .header("Content-Length")
+            .and_then(|length| length.parse::<u64>().ok());
+        if content_length.is_some_and(|length| length > max_body_size) {
+            return Err(HttpClientError::BodyTooLarge(max_body_size).into());
+        }
+
+        let mut body = Vec::with_capacity(content_length.unwrap_or(0) as usize);
+        let mut chunk = vec![0; READ_CHUNK_SIZE];
+        loop {
+            let read = match seconds(settings.read_timeout) {
+                Some(duration) => timeout(duration, response.read(&mut chunk))
+                    .await
+                    .map_err(|_| HttpClientError::Timeout(settings.read_timeout))??,
+                None => response.read(&mut chunk).await?,
+            };
+            if read == 0 {
+                break Ok(body);
+            }
+            if (body.len() + read) as u64 > max_body_size {
+                break Err(HttpClientError::BodyTooLarge(max_body_size).into());
+            }
+            body.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    /// Sends a GET request following redirections, and returns the first response that is not a
+    /// redirection.
+    async fn request(
+        url: &str,
+        headers: &RequestHeaders,
+        conditions: &[(&'static str, String)],
+    ) -> Result<surf::Response> {
+        let settings = Self::settings();
+        let user_agent = headers
+            .user_agent
+            .as_deref()
+            .or(settings.user_agent.as_deref())
+            .unwrap_or(USER_AGENT);
+        let mut options = RequestOptions {
+            connect_timeout: seconds(settings.connect_timeout),
+            proxy: match settings.proxy.as_ref() {
+                Some(proxy) => Some(
+                    proxy
+                        .parse()
+                        .map_err(|_| Error::message(format!("invalid proxy '{}'", proxy)))?,
+                ),
+                None => None,
+            },
+            headers: Vec::new(),
+        };
+        for (name, value) in headers.extra.iter() {
+            // headers were validated when they were set
+            if let (Ok(name), Ok(value)) = (
+                http::header::HeaderName::from_bytes(name.as_bytes()),
+                http::header::HeaderValue::from_str(value),
+            ) {
+                options.headers.push((name, value));
+            }
+        }
+
         let mut url = Url::parse(url)?;
+        let origin = url.origin();
         let mut redirection_count = 0;
 
         loop {
-            let mut response = match surf::get(&url)
-                .set_header("User-Agent", USER_AGENT)
-                .set_header("Content-Length", "0")
-                .await
-            {
+            let mut request = surf::get(&url)
+                .set_header("User-Agent", user_agent)
+                .set_header("Content-Length", "0");
+            for (key, value) in conditions.iter() {
+                request = request.set_header(key, value);
+            }
+            let request = request.middleware(options.clone());
+
+            let response = match seconds(settings.read_timeout) {
+                Some(duration) => timeout(duration, request)
+                    .await
+                    .map_err(|_| HttpClientError::Timeout(settings.read_timeout))?,
+                None => request.await,
+            };
+            let response = match response {
                 Ok(resp) => resp,
                 Err(e) => return Err(HttpClientError::from(e).into()),
             };
             let status = response.status();
 
-            if status.is_success() {
-                break Ok(response.body_bytes().await?);
+            if status == http::status::StatusCode::NOT_MODIFIED || !status.is_redirection() {
+                break Ok(response);
             }
 
-            if status.is_redirection() {
-                if redirection_count > MAXIMUM_REDIRECTION {
-                    break Err(HttpClientError::TooManyRedirections.into());
-                }
+            if redirection_count > MAXIMUM_REDIRECTION {
+                break Err(HttpClientError::TooManyRedirections.into());
+            }
 
-                redirection_count += 1;
+            redirection_count += 1;
 
-                if let Some(location) = response.header("Location") {
-                    match Url::parse(location) {
-                        Ok(parsed) => url = parsed,
-                        Err(e) if e == ParseError::RelativeUrlWithoutBase => {
-                            url.set_path(location);
-                        }
-                        Err(e) => break Err(e.into()),
+            if let Some(location) = response.header("Location") {
+                match Url::parse(location) {
+                    Ok(parsed) => url = parsed,
+                    Err(e) if e == ParseError::RelativeUrlWithoutBase => {
+                        url.set_path(location);
                     }
-                } else {
-                    break Err(HttpClientError::MissingLocationHeader.into());
+                    Err(e) => break Err(e.into()),
+                }
+                // extra headers are only meant for the origin they were set for
+                if url.origin() != origin {
+                    options.headers.clear();
                 }
             } else {
-                break Err(HttpClientError::UnexpectedStatusCode(status).into());
+                break Err(HttpClientError::MissingLocationHeader.into());
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::task;
+    use std::net::TcpListener;
+
+    /// Starts a server echoing the `X-Token` and `User-Agent` headers at `/echo`, and
+    /// redirecting `/redirect` to `redirect_to`.
+    fn spawn_server(redirect_to: Option<String>) -> String {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("127.0.0.1:{}", port);
+
+        let mut app = tide::new();
+        app.at("/echo").get(|req: tide::Request<()>| async move {
+            let header = |name| {
+                req.header(name)
+                    .map(|value| value.as_str().to_owned())
+                    .unwrap_or_else(|| "none".to_owned())
+            };
+            Ok(format!("{} {}", header("X-Token"), header("User-Agent")))
+        });
+        if let Some(redirect_to) = redirect_to {
+            app.at("/redirect").get(tide::Redirect::new(redirect_to));
+        }
+        task::spawn({
+            let addr = addr.clone();
+            async move { app.listen(addr).await.unwrap() }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn test_cross_origin_redirect() {
+        task::block_on(async {
+            let other = spawn_server(None);
+            let origin = spawn_server(Some(format!("{}/echo", other)));
+            task::sleep(Duration::from_millis(200)).await;
+
+            let headers = RequestHeaders {
+                user_agent: Some("TestAgent".to_owned()),
+                extra: vec![("X-Token".to_owned(), "secret".to_owned())],
+            };
+            let body = HttpClient::get_with_headers(&format!("{}/echo", origin), &headers).await?;
+            assert_eq!(String::from_utf8_lossy(&body), "secret TestAgent");
+
+            let body =
+                HttpClient::get_with_headers(&format!("{}/redirect", origin), &headers).await?;
+            assert_eq!(String::from_utf8_lossy(&body), "none TestAgent");
+
+            let origin = Url::parse(&origin)?;
+            assert_eq!(
+                headers.for_url(&origin, &format!("{}/echo", other)).extra,
+                vec![]
+            );
+            assert_eq!(headers.for_url(&origin, "not a url").extra, vec![]);
+            assert_eq!(
+                headers.for_url(&origin, &format!("{}/page", origin)).extra,
+                headers.extra
+            );
+            Ok::<_, Error>(())
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+/// Settings read from a TOML configuration file, passed with `--config` or `LARES_CONFIG`.
+///
+/// Every setting is optional. Command line flags take precedence over environment variables,
+/// which take precedence over the file, which takes precedence over the defaults below.
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::model::Retention;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database: Database,
+    pub server: Server,
+    pub crawler: Crawler,
+    pub http: Http,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Database {
+    pub path: PathBuf,
+    /// Deletes read items published more than this many days ago
+    pub keep_read_days: Option<u32>,
+    /// Deletes all but this many most recent items of each feed
+    pub keep_per_feed: Option<u32>,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Database {
+            path: PathBuf::from("lares.db"),
+            keep_read_days: None,
+            keep_per_feed: None,
+        }
+    }
+}
+
+impl Database {
+    pub fn retention(&self) -> Retention {
+        let mut retention = Retention::new();
+        if let Some(days) = self.keep_read_days {
+            retention = retention.read_days(days);
+        }
+        if let Some(count) = self.keep_per_feed {
+            retention = retention.per_feed(count);
+        }
+        retention
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    pub host: String,
+    pub port: u32,
+    /// Credential of the administrator, see `State::set_credential`
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server {
+            host: "127.0.0.1".to_owned(),
+            port: 4000,
+            username: None,
+            password: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Crawler {
+    /// Crawl interval (unit: minutes)
+    pub interval: u32,
+    /// Schedules crawls of each feed based on how often it is updated
+    pub adaptive: bool,
+    /// Maximum number of feeds crawled at the same time
+    pub concurrency: usize,
+    /// Maximum number of feeds from the same host crawled at the same time
+    pub per_host_concurrency: usize,
+    /// Delay between requests to the same host (unit: milliseconds)
+    pub per_host_delay: u64,
+}
+
+impl Default for Crawler {
+    fn default() -> Self {
+        Crawler {
+            interval: 30,
+            adaptive: false,
+            concurrency: 8,
+            per_host_concurrency: 1,
+            per_host_delay: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http {
+    /// Maximum time to establish a connection (unit: seconds, 0 to disable)
+    pub connect_timeout: u64,
+    /// Maximum time waiting for the server to respond or to send more data (unit: seconds, 0 to
+    /// disable)
+    pub read_timeout: u64,
+    /// HTTP, HTTPS or SOCKS proxy, e.g. `socks5h://127.0.0.1:1080`
+    pub proxy: Option<String>,
+    /// Replaces the default `User-Agent`
+    pub user_agent: Option<String>,
+    /// Responses larger than this are rejected (unit: bytes)
+    pub max_body_size: u64,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Http {
+            connect_timeout: 10,
+            read_timeout: 30,
+            proxy: None,
+            user_agent: None,
+            max_body_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::parse(&content)
+            .map_err(|e| Error::message(format!("invalid config file {}: {}", path.display(), e)))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let config: Self = toml::from_str(content).map_err(|e| Error::message(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks settings that depend on each other, after flags and environment variables are
+    /// applied.
+    pub fn validate(&self) -> Result<()> {
+        if self.server.username.is_some() != self.server.password.is_some() {
+            return Err(Error::message(
+                "server.username and server.password must be set together".to_owned(),
+            ));
+        }
+        if self.crawler.interval == 0 {
+            return Err(Error::message(
+                "crawler.interval must be positive".to_owned(),
+            ));
+        }
+        if let Some(proxy) = self.http.proxy.as_ref() {
+            proxy
+                .parse::<http::Uri>()
+                .map_err(|e| Error::message(format!("invalid http.proxy '{}': {}", proxy, e)))?;
+        }
+        Ok(())
+    }
+
+    /// Renders the configuration as TOML, hiding the password.
+    pub fn to_toml(&self) -> Result<String> {
+        let mut value = toml::Value::try_from(self).map_err(|e| Error::message(e.to_string()))?;
+        if let Some(server) = value.get_mut("server").and_then(|v| v.as_table_mut()) {
+            if server.contains_key("password") {
+                server.insert("password".to_owned(), "********".into());
+            }
+        }
+        toml::to_string_pretty(&value).map_err(|e| Error::message(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+            [database]
+            path = "/var/lib/lares/lares.db"
+            keep_per_feed = 500
+
+            [server]
+            port = 8080
+            username = "lares"
+            password = "secret"
+
+            [crawler]
+            adaptive = true
+
+            [http]
+            proxy = "socks5h://127.0.0.1:1080"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.database.path,
+            PathBuf::from("/var/lib/lares/lares.db")
+        );
+        assert_eq!(config.database.keep_per_feed, Some(500));
+        assert_eq!(config.server.host, "127.0.0.1");
+        assert_eq!(config.server.port, 8080);
+        assert!(config.crawler.adaptive);
+        assert_eq!(config.crawler.interval, 30);
+        assert_eq!(config.http.read_timeout, 30);
+        assert!(config.http.proxy.is_some());
+
+        let rendered = config.to_toml().unwrap();
+        assert!(!rendered.contains("secret"));
+        assert!(rendered.contains("port = 8080"));
+
+        assert!(Config::parse("").is_ok());
+        assert!(Config::parse("[server]\nprot = 8080").is_err());
+        assert!(Config::parse("[server]\nport = \"8080\"").is_err());
+        assert!(Config::parse("[server]\nusername = \"lares\"").is_err());
+        assert!(Config::parse("[http]\nproxy = \"not a proxy\"").is_err());
+    }
+}
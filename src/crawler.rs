@@ -1,46 +1,216 @@
 use crate::error::Result;
-use crate::model::{Feed, ModelExt};
+use crate::model::{Feed, Retention};
 use crate::state::State;
 use async_std::stream;
-use async_std::stream::StreamExt;
 use async_std::task;
-use futures::future::join_all;
-use std::time::Duration;
+use chrono::Utc;
+use futures::stream::StreamExt;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Upper bound of the delay before retrying a failing feed.
+const MAXIMUM_BACKOFF_SECS: u64 = 24 * 60 * 60;
+
+/// How often the crawler looks for feeds that are due.
+const SCHEDULER_TICK_SECS: u64 = 60;
+
+/// How often expired items are pruned.
+const PRUNE_INTERVAL_SECS: u64 = 60 * 60;
+
+/// Bounds of crawl intervals chosen by adaptive scheduling.
+const ADAPTIVE_MINIMUM_SECS: u64 = 5 * 60;
+const ADAPTIVE_MAXIMUM_SECS: u64 = 24 * 60 * 60;
 
 pub struct Crawler {
     state: State,
     interval_secs: u64,
+    /// Maximum number of feeds crawled at the same time
+    concurrency: usize,
+    /// Maximum number of feeds from the same host crawled at the same time
+    host_concurrency: usize,
+    /// Schedules feeds based on how often they are updated
+    adaptive: bool,
+    /// Rules for deleting old items
+    retention: Retention,
 }
 
 impl Crawler {
-    pub fn new(state: State, interval_secs: u64) -> Self {
+    /// Creates a crawler following the crawler settings of `state`.
+    pub fn new(state: State) -> Self {
+        let settings = &state.crawler;
         Crawler {
+            interval_secs: settings.interval as u64 * 60,
+            concurrency: settings.concurrency.max(1),
+            host_concurrency: settings.per_host_concurrency.max(1),
+            adaptive: settings.adaptive,
             state,
-            interval_secs,
+            retention: Retention::new(),
+        }
+    }
+
+    pub fn set_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Splits feeds into lanes that are crawled sequentially. Feeds from the same host are spread
+    /// over at most `host_concurrency` lanes.
+    fn lanes(&self, feeds: Vec<Feed>) -> Vec<Vec<Feed>> {
+        let mut hosts: HashMap<String, Vec<Vec<Feed>>> = HashMap::new();
+
+        for feed in feeds.into_iter() {
+            let host = Url::parse(&feed.url)
+                .ok()
+                .and_then(|url| url.host_str().map(ToOwned::to_owned))
+                .unwrap_or_default();
+            let lanes = hosts.entry(host).or_default();
+
+            if lanes.len() < self.host_concurrency {
+                lanes.push(vec![feed]);
+            } else {
+                // unwrap(): `host_concurrency` is at least 1
+                let lane = lanes.iter_mut().min_by_key(|lane| lane.len()).unwrap();
+                lane.push(feed);
+            }
         }
+
+        hosts.into_values().flatten().collect()
+    }
+
+    /// Delay before crawling a feed again after `error_count` consecutive failures. The delay
+    /// doubles with every failure, starting from the crawl interval.
+    fn backoff(&self, error_count: u32) -> Duration {
+        let factor = 2u64.saturating_pow(error_count.saturating_sub(1));
+        Duration::from_secs(
+            self.interval_secs
+                .saturating_mul(factor)
+                .min(MAXIMUM_BACKOFF_SECS),
+        )
+    }
+
+    /// Delay before crawling a feed again after a successful crawl.
+    ///
+    /// Feed-specific intervals always take precedence. With adaptive scheduling, feeds are crawled
+    /// about twice between two of their items, but not more often than the feed asks for.
+    fn next_interval(&self, conn: &rusqlite::Connection, feed: &Feed) -> Result<Duration> {
+        if let Some(minutes) = feed.crawl_interval {
+            return Ok(Duration::from_secs(minutes as u64 * 60));
+        }
+
+        if !self.adaptive {
+            return Ok(Duration::from_secs(self.interval_secs));
+        }
+
+        let mut secs = feed
+            .observed_interval(conn)?
+            .map(|interval| interval.num_seconds().max(0) as u64 / 2)
+            .unwrap_or(self.interval_secs);
+        if let Some(hint) = feed.update_hint {
+            secs = secs.max(hint as u64 * 60);
+        }
+
+        Ok(Duration::from_secs(
+            secs.clamp(ADAPTIVE_MINIMUM_SECS, ADAPTIVE_MAXIMUM_SECS),
+        ))
+    }
+
+    fn record(&self, id: u32, result: &Result<Feed>) -> Result<()> {
+        let conn = self.state.db.get()?;
+
+        match result {
+            Ok(feed) => {
+                let interval = chrono::Duration::from_std(self.next_interval(&conn, feed)?)
+                    .unwrap_or_else(|_| chrono::Duration::zero());
+                Feed::record_success(&conn, id, Some(Utc::now() + interval))?;
+            }
+            Err(e) => {
+                let error = e.describe();
+                warn!("failed to crawl feed {}: {}", id, error);
+
+                let error_count = Feed::record_failure(&conn, id, &error)?;
+                let backoff = chrono::Duration::from_std(self.backoff(error_count))
+                    .unwrap_or_else(|_| chrono::Duration::zero());
+                Feed::set_next_crawl_at(&conn, id, Utc::now() + backoff)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Crawls a single feed right away, and records the result like a scheduled crawl.
+    pub async fn crawl_feed(&self, feed: Feed) -> Result<Feed> {
+        let id = feed.id;
+        let result = feed.crawl(self.state.clone()).await;
+        self.record(id, &result)?;
+        result
     }
 
     async fn crawl(&self) -> Result<()> {
         let feeds = {
             let conn = self.state.db.get()?;
-            Feed::all(&conn)?
+            Feed::all_due(&conn, Utc::now())?
         };
 
-        let _ = join_all(feeds.into_iter().map(|feed| {
-            let state = self.state.clone();
-            task::spawn(async move { feed.crawl(state).await })
-        }))
-        .await;
+        let results = futures::stream::iter(self.lanes(feeds))
+            .map(|lane| {
+                let state = self.state.clone();
+                let host_delay = self.state.host_delay();
+                task::spawn(async move {
+                    let mut results = Vec::with_capacity(lane.len());
+                    for (idx, feed) in lane.into_iter().enumerate() {
+                        if idx > 0 {
+                            task::sleep(host_delay).await;
+                        }
+                        let id = feed.id;
+                        results.push((id, feed.crawl(state.clone()).await));
+                    }
+                    results
+                })
+            })
+            .buffer_unordered(self.concurrency)
+            .collect::<Vec<_>>()
+            .await;
+
+        for (id, result) in results.into_iter().flatten() {
+            if let Err(e) = self.record(id, &result) {
+                warn!("failed to record crawl result of feed {}: {:?}", id, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn prune(&self) -> Result<()> {
+        if self.retention.is_empty() {
+            return Ok(());
+        }
+
+        let conn = self.state.db.get()?;
+        let deleted = self.retention.prune(&conn, Utc::now())?;
+        if deleted > 0 {
+            info!("pruned {} expired items", deleted);
+        }
         Ok(())
     }
 
     pub async fn runloop(self) -> Result<()> {
-        let mut interval = stream::interval(Duration::from_secs(self.interval_secs));
+        let tick = self.interval_secs.clamp(1, SCHEDULER_TICK_SECS);
+        let mut interval = stream::interval(Duration::from_secs(tick));
+        let mut last_pruned: Option<Instant> = None;
         while let Some(_) = interval.next().await {
             match self.crawl().await {
                 Ok(_) => (),
                 Err(e) => eprintln!("error: {:?}", e),
             }
+
+            let prune_interval = Duration::from_secs(PRUNE_INTERVAL_SECS);
+            if !matches!(last_pruned, Some(at) if at.elapsed() < prune_interval) {
+                if let Err(e) = self.prune() {
+                    eprintln!("error: {:?}", e);
+                }
+                last_pruned = Some(Instant::now());
+            }
         }
         Ok(())
     }
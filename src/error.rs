@@ -50,9 +50,15 @@ pub enum Error {
         position: usize,
     },
 
+    #[error("database schema version {version} is newer than supported version {supported}")]
+    SchemaTooNew { version: u32, supported: u32 },
+
     #[error("url parsing error")]
     UrlError(#[from] url::ParseError),
 
+    #[error("Invalid regular expression")]
+    RegexError(#[from] regex::Error),
+
     #[error("{}", _0)]
     Message(String),
 }
@@ -61,6 +67,18 @@ impl Error {
     pub fn message(msg: String) -> Self {
         Error::Message(msg)
     }
+
+    /// Formats the error along with all of its sources, separated by `: `.
+    pub fn describe(&self) -> String {
+        let mut result = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(e) = source {
+            result.push_str(": ");
+            result.push_str(&e.to_string());
+            source = e.source();
+        }
+        result
+    }
 }
 
 impl From<(quick_xml::Error, usize)> for Error {
@@ -0,0 +1,236 @@
+/// Extracts the main content of web pages, for feeds that only contain summaries.
+///
+/// Similar to Readability, paragraphs are scored by their length and number of commas. Scores
+/// are credited to the parent and, halved, to the grandparent of each paragraph, and the element
+/// with the highest score is taken as the main content.
+use crate::sanitize::Tag;
+
+/// Tags that are skipped along with their content.
+const SKIPPED_TAGS: &[&'static str] = &["script", "style", "template", "noscript", "svg", "head"];
+
+/// Tags that have no content and no end tag.
+const VOID_TAGS: &[&'static str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Tags whose start closes an open paragraph.
+const BLOCK_TAGS: &[&'static str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "div",
+    "dl",
+    "figure",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "ul",
+];
+
+/// Tags that do not contain the main content.
+const UNLIKELY_TAGS: &[&'static str] = &["nav", "header", "footer", "aside", "form"];
+
+/// Class names and ids of elements that are unlikely to contain the main content, unless they
+/// also match `LIKELY_NAMES`.
+const UNLIKELY_NAMES: &[&'static str] = &[
+    "comment", "footer", "sidebar", "nav", "menu", "share", "social", "related", "promo", "banner",
+];
+const LIKELY_NAMES: &[&'static str] = &["article", "content", "main", "post", "body"];
+
+/// Paragraphs shorter than this are ignored.
+const MINIMUM_PARAGRAPH_LENGTH: usize = 25;
+
+struct Element {
+    name: String,
+    parent: Option<usize>,
+    /// Byte range of the content in the page
+    start: usize,
+    end: usize,
+    /// Whether this element or one of its ancestors is unlikely to contain the main content
+    unlikely: bool,
+    score: f64,
+    /// Text length and number of commas, only tracked for paragraphs
+    text_len: usize,
+    commas: usize,
+}
+
+fn is_unlikely(tag: &Tag) -> bool {
+    if UNLIKELY_TAGS.contains(&tag.name.as_str()) {
+        return true;
+    }
+
+    let names = format!(
+        "{} {}",
+        tag.attribute("class").unwrap_or_default(),
+        tag.attribute("id").unwrap_or_default()
+    )
+    .to_ascii_lowercase();
+    UNLIKELY_NAMES.iter().any(|name| names.contains(name))
+        && !LIKELY_NAMES.iter().any(|name| names.contains(name))
+}
+
+/// Closes an element at `end`, and credits the score of paragraphs to their ancestors.
+fn close(elements: &mut [Element], index: usize, end: usize) {
+    let element = &mut elements[index];
+    element.end = end;
+    if element.name != "p" || element.unlikely || element.text_len < MINIMUM_PARAGRAPH_LENGTH {
+        return;
+    }
+
+    let score = 1.0 + element.commas as f64 + (element.text_len / 100).min(3) as f64;
+    if let Some(parent) = element.parent {
+        elements[parent].score += score;
+        if let Some(grandparent) = elements[parent].parent {
+            elements[grandparent].score += score / 2.0;
+        }
+    }
+}
+
+/// Finds the main content of a web page, and returns its inner HTML.
+pub fn extract(html: &str) -> Option<&str> {
+    let mut elements: Vec<Element> = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    let mut pos = 0;
+
+    while let Some(offset) = html[pos..].find('<') {
+        let text = html[pos..pos + offset].trim();
+        if let Some(&paragraph) = open.iter().rev().find(|&&i| elements[i].name == "p") {
+            elements[paragraph].text_len += text.chars().count();
+            elements[paragraph].commas += text.matches(',').count();
+        }
+        pos += offset;
+
+        let rest = &html[pos..];
+        if rest.starts_with("<!--") {
+            pos = rest
+                .find("-->")
+                .map(|end| pos + end + 3)
+                .unwrap_or(html.len());
+            continue;
+        } else if rest.starts_with("<!") || rest.starts_with("<?") {
+            pos = rest
+                .find('>')
+                .map(|end| pos + end + 1)
+                .unwrap_or(html.len());
+            continue;
+        }
+
+        let (tag, len) = match Tag::parse(rest) {
+            Some(tag) => tag,
+            None => {
+                pos += 1;
+                continue;
+            }
+        };
+        let tag_start = pos;
+        pos += len;
+
+        if tag.is_end {
+            if let Some(depth) = open.iter().rposition(|&i| elements[i].name == tag.name) {
+                for index in open.split_off(depth) {
+                    close(&mut elements, index, tag_start);
+                }
+            }
+            continue;
+        }
+
+        let name = tag.name.as_str();
+        if SKIPPED_TAGS.contains(&name) {
+            let end = format!("</{}", name);
+            pos = html[pos..]
+                .to_ascii_lowercase()
+                .find(&end)
+                .map(|end| pos + end)
+                .unwrap_or(html.len());
+            continue;
+        }
+        if BLOCK_TAGS.contains(&name) {
+            if let Some(&index) = open.last().filter(|&&i| elements[i].name == "p") {
+                open.pop();
+                close(&mut elements, index, tag_start);
+            }
+        }
+        if VOID_TAGS.contains(&name) {
+            continue;
+        }
+
+        let parent = open.last().copied();
+        elements.push(Element {
+            unlikely: matches!(parent, Some(i) if elements[i].unlikely) || is_unlikely(&tag),
+            name: tag.name,
+            parent,
+            start: pos,
+            end: html.len(),
+            score: 0.0,
+            text_len: 0,
+            commas: 0,
+        });
+        open.push(elements.len() - 1);
+    }
+
+    for index in open.into_iter().rev() {
+        close(&mut elements, index, html.len());
+    }
+
+    elements
+        .iter()
+        .filter(|element| element.score > 0.0)
+        .max_by(|a, b| {
+            a.score
+                .partial_cmp(&b.score)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .map(|element| &html[element.start..element.end])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        let page = r#"
+        <!DOCTYPE html>
+        <html>
+        <head><title>Post</title><style>p { color: red; }</style></head>
+        <body>
+            <nav><p>Home, Archive, About, Contact, and a few more links</p></nav>
+            <div class="main-column">
+                <article>
+                    <h1>Title</h1>
+                    <p>The first paragraph of the article, which is long enough to count.
+                    <p>The second paragraph, with a <a href="/link">link</a>, and some commas.</p>
+                    <script>document.write("<p>Injected, injected, injected</p>")</script>
+                </article>
+                <div class="comments">
+                    <p>A comment, a comment, a comment, a comment, a comment, a comment.</p>
+                </div>
+            </div>
+            <footer><p>Copyright, all rights reserved, since forever and ever.</p></footer>
+        </body>
+        </html>"#;
+
+        let content = extract(page).unwrap();
+        assert!(content.trim_start().starts_with("<h1>Title</h1>"));
+        assert!(content.contains("The second paragraph"));
+        assert!(!content.contains("comment"));
+
+        assert_eq!(extract("<p>Too short.</p>"), None);
+    }
+}
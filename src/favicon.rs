@@ -0,0 +1,76 @@
+/// Discovers and downloads favicons of websites.
+use log::debug;
+use url::Url;
+
+use crate::client::HttpClient;
+use crate::error::{Error, Result};
+use crate::find::find_rel_icons;
+
+/// Guesses the MIME type of an image from its magic bytes.
+fn sniff_mime(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.starts_with(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"\x00\x00\x01\x00") {
+        Some("image/x-icon")
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        Some("image/webp")
+    } else {
+        let head = String::from_utf8_lossy(&bytes[..bytes.len().min(256)]);
+        if head.contains("<svg") {
+            Some("image/svg+xml")
+        } else {
+            None
+        }
+    }
+}
+
+/// Finds candidate icon URLs for the given website, in order of preference.
+async fn candidates(site_url: &Url) -> Vec<Url> {
+    let mut result = Vec::new();
+
+    match HttpClient::get(site_url.as_str()).await {
+        Ok(page) => match find_rel_icons(&page[..]) {
+            Ok(hrefs) => result.extend(hrefs.iter().filter_map(|href| site_url.join(href).ok())),
+            Err(e) => debug!("unable to parse {} for icons: {:?}", site_url, e),
+        },
+        Err(e) => debug!("unable to fetch {}: {:?}", site_url, e),
+    }
+
+    if let Ok(fallback) = site_url.join("/favicon.ico") {
+        if !result.contains(&fallback) {
+            result.push(fallback);
+        }
+    }
+
+    result
+}
+
+/// Downloads the favicon of `site_url` and returns it in Fever's format (a data URI without the
+/// `data:` prefix, e.g. `image/png;base64,...`).
+pub async fn fetch(site_url: &str) -> Result<String> {
+    let site_url = Url::parse(site_url)?;
+
+    for url in candidates(&site_url).await {
+        debug!("trying favicon {}", url);
+        let bytes = match HttpClient::get(url.as_str()).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                debug!("unable to fetch favicon {}: {:?}", url, e);
+                continue;
+            }
+        };
+
+        if let Some(mime) = sniff_mime(&bytes) {
+            return Ok(format!("{};base64,{}", mime, base64::encode(&bytes)));
+        }
+    }
+
+    Err(Error::message(format!(
+        "unable to find favicon for {}",
+        site_url
+    )))
+}
@@ -5,8 +5,8 @@ use std::io::BufRead;
 
 use crate::error::Result;
 
-/// Parses HTML page to find `<link rel="alternate" />` and extract hrefs.
-pub fn find_rel_alternates<B: BufRead>(reader: B) -> Result<Vec<String>> {
+/// Parses HTML page to find `<link />` tags whose `rel` satisfies `matches` and extract hrefs.
+fn find_links<B: BufRead, F: Fn(&[u8]) -> bool>(reader: B, matches: F) -> Result<Vec<String>> {
     let mut reader = Reader::from_reader(reader);
     reader.check_end_names(false);
 
@@ -20,7 +20,7 @@ pub fn find_rel_alternates<B: BufRead>(reader: B) -> Result<Vec<String>> {
                     if acc {
                         acc
                     } else if let Ok(attr) = attr {
-                        attr.key == b"rel" && attr.value.as_ref() == b"alternate"
+                        attr.key == b"rel" && matches(attr.value.as_ref())
                     } else {
                         false
                     }
@@ -54,3 +54,108 @@ pub fn find_rel_alternates<B: BufRead>(reader: B) -> Result<Vec<String>> {
 
     Ok(result)
 }
+
+/// Parses HTML page to find `<link rel="alternate" />` and extract hrefs.
+pub fn find_rel_alternates<B: BufRead>(reader: B) -> Result<Vec<String>> {
+    find_links(reader, |rel| rel == b"alternate")
+}
+
+/// Parses HTML page to find `<link rel="icon" />` (including `rel="shortcut icon"`) and extract
+/// hrefs.
+pub fn find_rel_icons<B: BufRead>(reader: B) -> Result<Vec<String>> {
+    find_links(reader, |rel| {
+        rel.split(|&c| c == b' ')
+            .any(|token| token.eq_ignore_ascii_case(b"icon"))
+    })
+}
+
+/// Parses HTML fragment to find `<a href="..." />` and extract hrefs along with their texts.
+///
+/// Item bodies are often not well-formed, so parsing stops at the first error and anchors found
+/// so far are returned.
+pub fn find_anchors<B: BufRead>(reader: B) -> Vec<(String, String)> {
+    let mut reader = Reader::from_reader(reader);
+    reader.check_end_names(false);
+
+    let mut buf = Vec::new();
+    let mut result = Vec::new();
+    let mut current: Option<(String, String)> = None;
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) if e.name() == b"a" => {
+                current = e
+                    .attributes()
+                    .filter_map(|attr| attr.ok())
+                    .find(|attr| attr.key == b"href")
+                    .and_then(|attr| String::from_utf8(attr.value.into_owned()).ok())
+                    .map(|href| (href, String::new()));
+            }
+            Ok(Event::Text(ref e)) => {
+                if let Some((_, text)) = current.as_mut() {
+                    let decoded = e
+                        .unescape_and_decode(&reader)
+                        .unwrap_or_else(|_| String::from_utf8_lossy(e.escaped()).into_owned());
+                    text.push_str(&decoded);
+                }
+            }
+            Ok(Event::End(ref e)) if e.name() == b"a" => {
+                if let Some((href, text)) = current.take() {
+                    result.push((href, text.trim().to_owned()));
+                }
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    result
+}
+
+/// Parses feed to find the update period advertised by the syndication module
+/// (`<sy:updatePeriod />` and `<sy:updateFrequency />`), in minutes.
+pub fn find_update_period<B: BufRead>(reader: B) -> Option<u32> {
+    let mut reader = Reader::from_reader(reader);
+    reader.check_end_names(false);
+
+    let mut buf = Vec::new();
+    let mut current: Option<Vec<u8>> = None;
+    let mut period: Option<u32> = None;
+    let mut frequency: u32 = 1;
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) => match e.local_name() {
+                // hints only appear in the channel, before any entry
+                b"item" | b"entry" => break,
+                name => current = Some(name.to_owned()),
+            },
+            Ok(Event::Text(ref e)) => {
+                let text = e.unescape_and_decode(&reader).unwrap_or_default();
+                match current.as_deref() {
+                    Some(b"updatePeriod") => {
+                        period = match text.trim() {
+                            "hourly" => Some(60),
+                            "daily" => Some(24 * 60),
+                            "weekly" => Some(7 * 24 * 60),
+                            "monthly" => Some(30 * 24 * 60),
+                            "yearly" => Some(365 * 24 * 60),
+                            _ => period,
+                        }
+                    }
+                    Some(b"updateFrequency") => {
+                        frequency = text.trim().parse().unwrap_or(1).max(1);
+                    }
+                    _ => (),
+                }
+            }
+            Ok(Event::End(_)) => current = None,
+            Ok(Event::Eof) | Err(_) => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    period.map(|period| period / frequency)
+}
@@ -6,11 +6,18 @@ mod error;
 mod api;
 mod cli;
 mod client;
+mod config;
 mod crawler;
+mod extract;
+mod favicon;
 mod find;
+mod migration;
 pub mod model;
 mod opml;
+mod reader;
 mod remote;
+mod rest;
+mod sanitize;
 mod state;
 mod utils;
 
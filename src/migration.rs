@@ -0,0 +1,411 @@
+/// Versioned schema migrations.
+///
+/// The schema version of a database is stored in `PRAGMA user_version`. Each migration upgrades
+/// the schema by exactly one version and is applied in its own transaction.
+use log::info;
+use rusqlite::{Connection, NO_PARAMS};
+
+use crate::error::{Error, Result};
+use crate::utils::{content_hash, strip_html};
+
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    up: fn(&Connection) -> Result<()>,
+}
+
+/// All migrations, ordered by version. Never modify a released migration; add a new one instead.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create initial schema",
+        up: |conn| {
+            // Databases created before migrations were introduced already have these tables.
+            conn.execute_batch(
+                r#"
+            CREATE TABLE IF NOT EXISTS `group` (
+                id INTEGER PRIMARY KEY,
+                title TEXT UNIQUE ON CONFLICT IGNORE
+            );
+            CREATE TABLE IF NOT EXISTS `feed` (
+                id INTEGER PRIMARY KEY,
+                title TEXT,
+                url TEXT,
+                site_url TEXT,
+                is_spark BOOLEAN,
+                last_updated DATETIME
+            );
+            CREATE TABLE IF NOT EXISTS `feed_group` (
+                id INTEGER PRIMARY KEY,
+                group_id INTEGER,
+                feed_id INTEGER,
+                UNIQUE(group_id, feed_id) ON CONFLICT IGNORE
+            );
+            CREATE TABLE IF NOT EXISTS `favicon` (
+                id INTEGER PRIMARY KEY,
+                data BLOB
+            );
+            CREATE TABLE IF NOT EXISTS `item` (
+                id INTEGER PRIMARY KEY,
+                feed_id INTEGER,
+                title TEXT,
+                author TEXT,
+                html BLOB,
+                url TEXT,
+                is_saved BOOLEAN,
+                is_read BOOLEAN,
+                created DATETIME
+            );
+            "#,
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        version: 2,
+        description: "add favicon to feeds",
+        up: |conn| add_column(conn, "feed", "favicon_id", "INTEGER"),
+    },
+    Migration {
+        version: 3,
+        description: "create link tables",
+        up: |conn| {
+            conn.execute_batch(
+                r#"
+            CREATE TABLE IF NOT EXISTS `link` (
+                id INTEGER PRIMARY KEY,
+                url TEXT UNIQUE ON CONFLICT IGNORE,
+                title TEXT
+            );
+            CREATE TABLE IF NOT EXISTS `link_item` (
+                id INTEGER PRIMARY KEY,
+                link_id INTEGER,
+                item_id INTEGER,
+                UNIQUE(link_id, item_id) ON CONFLICT IGNORE
+            );
+            "#,
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        version: 4,
+        description: "add cache validators to feeds",
+        up: |conn| {
+            add_column(conn, "feed", "etag", "TEXT")?;
+            add_column(conn, "feed", "last_modified", "TEXT")
+        },
+    },
+    Migration {
+        version: 5,
+        description: "add crawl error tracking to feeds",
+        up: |conn| {
+            add_column(conn, "feed", "last_error", "TEXT")?;
+            add_column(conn, "feed", "error_count", "INTEGER NOT NULL DEFAULT 0")?;
+            add_column(conn, "feed", "next_crawl_at", "DATETIME")
+        },
+    },
+    Migration {
+        version: 6,
+        description: "add crawl scheduling to feeds",
+        up: |conn| {
+            add_column(conn, "feed", "crawl_interval", "INTEGER")?;
+            add_column(conn, "feed", "update_hint", "INTEGER")
+        },
+    },
+    Migration {
+        version: 7,
+        description: "create full-text search index of items",
+        up: |conn| {
+            conn.execute_batch(
+                r#"
+            CREATE VIRTUAL TABLE IF NOT EXISTS `item_search` USING fts5(
+                title,
+                author,
+                body
+            );
+            DELETE FROM `item_search`;
+            "#,
+            )?;
+
+            let mut select = conn.prepare("SELECT id, title, author, html FROM `item`")?;
+            let mut insert = conn.prepare(
+                "INSERT INTO `item_search` (rowid, title, author, body) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            let mut rows = select.query(NO_PARAMS)?;
+            while let Some(row) = rows.next()? {
+                let html: Option<String> = row.get(3)?;
+                insert.execute(rusqlite::params![
+                    row.get::<_, u32>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    strip_html(&html.unwrap_or_default()),
+                ])?;
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        version: 8,
+        description: "add deduplication keys to items",
+        up: |conn| {
+            add_column(conn, "item", "guid", "TEXT")?;
+            add_column(conn, "item", "content_hash", "TEXT")?;
+            add_column(conn, "feed", "update_items", "BOOLEAN NOT NULL DEFAULT 0")?;
+
+            let mut select = conn.prepare("SELECT id, title, html FROM `item`")?;
+            let mut update =
+                conn.prepare("UPDATE `item` SET `content_hash` = ?1 WHERE `id` = ?2")?;
+            let mut rows = select.query(NO_PARAMS)?;
+            while let Some(row) = rows.next()? {
+                let title: Option<String> = row.get(1)?;
+                let html: Option<String> = row.get(2)?;
+                update.execute(rusqlite::params![
+                    content_hash(&title.unwrap_or_default(), &html.unwrap_or_default()),
+                    row.get::<_, u32>(0)?,
+                ])?;
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        version: 9,
+        description: "record where dates of items come from",
+        // items used to be stored only if they had a published date
+        up: |conn| {
+            add_column(
+                conn,
+                "item",
+                "date_source",
+                "TEXT NOT NULL DEFAULT 'published'",
+            )
+        },
+    },
+    Migration {
+        version: 10,
+        description: "add full content fetching",
+        up: |conn| {
+            add_column(conn, "feed", "full_content", "BOOLEAN NOT NULL DEFAULT 0")?;
+            add_column(conn, "item", "summary", "TEXT")
+        },
+    },
+    Migration {
+        version: 11,
+        description: "create filter rules and item tags",
+        up: |conn| {
+            conn.execute_batch(
+                r#"
+            CREATE TABLE IF NOT EXISTS `rule` (
+                id INTEGER PRIMARY KEY,
+                feed_id INTEGER,
+                group_id INTEGER,
+                field TEXT NOT NULL,
+                pattern TEXT NOT NULL,
+                is_regex BOOLEAN NOT NULL DEFAULT 0,
+                action TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS `item_tag` (
+                id INTEGER PRIMARY KEY,
+                item_id INTEGER,
+                tag TEXT,
+                UNIQUE(item_id, tag) ON CONFLICT IGNORE
+            );
+            "#,
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        version: 12,
+        description: "add users with their own subscriptions and item state",
+        up: |conn| {
+            conn.execute_batch(
+                r#"
+            CREATE TABLE IF NOT EXISTS `user` (
+                id INTEGER PRIMARY KEY,
+                username TEXT NOT NULL UNIQUE,
+                api_key TEXT NOT NULL,
+                created DATETIME NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS `subscription` (
+                id INTEGER PRIMARY KEY,
+                user_id INTEGER NOT NULL,
+                feed_id INTEGER NOT NULL,
+                UNIQUE(user_id, feed_id) ON CONFLICT IGNORE
+            );
+            CREATE TABLE IF NOT EXISTS `item_state` (
+                user_id INTEGER NOT NULL,
+                item_id INTEGER NOT NULL,
+                is_read BOOLEAN NOT NULL DEFAULT 0,
+                is_saved BOOLEAN NOT NULL DEFAULT 0,
+                PRIMARY KEY (user_id, item_id)
+            );
+            CREATE INDEX IF NOT EXISTS `item_state_item_id` ON `item_state` (item_id);
+            "#,
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        version: 13,
+        description: "add per-feed request headers",
+        up: |conn| {
+            add_column(conn, "feed", "user_agent", "TEXT")?;
+            add_column(conn, "feed", "headers", "TEXT")
+        },
+    },
+    Migration {
+        version: 14,
+        description: "record failed favicon lookups",
+        up: |conn| add_column(conn, "feed", "favicon_checked_at", "DATETIME"),
+    },
+];
+
+/// Adds a column to a table unless it already exists.
+fn add_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let exists = conn
+        .prepare(&format!("PRAGMA table_info(`{}`)", table))?
+        .query_map(NO_PARAMS, |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .any(|name| name == column);
+
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE `{}` ADD COLUMN `{}` {}", table, column, decl),
+            NO_PARAMS,
+        )?;
+    }
+    Ok(())
+}
+
+/// Schema version this binary is built for.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// Schema version of the database.
+pub fn current_version(conn: &Connection) -> Result<u32> {
+    Ok(conn.query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))?)
+}
+
+/// Ensures the database is not newer than this binary.
+pub fn check_version(conn: &Connection) -> Result<u32> {
+    let version = current_version(conn)?;
+    if version > latest_version() {
+        return Err(Error::SchemaTooNew {
+            version,
+            supported: latest_version(),
+        });
+    }
+    Ok(version)
+}
+
+/// Migrations that are not yet applied to the database.
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>> {
+    let version = check_version(conn)?;
+    Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+}
+
+/// Applies all pending migrations and returns them.
+pub fn migrate(conn: &Connection) -> Result<Vec<&'static Migration>> {
+    let pending = pending(conn)?;
+
+    for migration in pending.iter() {
+        info!(
+            "migrating database to version {}: {}",
+            migration.version, migration.description
+        );
+        let tx = conn.unchecked_transaction()?;
+        (migration.up)(&tx)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+
+    Ok(pending)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 0);
+        assert_eq!(pending(&conn).unwrap().len(), MIGRATIONS.len());
+
+        let applied = migrate(&conn).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+
+        // applying again is a no-op
+        assert!(migrate(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_migrate_unversioned() {
+        // databases created before migrations were introduced
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r#"
+        CREATE TABLE `feed` (
+            id INTEGER PRIMARY KEY,
+            title TEXT,
+            url TEXT,
+            site_url TEXT,
+            is_spark BOOLEAN,
+            last_updated DATETIME
+        );
+        INSERT INTO `feed` (title) VALUES ('feed');
+        "#,
+        )
+        .unwrap();
+
+        migrate(&conn).unwrap();
+        let favicon_id: Option<u32> = conn
+            .query_row("SELECT favicon_id FROM `feed`", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(favicon_id, None);
+    }
+
+    #[test]
+    fn test_migrate_search_index() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r#"
+        CREATE TABLE `item` (
+            id INTEGER PRIMARY KEY,
+            feed_id INTEGER,
+            title TEXT,
+            author TEXT,
+            html BLOB,
+            url TEXT,
+            is_saved BOOLEAN,
+            is_read BOOLEAN,
+            created DATETIME
+        );
+        INSERT INTO `item` (title, author, html) VALUES ('title', 'author', '<p>body</p>');
+        "#,
+        )
+        .unwrap();
+
+        migrate(&conn).unwrap();
+        let body: String = conn
+            .query_row(
+                "SELECT body FROM `item_search` WHERE `item_search` MATCH 'body'",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(body, "body");
+    }
+
+    #[test]
+    fn test_too_new() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", latest_version() + 1)
+            .unwrap();
+        assert!(migrate(&conn).is_err());
+    }
+}
@@ -1,13 +1,16 @@
 use chrono::{DateTime, TimeZone, Utc};
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{params, Connection, OptionalExtension, Row, NO_PARAMS};
+use rusqlite::{params, Connection, OptionalExtension, Row, ToSql, NO_PARAMS};
 use serde::Serialize;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::rc::Rc;
+use url::Url;
 
-use crate::client::HttpClient;
+use crate::client::{CacheValidators, Fetched, HttpClient, RequestHeaders};
 use crate::error::{Error, Result};
+use crate::sanitize::sanitize;
+use crate::utils::{content_hash, md5_hex, normalize_url, strip_html};
 
 pub trait Model: Sized {
     const TABLE: &'static str;
@@ -57,7 +60,7 @@ impl<T: Model + Sized> ModelExt<T> for T {
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 pub struct Group {
     pub id: u32,
     pub title: String,
@@ -68,19 +71,6 @@ impl Group {
         Self { id: 0, title }
     }
 
-    pub fn create_table(conn: &Connection) -> Result<()> {
-        conn.execute(
-            r#"
-        CREATE TABLE IF NOT EXISTS `group` (
-            id INTEGER PRIMARY KEY,
-            title TEXT UNIQUE ON CONFLICT IGNORE
-        )
-        "#,
-            NO_PARAMS,
-        )?;
-        Ok(())
-    }
-
     pub fn insert(mut self, conn: &Connection) -> Result<Self> {
         self.id = conn
             .prepare("INSERT INTO `group` (title) VALUES (?1)")?
@@ -94,6 +84,21 @@ impl Group {
             .map_err(Into::into)
     }
 
+    /// Groups the feed belongs to.
+    pub fn get_by_feed(conn: &Connection, feed_id: u32) -> Result<Vec<Self>> {
+        Ok(conn
+            .prepare(
+                r"
+        SELECT *
+        FROM `group`
+        WHERE `id` IN (
+            SELECT `group_id` FROM `feed_group` WHERE `feed_id` = ?1
+        )",
+            )?
+            .query_map(params![feed_id], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+
     pub fn get_feeds(&self, conn: &Connection) -> Result<Vec<Feed>> {
         Ok(conn
             .prepare(
@@ -119,22 +124,64 @@ impl Group {
         Ok(feed)
     }
 
+    /// Removes the feed from the group. Feeds that no longer belong to any group become sparks.
+    pub fn remove_feed(&self, conn: &Connection, mut feed: Feed) -> Result<Feed> {
+        conn.execute(
+            "DELETE FROM `feed_group` WHERE `group_id` = ?1 AND `feed_id` = ?2",
+            params![self.id, feed.id],
+        )?;
+        if Group::get_by_feed(conn, feed.id)?.is_empty() {
+            conn.execute(
+                "UPDATE `feed` SET `is_spark` = 1 WHERE `id` = ?1",
+                params![feed.id],
+            )?;
+            feed.is_spark = 1;
+        }
+        Ok(feed)
+    }
+
+    pub fn rename(mut self, conn: &Connection, title: String) -> Result<Self> {
+        conn.execute(
+            "UPDATE `group` SET `title` = ?1 WHERE `id` = ?2",
+            params![title, self.id],
+        )?;
+        self.title = title;
+        Ok(self)
+    }
+
+    /// Deletes the group. Its feeds that belong to no other group become sparks.
+    pub fn remove(self, conn: &Connection) -> Result<Self> {
+        if let Ok(feed_groups) = FeedGroup::get_by_group(conn, self.id) {
+            feed_groups.delete(conn)?;
+        }
+        Rule::delete_by_group(conn, self.id)?;
+        self.delete(conn)
+    }
+
     pub fn read(&self, conn: &Connection, before: Option<u32>) -> Result<()> {
+        self.set_read(conn, true, before)
+    }
+
+    pub fn unread(&self, conn: &Connection, before: Option<u32>) -> Result<()> {
+        self.set_read(conn, false, before)
+    }
+
+    fn set_read(&self, conn: &Connection, is_read: bool, before: Option<u32>) -> Result<()> {
         const BASE_SQL: &'static str = r"
         UPDATE `item`
-        SET `is_read` = 1
+        SET `is_read` = ?1
         WHERE `feed_id` IN (
-            SELECT `feed_id` FROM `feed_group` WHERE `group_id` = ?1
+            SELECT `feed_id` FROM `feed_group` WHERE `group_id` = ?2
         )";
 
         if let Some(before) = before {
             let before = Utc.timestamp(before as i64, 0);
-            dbg!(conn.execute(
-                &format!("{} AND `created` < ?2", BASE_SQL),
-                params![self.id, before],
-            ))?;
+            conn.execute(
+                &format!("{} AND `created` < ?3", BASE_SQL),
+                params![is_read, self.id, before],
+            )?;
         } else {
-            conn.execute(BASE_SQL, params![self.id])?;
+            conn.execute(BASE_SQL, params![is_read, self.id])?;
         }
 
         Ok(())
@@ -160,6 +207,9 @@ impl Model for Group {
     }
 }
 
+/// Days before looking for the favicon of a site again after failing to find one.
+const FAVICON_RETRY_DAYS: i64 = 7;
+
 #[derive(Debug, Serialize)]
 pub struct Feed {
     pub id: u32,
@@ -169,6 +219,40 @@ pub struct Feed {
     pub is_spark: u8,
     #[serde(serialize_with = "crate::utils::serialize_timestamp")]
     pub last_updated_on_time: DateTime<Utc>,
+    pub favicon_id: u32,
+    #[serde(skip)]
+    pub etag: Option<String>,
+    #[serde(skip)]
+    pub last_modified: Option<String>,
+    #[serde(skip)]
+    pub last_error: Option<String>,
+    #[serde(skip)]
+    pub error_count: u32,
+    /// The crawler skips this feed until this time, used to back off from failing feeds.
+    #[serde(skip)]
+    pub next_crawl_at: Option<DateTime<Utc>>,
+    /// Overrides the crawl interval of the server (unit: minutes)
+    #[serde(skip)]
+    pub crawl_interval: Option<u32>,
+    /// Minimum update period advertised by the feed with `<ttl>` or `<sy:updatePeriod>` (unit:
+    /// minutes)
+    #[serde(skip)]
+    pub update_hint: Option<u32>,
+    /// Updates stored items when their content changes in the feed
+    #[serde(skip)]
+    pub update_items: bool,
+    /// Replaces the content of new items with the main content of their web pages
+    #[serde(skip)]
+    pub full_content: bool,
+    /// Replaces the `User-Agent` sent when crawling this feed
+    #[serde(skip)]
+    pub user_agent: Option<String>,
+    /// Extra headers sent when crawling this feed, one `Name: Value` per line
+    #[serde(skip)]
+    pub headers: Option<String>,
+    /// Last failed attempt to find the favicon of the site, cleared when the site URL changes
+    #[serde(skip)]
+    pub favicon_checked_at: Option<DateTime<Utc>>,
 }
 
 impl Feed {
@@ -180,26 +264,22 @@ impl Feed {
             site_url,
             is_spark: 1,
             last_updated_on_time: Utc::now(),
+            favicon_id: 0,
+            etag: None,
+            last_modified: None,
+            last_error: None,
+            error_count: 0,
+            next_crawl_at: None,
+            crawl_interval: None,
+            update_hint: None,
+            update_items: false,
+            full_content: false,
+            user_agent: None,
+            headers: None,
+            favicon_checked_at: None,
         }
     }
 
-    pub fn create_table(conn: &Connection) -> Result<()> {
-        conn.execute(
-            r#"
-        CREATE TABLE IF NOT EXISTS `feed` (
-            id INTEGER PRIMARY KEY,
-            title TEXT,
-            url TEXT,
-            site_url TEXT,
-            is_spark BOOLEAN,
-            last_updated DATETIME
-        )
-        "#,
-            NO_PARAMS,
-        )?;
-        Ok(())
-    }
-
     pub fn get_by_url(conn: &Connection, url: &str) -> Result<Option<Self>> {
         Ok(conn
             .query_row(
@@ -210,6 +290,134 @@ impl Feed {
             .optional()?)
     }
 
+    /// Feeds that are due to be crawled at `now`.
+    pub fn all_due(conn: &Connection, now: DateTime<Utc>) -> Result<Vec<Self>> {
+        Ok(conn
+            .prepare("SELECT * FROM `feed` WHERE `next_crawl_at` IS NULL OR `next_crawl_at` <= ?1")?
+            .query_map(params![now], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Feeds that failed to crawl last time.
+    pub fn all_failing(conn: &Connection) -> Result<Vec<Self>> {
+        Ok(conn
+            .prepare("SELECT * FROM `feed` WHERE `error_count` > 0")?
+            .query_map(NO_PARAMS, Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Clears the error state of a feed after a successful crawl, and schedules the next crawl.
+    pub fn record_success(
+        conn: &Connection,
+        id: u32,
+        next_crawl_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        conn.execute(
+            r"
+        UPDATE `feed`
+        SET `last_error` = NULL, `error_count` = 0, `next_crawl_at` = ?1
+        WHERE `id` = ?2",
+            params![next_crawl_at, id],
+        )?;
+        Ok(())
+    }
+
+    /// Records a failed crawl, and returns the number of consecutive failures.
+    pub fn record_failure(conn: &Connection, id: u32, error: &str) -> Result<u32> {
+        conn.execute(
+            r"
+        UPDATE `feed`
+        SET `last_error` = ?1, `error_count` = `error_count` + 1
+        WHERE `id` = ?2",
+            params![error, id],
+        )?;
+        Ok(conn.query_row(
+            "SELECT `error_count` FROM `feed` WHERE `id` = ?1",
+            params![id],
+            |row| row.get(0),
+        )?)
+    }
+
+    pub fn set_crawl_interval(conn: &Connection, id: u32, minutes: Option<u32>) -> Result<()> {
+        conn.execute(
+            "UPDATE `feed` SET `crawl_interval` = ?1, `next_crawl_at` = NULL WHERE `id` = ?2",
+            params![minutes, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_update_items(conn: &Connection, id: u32, update_items: bool) -> Result<()> {
+        conn.execute(
+            "UPDATE `feed` SET `update_items` = ?1 WHERE `id` = ?2",
+            params![update_items, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_full_content(conn: &Connection, id: u32, full_content: bool) -> Result<()> {
+        conn.execute(
+            "UPDATE `feed` SET `full_content` = ?1 WHERE `id` = ?2",
+            params![full_content, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_user_agent(conn: &Connection, id: u32, user_agent: Option<&str>) -> Result<()> {
+        conn.execute(
+            "UPDATE `feed` SET `user_agent` = ?1 WHERE `id` = ?2",
+            params![user_agent, id],
+        )?;
+        Ok(())
+    }
+
+    /// Replaces the extra headers of a feed, written one `Name: Value` per line.
+    pub fn set_headers(conn: &Connection, id: u32, headers: Option<&str>) -> Result<()> {
+        if let Some(headers) = headers {
+            RequestHeaders::parse_extra(headers)?;
+        }
+        conn.execute(
+            "UPDATE `feed` SET `headers` = ?1 WHERE `id` = ?2",
+            params![headers, id],
+        )?;
+        Ok(())
+    }
+
+    /// Headers sent when crawling this feed. Web pages of its items only get the extra headers
+    /// when they share the origin of the feed, see `RequestHeaders::for_url`.
+    pub fn request_headers(&self) -> Result<RequestHeaders> {
+        Ok(RequestHeaders {
+            user_agent: self.user_agent.clone(),
+            extra: match self.headers.as_ref() {
+                Some(headers) => RequestHeaders::parse_extra(headers)?,
+                None => Vec::new(),
+            },
+        })
+    }
+
+    /// Average time between the most recent items of the feed.
+    pub fn observed_interval(&self, conn: &Connection) -> Result<Option<chrono::Duration>> {
+        const SAMPLE_SIZE: u32 = 10;
+        let created = conn
+            .prepare("SELECT `created` FROM `item` WHERE `feed_id` = ?1 ORDER BY `created` DESC LIMIT ?2")?
+            .query_map(params![self.id, SAMPLE_SIZE], |row| row.get::<_, DateTime<Utc>>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match (created.first(), created.last()) {
+            (Some(newest), Some(oldest)) if created.len() > 1 => {
+                Ok(Some((*newest - *oldest) / (created.len() as i32 - 1)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn set_next_crawl_at(conn: &Connection, id: u32, at: DateTime<Utc>) -> Result<()> {
+        conn.execute(
+            "UPDATE `feed` SET `next_crawl_at` = ?1 WHERE `id` = ?2",
+            params![at, id],
+        )?;
+        Ok(())
+    }
+
     pub fn insert(mut self, conn: &Connection) -> Result<Self> {
         self.id = conn
             .prepare("INSERT INTO `feed` (title, url, site_url, is_spark, last_updated) VALUES (?1, ?2, ?3, ?4, ?5)")?
@@ -217,6 +425,30 @@ impl Feed {
         Ok(self)
     }
 
+    /// Saves the title and site URL of the feed. A new site URL is looked up for a favicon again.
+    pub fn update(&self, conn: &Connection) -> Result<()> {
+        conn.execute(
+            r"
+        UPDATE `feed`
+        SET `title` = ?1,
+            `favicon_checked_at` =
+                CASE WHEN `site_url` = ?2 THEN `favicon_checked_at` ELSE NULL END,
+            `site_url` = ?2
+        WHERE `id` = ?3",
+            params![self.title, self.site_url, self.id],
+        )?;
+        Ok(())
+    }
+
+    /// Deletes the feed along with its items and group memberships.
+    pub fn remove(self, conn: &Connection) -> Result<Self> {
+        FeedGroup::delete_by_feed(conn, self.id)?;
+        Item::delete_by_feed(conn, self.id)?;
+        Rule::delete_by_feed(conn, self.id)?;
+        User::delete_subscriptions(conn, self.id)?;
+        self.delete(conn)
+    }
+
     pub fn items(&self, conn: &Connection, limit: Option<u32>) -> Result<Vec<Item>> {
         let mut stmt = "SELECT * FROM `item` WHERE `feed_id` = ?1 ORDER BY `id` DESC".to_owned();
         if let Some(limit) = limit {
@@ -229,79 +461,263 @@ impl Feed {
     }
 
     pub async fn crawl(mut self, state: crate::state::State) -> Result<Self> {
-        let exist_urls = {
+        let validators = CacheValidators {
+            etag: self.etag.clone(),
+            last_modified: self.last_modified.clone(),
+        };
+        let headers = self.request_headers()?;
+        let (content, validators) =
+            match HttpClient::get_conditional(&self.url, &validators, &headers).await? {
+                Fetched::Modified { body, validators } => (body, validators),
+                Fetched::NotModified => {
+                    log::debug!("feed {} is not modified", self.id);
+                    return self
+                        .finish_crawl(&state, Vec::new(), Vec::new(), None)
+                        .await;
+                }
+            };
+
+        let feed = parse_feed(&content[..], Some(&self.url))?;
+        self.update_hint = match (feed.ttl, crate::find::find_update_period(&content[..])) {
+            (Some(ttl), Some(period)) => Some(ttl.max(period)),
+            (ttl, period) => ttl.or(period),
+        };
+
+        let now = Utc::now();
+        let items = feed
+            .entries
+            .into_iter()
+            .rev()
+            .map(|entry| Item::from_entry(&self, entry, now))
+            .collect();
+        let (mut added, mut changed) = {
             let conn = state.db.get()?;
-            self.items(&conn, None)?
+            let (added, changed) = self.dedupe(&conn, items)?;
+
+            // rules are applied before fetching full contents, so dropped items are not fetched
+            let rules = RuleSet::for_feed(&conn, self.id)?;
+            let added = added
                 .into_iter()
-                .map(|item| item.url)
-                .collect::<HashSet<String>>()
+                .filter_map(|mut item| rules.apply(&mut item).map(|tags| (item, tags)))
+                .collect::<Vec<_>>();
+            (added, changed)
         };
-        let content = HttpClient::get(&self.url).await?;
-        let feed = feed_rs::parser::parse(&content[..])?;
-
-        let mut items = Vec::new();
-        for item in feed.entries.into_iter().rev() {
-            if let Some(link) = item.links.first() {
-                if exist_urls.contains(&link.href) {
-                    continue;
+
+        if self.full_content {
+            let origin = Url::parse(&self.url)?;
+            // newest items first, so the cap leaves out the oldest ones of a first crawl
+            let added = added.iter_mut().rev().map(|(item, _)| item);
+            let items = added
+                .chain(changed.iter_mut())
+                .filter(|item| !item.url.is_empty())
+                .collect::<Vec<_>>();
+            if items.len() > FULL_CONTENT_LIMIT {
+                log::info!(
+                    "fetching full content of {} of {} items of feed {}",
+                    FULL_CONTENT_LIMIT,
+                    items.len(),
+                    self.id
+                );
+            }
+            for (idx, item) in items.into_iter().take(FULL_CONTENT_LIMIT).enumerate() {
+                if idx > 0 {
+                    async_std::task::sleep(state.host_delay()).await;
+                }
+                let headers = headers.for_url(&origin, &item.url);
+                if let Err(e) = item.fetch_full_content(&headers).await {
+                    log::warn!("unable to fetch full content of {}: {:?}", item.url, e);
                 }
+            }
+        }
 
-                let created = if let Some(published) = item.published {
-                    published
-                } else {
-                    continue;
-                };
+        self.finish_crawl(&state, added, changed, Some(validators))
+            .await
+    }
+
+    /// Splits crawled items into new items and stored items whose content changed. Items are
+    /// matched by GUID first, then by normalized URL, and then by content. Changed items are only
+    /// returned if the feed has `update_items` set, with the ids of the stored items.
+    pub fn dedupe(&self, conn: &Connection, items: Vec<Item>) -> Result<(Vec<Item>, Vec<Item>)> {
+        let mut seen = SeenItems::default();
+        let mut stmt =
+            conn.prepare("SELECT id, guid, url, content_hash FROM `item` WHERE `feed_id` = ?1")?;
+        let mut rows = stmt.query(params![self.id])?;
+        while let Some(row) = rows.next()? {
+            let url: Option<String> = row.get(2)?;
+            seen.insert(
+                row.get(0)?,
+                row.get(1)?,
+                &normalize_url(&url.unwrap_or_default()),
+                row.get(3)?,
+            );
+        }
 
-                let author = item
-                    .authors
-                    .iter()
-                    .map(|a| a.name.as_str())
-                    .collect::<Vec<_>>()
-                    .join(",");
-                let url = link.href.clone();
-
-                items.push(Item {
-                    id: 0,
-                    feed_id: self.id,
-                    title: item.title.map(|t| t.content).unwrap_or_default(),
-                    author,
-                    html: item
-                        .content
-                        .and_then(|c| c.body)
-                        .or(item.summary.map(|c| c.content))
-                        .unwrap_or_default(),
-                    url,
-                    is_saved: 0,
-                    is_read: 0,
-                    created_on_time: created,
-                });
+        let mut added = Vec::new();
+        let mut changed = Vec::new();
+        for mut item in items.into_iter() {
+            match seen.find(&item) {
+                None => {
+                    seen.insert(0, item.guid.clone(), &item.url, item.content_hash.clone());
+                    added.push(item);
+                }
+                // items that are not stored yet are duplicates within the feed
+                Some((id, content_hash)) => {
+                    if self.update_items && *id != 0 && *content_hash != item.content_hash {
+                        item.id = *id;
+                        changed.push(item);
+                    }
+                }
             }
         }
 
+        Ok((added, changed))
+    }
+
+    /// Stores new items along with their tags, changed items, and cache validators if the feed was
+    /// modified.
+    async fn finish_crawl(
+        mut self,
+        state: &crate::state::State,
+        added: Vec<(Item, Vec<String>)>,
+        changed: Vec<Item>,
+        validators: Option<CacheValidators>,
+    ) -> Result<Self> {
         let now = Utc::now();
         {
             let conn = state.db.get()?;
-            Item::insert_multi(&conn, items)?;
+            let (added, tags): (Vec<_>, Vec<_>) = added.into_iter().unzip();
+            for (item, tags) in Item::insert_multi(&conn, added)?.into_iter().zip(tags) {
+                Link::insert_for_item(&conn, &item)?;
+                Item::add_tags(&conn, item.id, &tags)?;
+                User::share_item_state(&conn, &item)?;
+            }
+            for item in changed.iter() {
+                item.update_content(&conn)?;
+                Link::insert_for_item(&conn, item)?;
+            }
             conn.execute(
                 "UPDATE `feed` SET `last_updated` = ?1 WHERE id = ?2",
                 params![now, self.id],
             )?;
+
+            if let Some(validators) = validators {
+                conn.execute(
+                    r"
+                UPDATE `feed`
+                SET `etag` = ?1, `last_modified` = ?2, `update_hint` = ?3
+                WHERE id = ?4",
+                    params![
+                        validators.etag,
+                        validators.last_modified,
+                        self.update_hint,
+                        self.id
+                    ],
+                )?;
+                self.etag = validators.etag;
+                self.last_modified = validators.last_modified;
+            }
         }
         self.last_updated_on_time = now;
 
+        if self.favicon_id == 0 && self.favicon_due(now) {
+            if let Err(e) = self.update_favicon(state).await {
+                log::warn!("unable to update favicon for feed {}: {:?}", self.id, e);
+                let conn = state.db.get()?;
+                self.record_favicon_failure(&conn, now)?;
+            }
+        }
+
         Ok(self)
     }
 
+    /// Whether to look for a favicon at `now`. Sites without one are only checked again after
+    /// `FAVICON_RETRY_DAYS`.
+    fn favicon_due(&self, now: DateTime<Utc>) -> bool {
+        match self.favicon_checked_at {
+            Some(at) => now - at >= chrono::Duration::days(FAVICON_RETRY_DAYS),
+            None => true,
+        }
+    }
+
+    fn record_favicon_failure(&mut self, conn: &Connection, now: DateTime<Utc>) -> Result<()> {
+        conn.execute(
+            "UPDATE `feed` SET `favicon_checked_at` = ?1 WHERE `id` = ?2",
+            params![now, self.id],
+        )?;
+        self.favicon_checked_at = Some(now);
+        Ok(())
+    }
+
+    /// Fetches the favicon of the feed's website and links it to the feed.
+    pub async fn update_favicon(&mut self, state: &crate::state::State) -> Result<()> {
+        let data = crate::favicon::fetch(&self.site_url).await?;
+
+        let conn = state.db.get()?;
+        let favicon = Favicon::new(data).insert(&conn)?;
+        conn.execute(
+            "UPDATE `feed` SET `favicon_id` = ?1 WHERE id = ?2",
+            params![favicon.id, self.id],
+        )?;
+        self.favicon_id = favicon.id;
+
+        Ok(())
+    }
+
     pub fn read(&self, conn: &Connection, before: Option<u32>) -> Result<()> {
-        const BASE_SQL: &'static str = "UPDATE `item` SET `is_read` = 1 WHERE `feed_id` = ?1";
+        self.set_read(conn, true, before)
+    }
+
+    pub fn unread(&self, conn: &Connection, before: Option<u32>) -> Result<()> {
+        self.set_read(conn, false, before)
+    }
+
+    fn set_read(&self, conn: &Connection, is_read: bool, before: Option<u32>) -> Result<()> {
+        const BASE_SQL: &'static str = "UPDATE `item` SET `is_read` = ?1 WHERE `feed_id` = ?2";
+        if let Some(before) = before {
+            let before = Utc.timestamp(before as i64, 0);
+            conn.execute(
+                &format!("{} AND `created` < ?3", BASE_SQL),
+                params![is_read, self.id, before],
+            )?;
+        } else {
+            conn.execute(BASE_SQL, params![is_read, self.id])?;
+        }
+
+        Ok(())
+    }
+
+    /// Marks items of all spark feeds (`is_spark = true`, Fever's group `-1`) or all non-spark
+    /// feeds (`is_spark = false`, Fever's "Kindling" group `0`) as read.
+    pub fn read_by_spark(conn: &Connection, is_spark: bool, before: Option<u32>) -> Result<()> {
+        Self::set_read_by_spark(conn, is_spark, true, before)
+    }
+
+    /// Marks items of all spark feeds or all non-spark feeds as unread. See `read_by_spark`.
+    pub fn unread_by_spark(conn: &Connection, is_spark: bool, before: Option<u32>) -> Result<()> {
+        Self::set_read_by_spark(conn, is_spark, false, before)
+    }
+
+    fn set_read_by_spark(
+        conn: &Connection,
+        is_spark: bool,
+        is_read: bool,
+        before: Option<u32>,
+    ) -> Result<()> {
+        const BASE_SQL: &'static str = r"
+        UPDATE `item`
+        SET `is_read` = ?1
+        WHERE `feed_id` IN (
+            SELECT `id` FROM `feed` WHERE `is_spark` = ?2
+        )";
+
         if let Some(before) = before {
             let before = Utc.timestamp(before as i64, 0);
             conn.execute(
-                &format!("{} AND `created` < ?2", BASE_SQL),
-                params![self.id, before],
+                &format!("{} AND `created` < ?3", BASE_SQL),
+                params![is_read, is_spark, before],
             )?;
         } else {
-            conn.execute(BASE_SQL, params![self.id])?;
+            conn.execute(BASE_SQL, params![is_read, is_spark])?;
         }
 
         Ok(())
@@ -319,6 +735,19 @@ impl Model for Feed {
             site_url: row.get(3)?,
             is_spark: row.get(4)?,
             last_updated_on_time: row.get(5)?,
+            favicon_id: row.get::<_, Option<u32>>(6)?.unwrap_or_default(),
+            etag: row.get(7)?,
+            last_modified: row.get(8)?,
+            last_error: row.get(9)?,
+            error_count: row.get(10)?,
+            next_crawl_at: row.get(11)?,
+            crawl_interval: row.get(12)?,
+            update_hint: row.get(13)?,
+            update_items: row.get(14)?,
+            full_content: row.get(15)?,
+            user_agent: row.get(16)?,
+            headers: row.get(17)?,
+            favicon_checked_at: row.get(18)?,
         })
     }
 
@@ -359,21 +788,6 @@ impl FeedGroup {
         ser.serialize_str(&crate::utils::comma_join_vec(ids))
     }
 
-    pub fn create_table(conn: &Connection) -> Result<()> {
-        conn.execute(
-            r#"
-        CREATE TABLE IF NOT EXISTS `feed_group` (
-            id INTEGER PRIMARY KEY,
-            group_id INTEGER,
-            feed_id INTEGER,
-            UNIQUE(group_id, feed_id) ON CONFLICT IGNORE
-        )
-        "#,
-            NO_PARAMS,
-        )?;
-        Ok(())
-    }
-
     fn fold_group(mut indices: Vec<(u32, u32)>) -> Result<Vec<Self>> {
         indices.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
         Ok(indices
@@ -434,7 +848,12 @@ impl FeedGroup {
         Ok(())
     }
 
+    /// Removes all feeds from the group. Feeds that no longer belong to any group become sparks.
     pub fn delete(mut self, conn: &Connection) -> Result<Self> {
+        conn.execute(
+            "DELETE FROM `feed_group` WHERE `group_id` = ?1",
+            params![self.group_id],
+        )?;
         let rarray = Rc::new(
             self.feed_ids
                 .iter()
@@ -442,35 +861,34 @@ impl FeedGroup {
                 .map(rusqlite::types::Value::from)
                 .collect::<Vec<_>>(),
         );
-        conn.prepare("UPDATE `feed` SET `is_spark` = 1 WHERE `id` IN rarray(?) ")?
-            .execute(&[&rarray])?;
-        conn.execute(
-            "DELETE FROM `feed_group` WHERE `group_id` = ?1",
-            params![self.group_id],
-        )?;
+        conn.prepare(
+            r"
+        UPDATE `feed` SET `is_spark` = 1
+        WHERE `id` IN rarray(?1) AND `id` NOT IN (SELECT `feed_id` FROM `feed_group`)",
+        )?
+        .execute(&[&rarray])?;
         self.feed_ids.clear();
         Ok(self)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Favicon {
-    id: u32,
-    data: String,
+    pub id: u32,
+    /// Base64 encoded image in Fever's format, e.g. `image/png;base64,...`
+    pub data: String,
 }
 
 impl Favicon {
-    pub fn create_table(conn: &Connection) -> Result<()> {
-        conn.execute(
-            r#"
-        CREATE TABLE IF NOT EXISTS `favicon` (
-            id INTEGER PRIMARY KEY,
-            data BLOB
-        )
-        "#,
-            NO_PARAMS,
-        )?;
-        Ok(())
+    pub fn new(data: String) -> Self {
+        Self { id: 0, data }
+    }
+
+    pub fn insert(mut self, conn: &Connection) -> Result<Self> {
+        self.id = conn
+            .prepare("INSERT INTO `favicon` (data) VALUES (?1)")?
+            .insert(params![self.data])? as u32;
+        Ok(self)
     }
 }
 
@@ -493,6 +911,42 @@ impl Model for Favicon {
     }
 }
 
+/// Where the date of an item comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DateSource {
+    Published,
+    Updated,
+    /// The entry has no date; the time it was first crawled is used instead.
+    FirstSeen,
+}
+
+impl DateSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Published => "published",
+            Self::Updated => "updated",
+            Self::FirstSeen => "first_seen",
+        }
+    }
+}
+
+impl rusqlite::types::ToSql for DateSource {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl rusqlite::types::FromSql for DateSource {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        match value.as_str()? {
+            "published" => Ok(Self::Published),
+            "updated" => Ok(Self::Updated),
+            "first_seen" => Ok(Self::FirstSeen),
+            _ => Err(rusqlite::types::FromSqlError::InvalidType),
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct Item {
     pub id: u32,
@@ -505,38 +959,119 @@ pub struct Item {
     pub is_read: u8,
     #[serde(serialize_with = "crate::utils::serialize_timestamp")]
     pub created_on_time: DateTime<Utc>,
+    /// Identifier of the entry in the feed (`<guid>` in RSS, `<id>` in Atom)
+    #[serde(skip)]
+    pub guid: Option<String>,
+    /// Hash of the title and content, used to detect duplicates and changes
+    #[serde(skip)]
+    pub content_hash: Option<String>,
+    #[serde(skip)]
+    pub date_source: DateSource,
+    /// Content from the feed, when `html` is the full content fetched from the web page
+    #[serde(skip)]
+    pub summary: Option<String>,
 }
 
 impl Item {
-    pub fn create_table(conn: &Connection) -> Result<()> {
-        conn.execute(
-            r#"
-        CREATE TABLE IF NOT EXISTS `item` (
-            id INTEGER PRIMARY KEY,
-            feed_id INTEGER,
-            title TEXT,
-            author TEXT,
-            html BLOB,
-            url TEXT,
-            is_saved BOOLEAN,
-            is_read BOOLEAN,
-            created DATETIME
-        )
-        "#,
-            NO_PARAMS,
-        )?;
+    /// Converts a feed entry. The item is dated by when the entry was published, or else last
+    /// updated, or else `first_seen`. Its content is sanitized, with relative URLs resolved against
+    /// the `xml:base` of the content, or else the entry link, or else the feed URL.
+    pub fn from_entry(
+        feed: &Feed,
+        entry: feed_rs::model::Entry,
+        first_seen: DateTime<Utc>,
+    ) -> Self {
+        let (created, date_source) = match (entry.published, entry.updated) {
+            (Some(published), _) => (published, DateSource::Published),
+            (None, Some(updated)) => (updated, DateSource::Updated),
+            (None, None) => (first_seen, DateSource::FirstSeen),
+        };
+        let author = entry
+            .authors
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        let url = entry
+            .links
+            .first()
+            .map(|link| normalize_url(&link.href))
+            .unwrap_or_default();
+        let title = entry.title.map(|t| t.content).unwrap_or_default();
+
+        // feed-rs keeps the `xml:base` of Atom content on the entry, and that of RSS content as
+        // its source
+        let base = entry
+            .base
+            .as_deref()
+            .into_iter()
+            .chain(
+                entry
+                    .content
+                    .as_ref()
+                    .filter(|c| c.body.is_some())
+                    .and_then(|c| c.src.as_ref())
+                    .map(|src| src.href.as_str()),
+            )
+            .chain(entry.links.first().map(|link| link.href.as_str()))
+            .chain(Some(feed.url.as_str()))
+            .find_map(|url| Url::parse(url).ok());
+        let raw_html = entry
+            .content
+            .and_then(|c| c.body)
+            .or(entry.summary.map(|c| c.content))
+            .unwrap_or_default();
+        let html = sanitize(&raw_html, base.as_ref());
+
+        Item {
+            id: 0,
+            feed_id: feed.id,
+            // hashed before sanitizing, so changes to the sanitizer are not mistaken for edits
+            content_hash: Some(content_hash(&title, &raw_html)),
+            guid: Some(entry.id).filter(|id| !id.is_empty()),
+            title,
+            author,
+            html,
+            url,
+            is_saved: 0,
+            is_read: 0,
+            created_on_time: created,
+            date_source,
+            summary: None,
+        }
+    }
+
+    /// Replaces the content with the main content of the item's web page, keeping the content
+    /// from the feed as summary.
+    pub async fn fetch_full_content(&mut self, headers: &RequestHeaders) -> Result<()> {
+        let base = Url::parse(&self.url)?;
+        let page = HttpClient::get_with_headers(&self.url, headers).await?;
+        let page = String::from_utf8_lossy(&page);
+        let content = crate::extract::extract(&page)
+            .ok_or_else(|| Error::message(format!("unable to find content of {}", self.url)))?;
+
+        let html = sanitize(content, Some(&base));
+        self.summary = Some(std::mem::replace(&mut self.html, html));
         Ok(())
     }
 
-    pub fn insert_multi(conn: &Connection, items: Vec<Item>) -> Result<()> {
+    pub fn insert_multi(conn: &Connection, items: Vec<Item>) -> Result<Vec<Item>> {
         let mut stmt = conn.prepare(
             r"
-        INSERT INTO `item` (feed_id, title, author, html, url, is_saved, is_read, created)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        INSERT INTO `item` (
+            feed_id, title, author, html, url, is_saved, is_read, created, guid, content_hash,
+            date_source, summary
+        )
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        )?;
+
+        let mut search = conn.prepare(
+            "INSERT INTO `item_search` (rowid, title, author, body) VALUES (?1, ?2, ?3, ?4)",
         )?;
 
-        for item in items.into_iter() {
-            stmt.execute(params![
+        let mut result = Vec::with_capacity(items.len());
+        for mut item in items.into_iter() {
+            item.id = stmt.insert(params![
                 item.feed_id,
                 item.title,
                 item.author,
@@ -545,88 +1080,1755 @@ impl Item {
                 item.is_saved,
                 item.is_read,
                 item.created_on_time,
+                item.guid,
+                item.content_hash,
+                item.date_source,
+                item.summary,
+            ])? as u32;
+            search.execute(params![
+                item.id,
+                item.title,
+                item.author,
+                strip_html(&item.html)
             ])?;
+            result.push(item);
         }
 
         stmt.finalize()?;
+        search.finalize()?;
+        Ok(result)
+    }
+
+    /// Saves the content of a changed item along with its search index entry. The read and saved
+    /// states are kept.
+    pub fn update_content(&self, conn: &Connection) -> Result<()> {
+        conn.execute(
+            r"
+        UPDATE `item`
+        SET `title` = ?1, `author` = ?2, `html` = ?3, `url` = ?4, `guid` = ?5, `content_hash` = ?6,
+            `summary` = ?7
+        WHERE `id` = ?8",
+            params![
+                self.title,
+                self.author,
+                self.html,
+                self.url,
+                self.guid,
+                self.content_hash,
+                self.summary,
+                self.id
+            ],
+        )?;
+        conn.execute(
+            "UPDATE `item_search` SET `title` = ?1, `author` = ?2, `body` = ?3 WHERE `rowid` = ?4",
+            params![self.title, self.author, strip_html(&self.html), self.id],
+        )?;
         Ok(())
     }
 
-    pub fn select(conn: &Connection, offset: Option<u32>, desc: bool) -> Result<Vec<Self>> {
-        let mut stmt = "SELECT * FROM `item` ORDER BY `id` ".to_owned();
-        if desc {
-            stmt.push_str("DESC");
-        }
-        stmt.push_str(" LIMIT 50");
-        if let Some(offset) = offset {
-            stmt.push_str(&format!(" OFFSET {}", offset));
+    pub fn add_tags(conn: &Connection, id: u32, tags: &[String]) -> Result<()> {
+        let mut stmt = conn.prepare("INSERT INTO `item_tag` (item_id, tag) VALUES (?1, ?2)")?;
+        for tag in tags.iter() {
+            stmt.execute(params![id, tag])?;
         }
-        Ok(conn
-            .prepare(&stmt)?
-            .query_map(NO_PARAMS, Self::from_row)?
+        Ok(())
+    }
+
+    /// Maps item ids to their tags.
+    pub fn tags_multi(conn: &Connection, ids: &[u32]) -> Result<HashMap<u32, Vec<String>>> {
+        let rarray = Rc::new(
+            ids.iter()
+                .map(|&id| id as i64)
+                .map(rusqlite::types::Value::from)
+                .collect::<Vec<_>>(),
+        );
+        let mut result: HashMap<u32, Vec<String>> = HashMap::new();
+        let mut stmt = conn.prepare(
+            "SELECT `item_id`, `tag` FROM `item_tag` WHERE `item_id` IN rarray(?1) ORDER BY `id`",
+        )?;
+        let mut rows = stmt.query([&rarray])?;
+        while let Some(row) = rows.next()? {
+            result.entry(row.get(0)?).or_default().push(row.get(1)?);
+        }
+        Ok(result)
+    }
+
+    /// All tags given to items.
+    pub fn all_tags(conn: &Connection) -> Result<Vec<String>> {
+        Ok(conn
+            .prepare("SELECT DISTINCT `tag` FROM `item_tag` ORDER BY `tag`")?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<Result<_, _>>()?)
+    }
+
+    pub fn delete_by_feed(conn: &Connection, feed_id: u32) -> Result<usize> {
+        Link::delete_by_feed(conn, feed_id)?;
+        conn.execute(
+            "DELETE FROM `item_tag` WHERE `item_id` IN (SELECT `id` FROM `item` WHERE `feed_id` = ?1)",
+            params![feed_id],
+        )?;
+        conn.execute(
+            "DELETE FROM `item_state` WHERE `item_id` IN (SELECT `id` FROM `item` WHERE `feed_id` = ?1)",
+            params![feed_id],
+        )?;
+        conn.execute(
+            "DELETE FROM `item_search` WHERE `rowid` IN (SELECT `id` FROM `item` WHERE `feed_id` = ?1)",
+            params![feed_id],
+        )?;
+        Ok(conn.execute("DELETE FROM `item` WHERE `feed_id` = ?1", params![feed_id])?)
+    }
+
+    /// Deletes the items along with their links and search index entries.
+    pub fn delete_multi(conn: &Connection, ids: &[u32]) -> Result<usize> {
+        let rarray = Rc::new(
+            ids.iter()
+                .map(|&id| id as i64)
+                .map(rusqlite::types::Value::from)
+                .collect::<Vec<_>>(),
+        );
+        conn.execute(
+            "DELETE FROM `link_item` WHERE `item_id` IN rarray(?1)",
+            [&rarray],
+        )?;
+        conn.execute(
+            "DELETE FROM `item_search` WHERE `rowid` IN rarray(?1)",
+            [&rarray],
+        )?;
+        conn.execute(
+            "DELETE FROM `item_tag` WHERE `item_id` IN rarray(?1)",
+            [&rarray],
+        )?;
+        conn.execute(
+            "DELETE FROM `item_state` WHERE `item_id` IN rarray(?1)",
+            [&rarray],
+        )?;
+        Ok(conn.execute("DELETE FROM `item` WHERE `id` IN rarray(?1)", [&rarray])?)
+    }
+
+    pub fn unread_ids(conn: &Connection) -> Result<Vec<u32>> {
+        Ok(conn
+            .prepare("SELECT id FROM `item` WHERE `is_read` = 0")?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<Result<_, _>>()?)
+    }
+
+    pub fn get_multi(conn: &Connection, ids: &[u32]) -> Result<Vec<Self>> {
+        let rarray = Rc::new(
+            ids.iter()
+                .map(|&id| id as i64)
+                .map(rusqlite::types::Value::from)
+                .collect::<Vec<_>>(),
+        );
+        Ok(conn
+            .prepare("SELECT * FROM `item` WHERE `id` IN rarray(?1) ORDER BY `id`")?
+            .query_map([&rarray], Item::from_row)?
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Marks all items as read, optionally only those created before `before`.
+    pub fn read_all(conn: &Connection, before: Option<u32>) -> Result<()> {
+        const BASE_SQL: &'static str = "UPDATE `item` SET `is_read` = 1";
+        if let Some(before) = before {
+            let before = Utc.timestamp(before as i64, 0);
+            conn.execute(
+                &format!("{} WHERE `created` < ?1", BASE_SQL),
+                params![before],
+            )?;
+        } else {
+            conn.execute(BASE_SQL, NO_PARAMS)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn saved_ids(conn: &Connection) -> Result<Vec<u32>> {
+        Ok(conn
+            .prepare("SELECT id FROM `item` WHERE `is_saved` = 1")?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<Result<_, _>>()?)
+    }
+
+    pub fn read(mut self, conn: &Connection) -> Result<Self> {
+        conn.execute(
+            "UPDATE `item` SET `is_read` = 1 WHERE `id` = ?1",
+            params![self.id],
+        )?;
+        self.is_read = 1;
+        Ok(self)
+    }
+
+    pub fn unread(mut self, conn: &Connection) -> Result<Self> {
+        conn.execute(
+            "UPDATE `item` SET `is_read` = 0 WHERE `id` = ?1",
+            params![self.id],
+        )?;
+        self.is_read = 0;
+        Ok(self)
+    }
+
+    pub fn save(mut self, conn: &Connection) -> Result<Self> {
+        conn.execute(
+            "UPDATE `item` SET `is_saved` = 1 WHERE `id` = ?1",
+            params![self.id],
+        )?;
+        self.is_saved = 1;
+        Ok(self)
+    }
+
+    pub fn unsave(mut self, conn: &Connection) -> Result<Self> {
+        conn.execute(
+            "UPDATE `item` SET `is_saved` = 0 WHERE `id` = ?1",
+            params![self.id],
+        )?;
+        self.is_saved = 0;
+        Ok(self)
+    }
+}
+
+impl Model for Item {
+    const TABLE: &'static str = "item";
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            feed_id: row.get(1)?,
+            title: row.get(2)?,
+            author: row.get(3)?,
+            html: row.get(4)?,
+            url: row.get(5)?,
+            is_saved: row.get(6)?,
+            is_read: row.get(7)?,
+            created_on_time: row.get(8)?,
+            guid: row.get(9)?,
+            content_hash: row.get(10)?,
+            date_source: row.get(11)?,
+            summary: row.get(12)?,
+        })
+    }
+
+    fn set_id(&mut self, id: u32) {
+        self.id = id;
+    }
+
+    fn get_id(&self) -> u32 {
+        self.id
+    }
+}
+
+/// Parses a feed, leaving the id of entries without one empty. feed_rs otherwise makes one up from
+/// the link and title, or at random, which must not be mistaken for a GUID set by the publisher.
+fn parse_feed(content: &[u8], uri: Option<&str>) -> Result<feed_rs::model::Feed> {
+    Ok(feed_rs::parser::Builder::new()
+        .base_uri(uri)
+        .id_generator(|_, _, _| String::new())
+        .build()
+        .parse(content)?)
+}
+
+/// Items of a feed, indexed by the keys used to detect duplicates.
+#[derive(Default)]
+struct SeenItems {
+    /// Ids and content hashes, `0` for items not stored yet
+    items: Vec<(u32, Option<String>)>,
+    guids: HashMap<String, usize>,
+    urls: HashMap<String, usize>,
+    content_hashes: HashMap<String, usize>,
+}
+
+impl SeenItems {
+    fn insert(&mut self, id: u32, guid: Option<String>, url: &str, content_hash: Option<String>) {
+        let index = self.items.len();
+        if let Some(guid) = guid {
+            self.guids.entry(guid).or_insert(index);
+        }
+        if !url.is_empty() {
+            self.urls.entry(url.to_owned()).or_insert(index);
+        }
+        if let Some(content_hash) = &content_hash {
+            self.content_hashes
+                .entry(content_hash.clone())
+                .or_insert(index);
+        }
+        self.items.push((id, content_hash));
+    }
+
+    fn find(&self, item: &Item) -> Option<&(u32, Option<String>)> {
+        let index = item
+            .guid
+            .as_ref()
+            .and_then(|guid| self.guids.get(guid))
+            .or_else(|| self.urls.get(&item.url))
+            .or_else(|| {
+                // entries that identify themselves are new when they match nothing, even if
+                // their content repeats an older one
+                if item.guid.is_some() || !item.url.is_empty() {
+                    return None;
+                }
+                item.content_hash
+                    .as_ref()
+                    .and_then(|content_hash| self.content_hashes.get(content_hash))
+            })?;
+        self.items.get(*index)
+    }
+}
+
+/// An outbound link found in item bodies.
+#[derive(Debug, Serialize)]
+pub struct Link {
+    pub id: u32,
+    pub url: String,
+    pub title: String,
+}
+
+/// A link along with its temperature, in the format of Fever's `?links` endpoint.
+#[derive(Debug, Serialize)]
+pub struct HotLink {
+    pub id: u32,
+    pub feed_id: u32,
+    pub item_id: u32,
+    pub temperature: f64,
+    pub is_item: u8,
+    pub is_local: u8,
+    pub is_saved: u8,
+    pub title: String,
+    pub url: String,
+    #[serde(serialize_with = "FeedGroup::serialize_json")]
+    pub item_ids: Vec<u32>,
+}
+
+/// Maximum number of web pages fetched for full content in a single crawl of a feed. Other items
+/// keep the content found in the feed.
+const FULL_CONTENT_LIMIT: usize = 20;
+
+/// Number of hot links returned per page.
+const HOT_LINK_PAGE_SIZE: usize = 50;
+
+/// A link needs to be referenced by at least this many items to be considered hot.
+const HOT_LINK_MIN_ITEMS: usize = 2;
+
+impl Link {
+    /// Extracts outbound links from the body of `item` and records them.
+    ///
+    /// Relative links are resolved against the item's url, and links pointing to the same host as
+    /// the item are not considered outbound.
+    pub fn insert_for_item(conn: &Connection, item: &Item) -> Result<()> {
+        let base = match url::Url::parse(&item.url) {
+            Ok(base) => base,
+            Err(_) => return Ok(()),
+        };
+
+        let mut seen = HashSet::new();
+        for (href, title) in crate::find::find_anchors(item.html.as_bytes()) {
+            let mut url = match base.join(&href) {
+                Ok(url) => url,
+                Err(_) => continue,
+            };
+            url.set_fragment(None);
+
+            if !(url.scheme() == "http" || url.scheme() == "https")
+                || url.host_str() == base.host_str()
+            {
+                continue;
+            }
+
+            let url = String::from(url);
+            if !seen.insert(url.clone()) {
+                continue;
+            }
+
+            conn.execute(
+                "INSERT INTO `link` (url, title) VALUES (?1, ?2)",
+                params![url, title],
+            )?;
+            conn.execute(
+                r"
+            INSERT INTO `link_item` (link_id, item_id)
+            SELECT `id`, ?2 FROM `link` WHERE `url` = ?1",
+                params![url, item.id],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Unlinks the items of a feed, and deletes links no longer referenced by any item.
+    pub fn delete_by_feed(conn: &Connection, feed_id: u32) -> Result<usize> {
+        let deleted = conn.execute(
+            r"
+        DELETE FROM `link_item`
+        WHERE `item_id` IN (
+            SELECT `id` FROM `item` WHERE `feed_id` = ?1
+        )",
+            params![feed_id],
+        )?;
+        Self::delete_orphans(conn)?;
+        Ok(deleted)
+    }
+
+    /// Deletes links that are no longer referenced by any item.
+    pub fn delete_orphans(conn: &Connection) -> Result<usize> {
+        Ok(conn.execute(
+            "DELETE FROM `link` WHERE `id` NOT IN (SELECT `link_id` FROM `link_item`)",
+            NO_PARAMS,
+        )?)
+    }
+
+    /// Computes hot links referenced by items created between `offset + range` days ago and
+    /// `offset` days ago. `page` starts from 1.
+    ///
+    /// Every referencing item adds up to 10 degrees to the link's temperature, fading linearly
+    /// with the age of the item within the window.
+    pub fn hot(conn: &Connection, offset: u32, range: u32, page: u32) -> Result<Vec<HotLink>> {
+        Self::hot_for(conn, None, offset, range, page)
+    }
+
+    /// Computes hot links, only considering items of feeds `user_id` subscribed to if set.
+    fn hot_for(
+        conn: &Connection,
+        user_id: Option<u32>,
+        offset: u32,
+        range: u32,
+        page: u32,
+    ) -> Result<Vec<HotLink>> {
+        let subscribed = match user_id {
+            Some(user_id) => subscribed_condition(user_id),
+            None => "1".to_owned(),
+        };
+        let range = range.max(1);
+        let end = Utc::now() - chrono::Duration::days(offset as i64);
+        let start = end - chrono::Duration::days(range as i64);
+
+        let rows = conn
+            .prepare(&format!(
+                r"
+        SELECT `link`.`id`, `link`.`url`, `link`.`title`, `item`.`id`, `item`.`feed_id`,
+            `item`.`is_saved`, `item`.`created`
+        FROM `link_item`
+        JOIN `link` ON `link`.`id` = `link_item`.`link_id`
+        JOIN `item` ON `item`.`id` = `link_item`.`item_id`
+        WHERE `item`.`created` >= ?1 AND `item`.`created` < ?2 AND {}
+        ORDER BY `item`.`id`",
+                subscribed
+            ))?
+            .query_map(params![start, end], |row| {
+                Ok((
+                    Link {
+                        id: row.get(0)?,
+                        url: row.get(1)?,
+                        title: row.get(2)?,
+                    },
+                    row.get::<_, u32>(3)?,
+                    row.get::<_, u32>(4)?,
+                    row.get::<_, u8>(5)?,
+                    row.get::<_, DateTime<Utc>>(6)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let window = (end - start).num_seconds() as f64;
+        let mut links: HashMap<u32, HotLink> = HashMap::new();
+        for (link, item_id, feed_id, is_saved, created) in rows {
+            let heat = 10.0 * (created - start).num_seconds() as f64 / window;
+            let hot = links.entry(link.id).or_insert_with(|| HotLink {
+                id: link.id,
+                feed_id,
+                item_id,
+                temperature: 0.0,
+                is_item: 0,
+                is_local: 0,
+                is_saved,
+                title: link.title,
+                url: link.url,
+                item_ids: Vec::new(),
+            });
+            hot.temperature += heat;
+            hot.item_ids.push(item_id);
+        }
+
+        let mut links = links
+            .into_values()
+            .filter(|link| link.item_ids.len() >= HOT_LINK_MIN_ITEMS)
+            .collect::<Vec<_>>();
+        links.sort_by(|lhs, rhs| {
+            rhs.temperature
+                .partial_cmp(&lhs.temperature)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(lhs.id.cmp(&rhs.id))
+        });
+
+        let skip = (page.max(1) as usize - 1) * HOT_LINK_PAGE_SIZE;
+        let mut links = links
+            .into_iter()
+            .skip(skip)
+            .take(HOT_LINK_PAGE_SIZE)
+            .collect::<Vec<_>>();
+
+        // links pointing to an item we have are local
+        let mut stmt = conn.prepare(&format!(
+            "SELECT `id`, `feed_id`, `is_saved` FROM `item` WHERE `url` = ?1 AND {}",
+            subscribed
+        ))?;
+        for link in links.iter_mut() {
+            link.temperature = (link.temperature * 10.0).round() / 10.0;
+            if let Some((item_id, feed_id, is_saved)) = stmt
+                .query_row(params![link.url], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })
+                .optional()?
+            {
+                link.item_id = item_id;
+                link.feed_id = feed_id;
+                link.is_saved = is_saved;
+                link.is_item = 1;
+                link.is_local = 1;
+            }
+        }
+
+        if let Some(user_id) = user_id {
+            let ids = links.iter().map(|link| link.item_id).collect::<Vec<_>>();
+            let states = item_states(conn, user_id, &ids)?;
+            for link in links.iter_mut() {
+                link.is_saved = states.get(&link.item_id).map_or(0, |&(_, saved)| saved);
+            }
+        }
+
+        Ok(links)
+    }
+}
+
+impl Model for Link {
+    const TABLE: &'static str = "link";
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            url: row.get(1)?,
+            title: row.get(2)?,
+        })
+    }
+
+    fn set_id(&mut self, id: u32) {
+        self.id = id;
+    }
+
+    fn get_id(&self) -> u32 {
+        self.id
+    }
+}
+
+/// Maximum number of items returned by a single `ItemQuery`, as defined by the Fever API.
+const ITEM_QUERY_LIMIT: usize = 50;
+
+/// Builds queries selecting items following the semantics of Fever's `?items` endpoint.
+#[derive(Debug, Default)]
+pub struct ItemQuery {
+    since_id: Option<u32>,
+    max_id: Option<u32>,
+    with_ids: Option<Vec<u32>>,
+    user_id: Option<u32>,
+}
+
+impl ItemQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Selects items with an id greater than `since_id`, in ascending order.
+    pub fn since_id(mut self, since_id: u32) -> Self {
+        self.since_id = Some(since_id);
+        self
+    }
+
+    /// Selects items with an id less than `max_id`, in descending order. `0` selects the most
+    /// recent items.
+    pub fn max_id(mut self, max_id: u32) -> Self {
+        self.max_id = Some(max_id);
+        self
+    }
+
+    /// Selects the specified items. Only the first 50 ids are honored. Takes precedence over
+    /// `since_id` and `max_id`.
+    pub fn with_ids(mut self, mut ids: Vec<u32>) -> Self {
+        ids.truncate(ITEM_QUERY_LIMIT);
+        self.with_ids = Some(ids);
+        self
+    }
+
+    /// Only selects items of feeds `user` subscribed to, along with the user's read and saved
+    /// state.
+    pub fn user(mut self, user: &User) -> Self {
+        self.user_id = Some(user.id);
+        self
+    }
+
+    pub fn fetch(self, conn: &Connection) -> Result<Vec<Item>> {
+        let subscribed = match self.user_id {
+            Some(user_id) => subscribed_condition(user_id),
+            None => "1".to_owned(),
+        };
+
+        let mut items = if let Some(ids) = self.with_ids {
+            let rarray = Rc::new(
+                ids.into_iter()
+                    .map(|id| id as i64)
+                    .map(rusqlite::types::Value::from)
+                    .collect::<Vec<_>>(),
+            );
+            conn.prepare(&format!(
+                "SELECT * FROM `item` WHERE `id` IN rarray(?1) AND {} ORDER BY `id`",
+                subscribed
+            ))?
+            .query_map([&rarray], Item::from_row)?
+            .collect::<Result<Vec<_>, _>>()?
+        } else {
+            let (condition, order) = match (self.since_id, self.max_id) {
+                (Some(since_id), _) => (format!("`id` > {}", since_id), "ASC"),
+                (None, Some(0)) => ("1".to_owned(), "DESC"),
+                (None, Some(max_id)) => (format!("`id` < {}", max_id), "DESC"),
+                (None, None) => ("1".to_owned(), "ASC"),
+            };
+            let stmt = format!(
+                "SELECT * FROM `item` WHERE {} AND {} ORDER BY `id` {} LIMIT {}",
+                condition, subscribed, order, ITEM_QUERY_LIMIT
+            );
+
+            conn.prepare(&stmt)?
+                .query_map(NO_PARAMS, Item::from_row)?
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        if let Some(user_id) = self.user_id {
+            apply_item_state(conn, user_id, &mut items)?;
+        }
+        Ok(items)
+    }
+}
+
+/// Rules deciding which items are deleted to keep the database small. Saved items are never
+/// deleted, and items only count as read once every user subscribed to their feed read them.
+/// Items of feeds without subscribers follow the global read state.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Retention {
+    read_days: Option<u32>,
+    per_feed: Option<u32>,
+}
+
+impl Retention {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps read items for `days` days after they were published.
+    pub fn read_days(mut self, days: u32) -> Self {
+        self.read_days = Some(days);
+        self
+    }
+
+    /// Keeps at most the `count` most recent items of each feed.
+    pub fn per_feed(mut self, count: u32) -> Self {
+        self.per_feed = Some(count);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read_days.is_none() && self.per_feed.is_none()
+    }
+
+    /// Items that are expired at `now`, as `(feed_id, item_id)` pairs.
+    pub fn expired(&self, conn: &Connection, now: DateTime<Utc>) -> Result<Vec<(u32, u32)>> {
+        const SQL: &'static str = r"
+        SELECT `feed_id`, `id`
+        FROM `item`
+        WHERE `is_saved` = 0 AND `created` < ?1
+            AND `id` NOT IN (SELECT `item_id` FROM `item_state` WHERE `is_saved` = 1)
+            AND CASE
+                WHEN EXISTS (
+                    SELECT 1 FROM `subscription`
+                    WHERE `subscription`.`feed_id` = `item`.`feed_id`
+                )
+                THEN NOT EXISTS (
+                    SELECT 1 FROM `subscription`
+                    WHERE `subscription`.`feed_id` = `item`.`feed_id`
+                        AND `subscription`.`user_id` NOT IN (
+                            SELECT `user_id` FROM `item_state`
+                            WHERE `item_id` = `item`.`id` AND `is_read` = 1
+                        )
+                )
+                ELSE `is_read` = 1
+            END
+        UNION
+        SELECT `feed_id`, `id`
+        FROM (
+            SELECT
+                `feed_id`,
+                `id`,
+                ROW_NUMBER() OVER (PARTITION BY `feed_id` ORDER BY `created` DESC, `id` DESC)
+                    AS `position`
+            FROM `item`
+            WHERE `is_saved` = 0
+                AND `id` NOT IN (SELECT `item_id` FROM `item_state` WHERE `is_saved` = 1)
+        )
+        WHERE `position` > ?2
+        ORDER BY `feed_id`, `id`";
+
+        if self.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Comparisons with NULL are never true, which disables the respective rule.
+        let before = self
+            .read_days
+            .map(|days| now - chrono::Duration::days(days as i64));
+        Ok(conn
+            .prepare(SQL)?
+            .query_map(params![before, self.per_feed], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Deletes expired items, and links no longer referenced by any item. Returns the number of
+    /// deleted items.
+    pub fn prune(&self, conn: &Connection, now: DateTime<Utc>) -> Result<usize> {
+        let ids = self
+            .expired(conn, now)?
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect::<Vec<_>>();
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        let deleted = Item::delete_multi(&tx, &ids)?;
+        Link::delete_orphans(&tx)?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+}
+
+/// Converts user input into an FTS5 query matching items that contain all terms. Terms ending
+/// with `*` match as prefixes.
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|term| match term.strip_suffix('*') {
+            Some(prefix) if !prefix.is_empty() => format!("\"{}\"*", prefix.replace('"', "\"\"")),
+            _ => format!("\"{}\"", term.replace('"', "\"\"")),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// An item matching a search, along with an excerpt highlighting the matched terms.
+#[derive(Debug)]
+pub struct SearchHit {
+    pub item: Item,
+    pub snippet: String,
+}
+
+/// Builds full-text searches over item titles, authors and bodies. Results are ordered by
+/// relevance.
+#[derive(Debug)]
+pub struct ItemSearch {
+    query: String,
+    feed_id: Option<u32>,
+    group_id: Option<u32>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    limit: u32,
+    offset: u32,
+    user_id: Option<u32>,
+}
+
+impl ItemSearch {
+    pub fn new(query: &str) -> Self {
+        Self {
+            query: fts_query(query),
+            feed_id: None,
+            group_id: None,
+            since: None,
+            until: None,
+            limit: 20,
+            offset: 0,
+            user_id: None,
+        }
+    }
+
+    pub fn feed_id(mut self, feed_id: u32) -> Self {
+        self.feed_id = Some(feed_id);
+        self
+    }
+
+    pub fn group_id(mut self, group_id: u32) -> Self {
+        self.group_id = Some(group_id);
+        self
+    }
+
+    /// Selects items created at or after `time`.
+    pub fn since(mut self, time: DateTime<Utc>) -> Self {
+        self.since = Some(time);
+        self
+    }
+
+    /// Selects items created before `time`.
+    pub fn until(mut self, time: DateTime<Utc>) -> Self {
+        self.until = Some(time);
+        self
+    }
+
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn offset(mut self, offset: u32) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Only selects items of feeds `user` subscribed to, along with the user's read and saved
+    /// state.
+    pub fn user(mut self, user: &User) -> Self {
+        self.user_id = Some(user.id);
+        self
+    }
+
+    pub fn fetch(&self, conn: &Connection) -> Result<Vec<SearchHit>> {
+        if self.query.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut conditions = vec![
+            "`item_search` MATCH ?1".to_owned(),
+            "(?2 IS NULL OR `item`.`created` >= ?2)".to_owned(),
+            "(?3 IS NULL OR `item`.`created` < ?3)".to_owned(),
+        ];
+        if let Some(feed_id) = self.feed_id {
+            conditions.push(format!("`item`.`feed_id` = {}", feed_id));
+        }
+        if let Some(group_id) = self.group_id {
+            conditions.push(format!(
+                "`item`.`feed_id` IN (SELECT `feed_id` FROM `feed_group` WHERE `group_id` = {})",
+                group_id
+            ));
+        }
+        if let Some(user_id) = self.user_id {
+            conditions.push(subscribed_condition(user_id));
+        }
+
+        let stmt = format!(
+            r"
+        SELECT `item`.*, snippet(`item_search`, 2, '[', ']', '...', 16) AS `snippet`
+        FROM `item_search`
+        JOIN `item` ON `item`.`id` = `item_search`.`rowid`
+        WHERE {}
+        ORDER BY `rank`
+        LIMIT {} OFFSET {}",
+            conditions.join(" AND "),
+            self.limit,
+            self.offset
+        );
+
+        let mut hits = conn
+            .prepare(&stmt)?
+            .query_map(params![self.query, self.since, self.until], |row| {
+                Ok(SearchHit {
+                    item: Item::from_row(row)?,
+                    snippet: row.get("snippet")?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let Some(user_id) = self.user_id {
+            let ids = hits.iter().map(|hit| hit.item.id).collect::<Vec<_>>();
+            let states = item_states(conn, user_id, &ids)?;
+            for hit in hits.iter_mut() {
+                let (is_read, is_saved) = states.get(&hit.item.id).copied().unwrap_or((0, 0));
+                hit.item.is_read = is_read;
+                hit.item.is_saved = is_saved;
+            }
+        }
+        Ok(hits)
+    }
+}
+
+/// Maximum number of items returned by a single `StreamQuery`.
+const STREAM_QUERY_LIMIT: u32 = 10000;
+
+/// A set of items, as addressed by the stream ids of the Google Reader API.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ItemStream {
+    All,
+    Feed(u32),
+    Group(u32),
+    Read,
+    Saved,
+    Tag(String),
+}
+
+impl ItemStream {
+    /// SQL condition selecting items of the stream. Read and saved items are those of the user
+    /// with `user_id`, if any. Values are appended to `params` and bound by their position.
+    fn condition(&self, user_id: Option<u32>, params: &mut Vec<Box<dyn ToSql>>) -> String {
+        match self {
+            ItemStream::All => "1".to_owned(),
+            ItemStream::Feed(id) => format!("`feed_id` = {}", id),
+            ItemStream::Group(id) => format!(
+                "`feed_id` IN (SELECT `feed_id` FROM `feed_group` WHERE `group_id` = {})",
+                id
+            ),
+            ItemStream::Read | ItemStream::Saved => {
+                let column = match self {
+                    ItemStream::Read => "is_read",
+                    _ => "is_saved",
+                };
+                match user_id {
+                    Some(user_id) => format!(
+                        "`id` IN (SELECT `item_id` FROM `item_state` WHERE `user_id` = {} AND `{}` = 1)",
+                        user_id, column
+                    ),
+                    None => format!("`{}` = 1", column),
+                }
+            }
+            ItemStream::Tag(tag) => {
+                params.push(Box::new(tag.clone()));
+                format!(
+                    "`id` IN (SELECT `item_id` FROM `item_tag` WHERE `tag` = ?{})",
+                    params.len()
+                )
+            }
+        }
+    }
+}
+
+/// Builds queries selecting items following the semantics of the Google Reader API's stream
+/// endpoints. Items are returned newest first unless `oldest_first` is set.
+#[derive(Debug)]
+pub struct StreamQuery {
+    stream: ItemStream,
+    include: Vec<ItemStream>,
+    exclude: Vec<ItemStream>,
+    newer_than: Option<DateTime<Utc>>,
+    older_than: Option<DateTime<Utc>>,
+    oldest_first: bool,
+    continuation: Option<u32>,
+    limit: u32,
+    user_id: Option<u32>,
+}
+
+impl StreamQuery {
+    pub fn new(stream: ItemStream) -> Self {
+        Self {
+            stream,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            newer_than: None,
+            older_than: None,
+            oldest_first: false,
+            continuation: None,
+            limit: 20,
+            user_id: None,
+        }
+    }
+
+    /// Only selects items that are also in `stream`.
+    pub fn include(mut self, stream: ItemStream) -> Self {
+        self.include.push(stream);
+        self
+    }
+
+    /// Skips items that are in `stream`.
+    pub fn exclude(mut self, stream: ItemStream) -> Self {
+        self.exclude.push(stream);
+        self
+    }
+
+    /// Selects items created at or after `time`.
+    pub fn newer_than(mut self, time: DateTime<Utc>) -> Self {
+        self.newer_than = Some(time);
+        self
+    }
+
+    /// Selects items created before `time`.
+    pub fn older_than(mut self, time: DateTime<Utc>) -> Self {
+        self.older_than = Some(time);
+        self
+    }
+
+    pub fn oldest_first(mut self, oldest_first: bool) -> Self {
+        self.oldest_first = oldest_first;
+        self
+    }
+
+    /// Continues a previous query that ended with the item `id`.
+    pub fn continuation(mut self, id: u32) -> Self {
+        self.continuation = Some(id);
+        self
+    }
+
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = limit.min(STREAM_QUERY_LIMIT);
+        self
+    }
+
+    /// Only selects items of feeds `user` subscribed to, along with the user's read and saved
+    /// state.
+    pub fn user(mut self, user: &User) -> Self {
+        self.user_id = Some(user.id);
+        self
+    }
+
+    /// Returns the continuation of a query that returned `ids`, if there may be more items.
+    pub fn next_continuation(&self, ids: &[u32]) -> Option<u32> {
+        if ids.len() as u32 >= self.limit {
+            ids.last().copied()
+        } else {
+            None
+        }
+    }
+
+    fn query<T, F>(&self, conn: &Connection, columns: &str, f: F) -> Result<Vec<T>>
+    where
+        F: FnMut(&Row) -> rusqlite::Result<T>,
+    {
+        let mut params: Vec<Box<dyn ToSql>> = Vec::new();
+        let mut conditions = vec![self.stream.condition(self.user_id, &mut params)];
+        for stream in self.include.iter() {
+            conditions.push(stream.condition(self.user_id, &mut params));
+        }
+        for stream in self.exclude.iter() {
+            conditions.push(format!(
+                "NOT ({})",
+                stream.condition(self.user_id, &mut params)
+            ));
+        }
+        if let Some(user_id) = self.user_id {
+            conditions.push(subscribed_condition(user_id));
+        }
+        if let Some(id) = self.continuation {
+            let op = if self.oldest_first { ">" } else { "<" };
+            conditions.push(format!("`id` {} {}", op, id));
+        }
+
+        if let Some(time) = self.newer_than {
+            params.push(Box::new(time));
+            conditions.push(format!("`created` >= ?{}", params.len()));
+        }
+        if let Some(time) = self.older_than {
+            params.push(Box::new(time));
+            conditions.push(format!("`created` < ?{}", params.len()));
+        }
+
+        let stmt = format!(
+            "SELECT {} FROM `item` WHERE {} ORDER BY `id` {} LIMIT {}",
+            columns,
+            conditions.join(" AND "),
+            if self.oldest_first { "ASC" } else { "DESC" },
+            self.limit
+        );
+
+        Ok(conn
+            .prepare(&stmt)?
+            .query_map(rusqlite::params_from_iter(params.iter()), f)?
+            .collect::<Result<_, _>>()?)
+    }
+
+    pub fn fetch(&self, conn: &Connection) -> Result<Vec<Item>> {
+        let mut items = self.query(conn, "*", Item::from_row)?;
+        if let Some(user_id) = self.user_id {
+            apply_item_state(conn, user_id, &mut items)?;
+        }
+        Ok(items)
+    }
+
+    pub fn fetch_ids(&self, conn: &Connection) -> Result<Vec<u32>> {
+        self.query(conn, "`id`", |row| row.get(0))
+    }
+}
+
+/// Opens the database without touching its schema.
+pub fn open_pool(path: &Path) -> Result<r2d2::Pool<SqliteConnectionManager>> {
+    let manager = SqliteConnectionManager::file(path).with_init(|c| {
+        rusqlite::vtab::array::load_module(&c)?;
+        Ok(())
+    });
+    Ok(r2d2::Pool::new(manager)?)
+}
+
+/// Opens the database and migrates its schema to the latest version.
+pub fn get_pool(path: &Path) -> Result<r2d2::Pool<SqliteConnectionManager>> {
+    let pool = open_pool(path)?;
+
+    {
+        let conn = pool.get()?;
+        crate::migration::migrate(&conn)?;
+    }
+
+    Ok(pool)
+}
+
+/// Part of an item matched by a `Rule`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuleField {
+    Title,
+    Author,
+    Url,
+    /// Text of the content, without markup
+    Content,
+    /// Any of the above
+    Any,
+}
+
+impl RuleField {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Title => "title",
+            Self::Author => "author",
+            Self::Url => "url",
+            Self::Content => "content",
+            Self::Any => "any",
+        }
+    }
+}
+
+impl std::str::FromStr for RuleField {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "title" => Ok(Self::Title),
+            "author" => Ok(Self::Author),
+            "url" => Ok(Self::Url),
+            "content" => Ok(Self::Content),
+            "any" => Ok(Self::Any),
+            _ => Err(Error::message(format!(
+                "unknown field '{}' (expected title, author, url, content or any)",
+                s
+            ))),
+        }
+    }
+}
+
+/// What a `Rule` does with matching items.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuleAction {
+    Read,
+    Save,
+    /// The item is not stored.
+    Drop,
+    Tag(String),
+}
+
+impl std::fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read => write!(f, "read"),
+            Self::Save => write!(f, "save"),
+            Self::Drop => write!(f, "drop"),
+            Self::Tag(tag) => write!(f, "tag:{}", tag),
+        }
+    }
+}
+
+impl std::str::FromStr for RuleAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read" => Ok(Self::Read),
+            "save" => Ok(Self::Save),
+            "drop" => Ok(Self::Drop),
+            _ => match s.strip_prefix("tag:").map(str::trim) {
+                Some(tag) if !tag.is_empty() => Ok(Self::Tag(tag.to_owned())),
+                _ => Err(Error::message(format!(
+                    "unknown action '{}' (expected read, save, drop or tag:<name>)",
+                    s
+                ))),
+            },
+        }
+    }
+}
+
+/// Filter applied to new items of feeds when they are crawled.
+#[derive(Debug)]
+pub struct Rule {
+    pub id: u32,
+    /// Only applies to items of this feed
+    pub feed_id: Option<u32>,
+    /// Only applies to items of feeds in this group
+    pub group_id: Option<u32>,
+    pub field: RuleField,
+    pub pattern: String,
+    /// Whether `pattern` is a regular expression, or else a case-insensitive substring
+    pub is_regex: bool,
+    pub action: RuleAction,
+}
+
+impl Rule {
+    pub fn new(field: RuleField, pattern: String, is_regex: bool, action: RuleAction) -> Self {
+        Rule {
+            id: 0,
+            feed_id: None,
+            group_id: None,
+            field,
+            pattern,
+            is_regex,
+            action,
+        }
+    }
+
+    pub fn feed_id(mut self, feed_id: u32) -> Self {
+        self.feed_id = Some(feed_id);
+        self
+    }
+
+    pub fn group_id(mut self, group_id: u32) -> Self {
+        self.group_id = Some(group_id);
+        self
+    }
+
+    /// Checks that the pattern is valid, and saves the rule.
+    pub fn insert(mut self, conn: &Connection) -> Result<Self> {
+        self.matcher()?;
+        self.id = conn
+            .prepare(
+                r"
+            INSERT INTO `rule` (feed_id, group_id, field, pattern, is_regex, action)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?
+            .insert(params![
+                self.feed_id,
+                self.group_id,
+                self.field.as_str(),
+                self.pattern,
+                self.is_regex,
+                self.action.to_string(),
+            ])? as u32;
+        Ok(self)
+    }
+
+    /// Rules that apply to items of a feed, directly or through one of its groups.
+    pub fn for_feed(conn: &Connection, feed_id: u32) -> Result<Vec<Self>> {
+        const SQL: &'static str = r"
+        SELECT * FROM `rule`
+        WHERE (`feed_id` IS NULL OR `feed_id` = ?1)
+        AND (
+            `group_id` IS NULL
+            OR `group_id` IN (SELECT `group_id` FROM `feed_group` WHERE `feed_id` = ?1)
+        )
+        ORDER BY `id`";
+
+        Ok(conn
+            .prepare(SQL)?
+            .query_map(params![feed_id], Self::from_row)?
+            .collect::<Result<_, _>>()?)
+    }
+
+    pub fn delete_by_feed(conn: &Connection, feed_id: u32) -> Result<usize> {
+        Ok(conn.execute("DELETE FROM `rule` WHERE `feed_id` = ?1", params![feed_id])?)
+    }
+
+    pub fn delete_by_group(conn: &Connection, group_id: u32) -> Result<usize> {
+        Ok(conn.execute(
+            "DELETE FROM `rule` WHERE `group_id` = ?1",
+            params![group_id],
+        )?)
+    }
+
+    fn matcher(&self) -> Result<Matcher> {
+        if self.is_regex {
+            Ok(Matcher::Regex(regex::Regex::new(&self.pattern)?))
+        } else {
+            Ok(Matcher::Substring(self.pattern.to_lowercase()))
+        }
+    }
+}
+
+impl Model for Rule {
+    const TABLE: &'static str = "rule";
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let field: String = row.get(3)?;
+        let action: String = row.get(6)?;
+        let invalid = |column, e: Error| {
+            rusqlite::Error::FromSqlConversionFailure(
+                column,
+                rusqlite::types::Type::Text,
+                Box::new(e),
+            )
+        };
+
+        Ok(Self {
+            id: row.get(0)?,
+            feed_id: row.get(1)?,
+            group_id: row.get(2)?,
+            field: field.parse().map_err(|e| invalid(3, e))?,
+            pattern: row.get(4)?,
+            is_regex: row.get(5)?,
+            action: action.parse().map_err(|e| invalid(6, e))?,
+        })
+    }
+
+    fn set_id(&mut self, id: u32) {
+        self.id = id;
+    }
+
+    fn get_id(&self) -> u32 {
+        self.id
+    }
+}
+
+enum Matcher {
+    Substring(String),
+    Regex(regex::Regex),
+}
+
+impl Matcher {
+    fn is_match(&self, text: &str) -> bool {
+        match self {
+            Self::Substring(pattern) => text.to_lowercase().contains(pattern.as_str()),
+            Self::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+/// Rules of a feed, ready to be applied to its new items.
+pub struct RuleSet {
+    rules: Vec<(Matcher, RuleField, RuleAction)>,
+}
+
+impl RuleSet {
+    pub fn for_feed(conn: &Connection, feed_id: u32) -> Result<Self> {
+        let rules = Rule::for_feed(conn, feed_id)?
+            .into_iter()
+            .filter_map(|rule| match rule.matcher() {
+                Ok(matcher) => Some((matcher, rule.field, rule.action)),
+                Err(e) => {
+                    log::warn!("ignoring invalid rule {}: {:?}", rule.id, e);
+                    None
+                }
+            })
+            .collect();
+        Ok(RuleSet { rules })
+    }
+
+    /// Applies the actions of all matching rules to an item. Returns the tags to add to the item,
+    /// or `None` if the item is dropped.
+    pub fn apply(&self, item: &mut Item) -> Option<Vec<String>> {
+        // text of the content, extracted when a rule needs it
+        let mut text: Option<String> = None;
+        let mut tags = Vec::new();
+
+        for (matcher, field, action) in self.rules.iter() {
+            let matched = match field {
+                RuleField::Title => matcher.is_match(&item.title),
+                RuleField::Author => matcher.is_match(&item.author),
+                RuleField::Url => matcher.is_match(&item.url),
+                RuleField::Content => {
+                    matcher.is_match(text.get_or_insert_with(|| strip_html(&item.html)))
+                }
+                RuleField::Any => {
+                    [&item.title, &item.author, &item.url]
+                        .iter()
+                        .any(|text| matcher.is_match(text))
+                        || matcher.is_match(text.get_or_insert_with(|| strip_html(&item.html)))
+                }
+            };
+            if !matched {
+                continue;
+            }
+
+            match action {
+                RuleAction::Read => item.is_read = 1,
+                RuleAction::Save => item.is_saved = 1,
+                RuleAction::Drop => return None,
+                RuleAction::Tag(tag) => {
+                    if !tags.contains(tag) {
+                        tags.push(tag.clone());
+                    }
+                }
+            }
+        }
+
+        Some(tags)
+    }
+}
+
+/// SQL condition selecting rows of `item` in feeds the user subscribed to.
+fn subscribed_condition(user_id: u32) -> String {
+    format!(
+        "`item`.`feed_id` IN (SELECT `feed_id` FROM `subscription` WHERE `user_id` = {})",
+        user_id
+    )
+}
+
+/// Maps item ids to a user's read and saved state of them. Items the user never changed are
+/// missing, and are unread and unsaved.
+fn item_states(conn: &Connection, user_id: u32, ids: &[u32]) -> Result<HashMap<u32, (u8, u8)>> {
+    let rarray = Rc::new(
+        ids.iter()
+            .map(|&id| id as i64)
+            .map(rusqlite::types::Value::from)
+            .collect::<Vec<_>>(),
+    );
+    let mut stmt = conn.prepare(
+        r"
+        SELECT `item_id`, `is_read`, `is_saved` FROM `item_state`
+        WHERE `user_id` = ?1 AND `item_id` IN rarray(?2)",
+    )?;
+    let states = stmt
+        .query_map(params![user_id, &rarray], |row| {
+            Ok((row.get(0)?, (row.get(1)?, row.get(2)?)))
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(states)
+}
+
+/// Replaces the read and saved state of items with the state of a user.
+fn apply_item_state(conn: &Connection, user_id: u32, items: &mut [Item]) -> Result<()> {
+    let ids = items.iter().map(|item| item.id).collect::<Vec<_>>();
+    let states = item_states(conn, user_id, &ids)?;
+    for item in items.iter_mut() {
+        let (is_read, is_saved) = states.get(&item.id).copied().unwrap_or((0, 0));
+        item.is_read = is_read;
+        item.is_saved = is_saved;
+    }
+    Ok(())
+}
+
+/// An account of the Fever API, with its own subscriptions and read and saved state of items.
+/// Feeds and items are shared by all users, so each feed is crawled once.
+#[derive(Clone, Debug)]
+pub struct User {
+    pub id: u32,
+    pub username: String,
+    /// `md5("<username>:<password>")`, as sent by Fever clients
+    pub api_key: String,
+    pub created: DateTime<Utc>,
+}
+
+impl User {
+    pub fn new(username: String, password: &str) -> Self {
+        User {
+            id: 0,
+            api_key: Self::api_key(&username, password),
+            username,
+            created: Utc::now(),
+        }
+    }
+
+    pub fn api_key(username: &str, password: &str) -> String {
+        md5_hex(&format!("{}:{}", username, password))
+    }
+
+    /// Token handed out to the user by the Google Reader API's `ClientLogin`.
+    pub fn reader_token(&self) -> String {
+        md5_hex(&format!("reader:{}", self.api_key))
+    }
+
+    pub fn get_by_reader_token(conn: &Connection, token: &str) -> Result<Option<Self>> {
+        Ok(Self::all(conn)?
+            .into_iter()
+            .find(|user| user.reader_token() == token))
+    }
+
+    pub fn insert(mut self, conn: &Connection) -> Result<Self> {
+        self.id = conn
+            .prepare("INSERT INTO `user` (username, api_key, created) VALUES (?1, ?2, ?3)")?
+            .insert(params![self.username, self.api_key, self.created])? as u32;
+        Ok(self)
+    }
+
+    pub fn get_by_name(conn: &Connection, username: &str) -> Result<Self> {
+        conn.query_row(
+            "SELECT * FROM `user` WHERE `username` = ?1",
+            params![username],
+            Self::from_row,
+        )
+        .map_err(|e| e.into())
+    }
+
+    pub fn get_by_api_key(conn: &Connection, api_key: &str) -> Result<Option<Self>> {
+        Ok(conn
+            .query_row(
+                "SELECT * FROM `user` WHERE `api_key` = ?1",
+                params![api_key],
+                Self::from_row,
+            )
+            .optional()?)
+    }
+
+    pub fn set_password(&mut self, conn: &Connection, password: &str) -> Result<()> {
+        self.api_key = Self::api_key(&self.username, password);
+        conn.execute(
+            "UPDATE `user` SET `api_key` = ?1 WHERE `id` = ?2",
+            params![self.api_key, self.id],
+        )?;
+        Ok(())
+    }
+
+    /// Deletes the user along with its subscriptions and item state.
+    pub fn remove(self, conn: &Connection) -> Result<Self> {
+        conn.execute(
+            "DELETE FROM `subscription` WHERE `user_id` = ?1",
+            params![self.id],
+        )?;
+        conn.execute(
+            "DELETE FROM `item_state` WHERE `user_id` = ?1",
+            params![self.id],
+        )?;
+        self.delete(conn)
+    }
+
+    pub fn subscribe(&self, conn: &Connection, feed_id: u32) -> Result<()> {
+        conn.execute(
+            "INSERT INTO `subscription` (user_id, feed_id) VALUES (?1, ?2)",
+            params![self.id, feed_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn unsubscribe(&self, conn: &Connection, feed_id: u32) -> Result<()> {
+        conn.execute(
+            "DELETE FROM `subscription` WHERE `user_id` = ?1 AND `feed_id` = ?2",
+            params![self.id, feed_id],
+        )?;
+        Ok(())
+    }
+
+    /// Deletes all subscriptions to a feed.
+    pub fn delete_subscriptions(conn: &Connection, feed_id: u32) -> Result<usize> {
+        Ok(conn.execute(
+            "DELETE FROM `subscription` WHERE `feed_id` = ?1",
+            params![feed_id],
+        )?)
+    }
+
+    /// Feeds the user subscribed to.
+    pub fn feeds(&self, conn: &Connection) -> Result<Vec<Feed>> {
+        Ok(conn
+            .prepare(
+                r"
+            SELECT * FROM `feed`
+            WHERE `id` IN (SELECT `feed_id` FROM `subscription` WHERE `user_id` = ?1)
+            ORDER BY `id`",
+            )?
+            .query_map(params![self.id], Feed::from_row)?
             .collect::<Result<_, _>>()?)
     }
 
-    pub fn delete_by_feed(conn: &Connection, feed_id: u32) -> Result<usize> {
-        Ok(conn.execute("DELETE FROM `item` WHERE `feed_id` = ?1", params![feed_id])?)
+    /// Groups with their feeds the user subscribed to. Groups without such feeds are skipped.
+    pub fn feed_groups(&self, conn: &Connection) -> Result<Vec<FeedGroup>> {
+        let feed_ids = self
+            .feeds(conn)?
+            .into_iter()
+            .map(|feed| feed.id)
+            .collect::<HashSet<_>>();
+        Ok(FeedGroup::all(conn)?
+            .into_iter()
+            .filter_map(|mut feed_group| {
+                feed_group.feed_ids.retain(|id| feed_ids.contains(id));
+                if feed_group.feed_ids.is_empty() {
+                    None
+                } else {
+                    Some(feed_group)
+                }
+            })
+            .collect())
+    }
+
+    /// Groups containing feeds the user subscribed to.
+    pub fn groups(&self, conn: &Connection) -> Result<Vec<Group>> {
+        let group_ids = self
+            .feed_groups(conn)?
+            .into_iter()
+            .map(|feed_group| feed_group.group_id)
+            .collect::<HashSet<_>>();
+        Ok(Group::all(conn)?
+            .into_iter()
+            .filter(|group| group_ids.contains(&group.id))
+            .collect())
     }
 
-    pub fn unread(conn: &Connection) -> Result<Vec<u32>> {
+    /// Number of items in feeds the user subscribed to.
+    pub fn item_count(&self, conn: &Connection) -> Result<u32> {
+        Ok(conn.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM `item` WHERE {}",
+                subscribed_condition(self.id)
+            ),
+            NO_PARAMS,
+            |row| row.get(0),
+        )?)
+    }
+
+    pub fn unread_ids(&self, conn: &Connection) -> Result<Vec<u32>> {
+        let sql = format!(
+            r"
+        SELECT `id` FROM `item`
+        WHERE {}
+        AND `id` NOT IN (SELECT `item_id` FROM `item_state` WHERE `user_id` = ?1 AND `is_read` = 1)",
+            subscribed_condition(self.id)
+        );
         Ok(conn
-            .prepare("SELECT id FROM `item` WHERE `is_read` = 0")?
-            .query_map(NO_PARAMS, |row| row.get(0))?
+            .prepare(&sql)?
+            .query_map(params![self.id], |row| row.get(0))?
             .collect::<Result<_, _>>()?)
     }
 
-    pub fn saved(conn: &Connection) -> Result<Vec<u32>> {
+    pub fn saved_ids(&self, conn: &Connection) -> Result<Vec<u32>> {
+        let sql = format!(
+            r"
+        SELECT `id` FROM `item`
+        WHERE {}
+        AND `id` IN (SELECT `item_id` FROM `item_state` WHERE `user_id` = ?1 AND `is_saved` = 1)",
+            subscribed_condition(self.id)
+        );
         Ok(conn
-            .prepare("SELECT id FROM `item` WHERE `is_saved` = 1")?
-            .query_map(NO_PARAMS, |row| row.get(0))?
+            .prepare(&sql)?
+            .query_map(params![self.id], |row| row.get(0))?
             .collect::<Result<_, _>>()?)
     }
 
-    pub fn read(mut self, conn: &Connection) -> Result<Self> {
-        conn.execute(
-            "UPDATE `item` SET `is_read` = 1 WHERE `id` = ?1",
-            params![self.id],
-        )?;
-        self.is_read = 1;
-        Ok(self)
+    /// Items with the given ids in feeds the user subscribed to, along with the user's read and
+    /// saved state.
+    pub fn items(&self, conn: &Connection, ids: &[u32]) -> Result<Vec<Item>> {
+        let feed_ids = self
+            .feeds(conn)?
+            .into_iter()
+            .map(|feed| feed.id)
+            .collect::<HashSet<_>>();
+        let mut items = Item::get_multi(conn, ids)?;
+        items.retain(|item| feed_ids.contains(&item.feed_id));
+        apply_item_state(conn, self.id, &mut items)?;
+        Ok(items)
     }
 
-    pub fn save(mut self, conn: &Connection) -> Result<Self> {
+    /// Computes hot links from items of feeds the user subscribed to. See `Link::hot`.
+    pub fn hot_links(
+        &self,
+        conn: &Connection,
+        offset: u32,
+        range: u32,
+        page: u32,
+    ) -> Result<Vec<HotLink>> {
+        Link::hot_for(conn, Some(self.id), offset, range, page)
+    }
+
+    pub fn set_item_read(&self, conn: &Connection, item_id: u32, is_read: bool) -> Result<()> {
+        let condition = format!("`id` = {}", item_id);
+        self.set_state(conn, "is_read", is_read, &condition, None)
+    }
+
+    pub fn set_item_saved(&self, conn: &Connection, item_id: u32, is_saved: bool) -> Result<()> {
+        let condition = format!("`id` = {}", item_id);
+        self.set_state(conn, "is_saved", is_saved, &condition, None)
+    }
+
+    pub fn set_feed_read(
+        &self,
+        conn: &Connection,
+        feed_id: u32,
+        is_read: bool,
+        before: Option<u32>,
+    ) -> Result<()> {
+        // feed and group conditions take no parameters
+        let condition = ItemStream::Feed(feed_id).condition(None, &mut Vec::new());
+        self.set_state(conn, "is_read", is_read, &condition, before)
+    }
+
+    pub fn set_group_read(
+        &self,
+        conn: &Connection,
+        group_id: u32,
+        is_read: bool,
+        before: Option<u32>,
+    ) -> Result<()> {
+        // feed and group conditions take no parameters
+        let condition = ItemStream::Group(group_id).condition(None, &mut Vec::new());
+        self.set_state(conn, "is_read", is_read, &condition, before)
+    }
+
+    /// Marks all items as read or unread.
+    pub fn set_all_read(
+        &self,
+        conn: &Connection,
+        is_read: bool,
+        before: Option<u32>,
+    ) -> Result<()> {
+        self.set_state(conn, "is_read", is_read, "1", before)
+    }
+
+    /// Marks items of all spark feeds or all non-spark feeds as read or unread. See
+    /// `Feed::read_by_spark`.
+    pub fn set_spark_read(
+        &self,
+        conn: &Connection,
+        is_spark: bool,
+        is_read: bool,
+        before: Option<u32>,
+    ) -> Result<()> {
+        let condition = format!(
+            "`feed_id` IN (SELECT `id` FROM `feed` WHERE `is_spark` = {})",
+            is_spark as u8
+        );
+        self.set_state(conn, "is_read", is_read, &condition, before)
+    }
+
+    /// Sets `column` of the user's state of items matching `condition`, optionally only those
+    /// created before `before`.
+    fn set_state(
+        &self,
+        conn: &Connection,
+        column: &'static str,
+        value: bool,
+        condition: &str,
+        before: Option<u32>,
+    ) -> Result<()> {
+        let before = before.map(|before| Utc.timestamp(before as i64, 0));
         conn.execute(
-            "UPDATE `item` SET `is_saved` = 1 WHERE `id` = ?1",
-            params![self.id],
+            &format!(
+                r"
+            INSERT INTO `item_state` (user_id, item_id, {0})
+            SELECT ?1, `id`, ?2 FROM `item`
+            WHERE {1} AND ({2}) AND (?3 IS NULL OR `created` < ?3)
+            ON CONFLICT (user_id, item_id) DO UPDATE SET {0} = excluded.{0}",
+                column,
+                subscribed_condition(self.id),
+                condition
+            ),
+            params![self.id, value, before],
         )?;
-        self.is_saved = 1;
-        Ok(self)
+        Ok(())
     }
 
-    pub fn unsave(mut self, conn: &Connection) -> Result<Self> {
+    /// Gives subscribers of the item's feed the read and saved state set by rules when it was
+    /// crawled.
+    pub fn share_item_state(conn: &Connection, item: &Item) -> Result<()> {
+        if item.is_read == 0 && item.is_saved == 0 {
+            return Ok(());
+        }
+
         conn.execute(
-            "UPDATE `item` SET `is_saved` = 0 WHERE `id` = ?1",
-            params![self.id],
+            r"
+            INSERT INTO `item_state` (user_id, item_id, is_read, is_saved)
+            SELECT `user_id`, ?1, ?2, ?3 FROM `subscription` WHERE `feed_id` = ?4",
+            params![item.id, item.is_read, item.is_saved, item.feed_id],
         )?;
-        self.is_saved = 0;
-        Ok(self)
+        Ok(())
     }
 }
 
-impl Model for Item {
-    const TABLE: &'static str = "item";
+impl Model for User {
+    const TABLE: &'static str = "user";
 
     fn from_row(row: &Row) -> rusqlite::Result<Self> {
         Ok(Self {
             id: row.get(0)?,
-            feed_id: row.get(1)?,
-            title: row.get(2)?,
-            author: row.get(3)?,
-            html: row.get(4)?,
-            url: row.get(5)?,
-            is_saved: row.get(6)?,
-            is_read: row.get(7)?,
-            created_on_time: row.get(8)?,
+            username: row.get(1)?,
+            api_key: row.get(2)?,
+            created: row.get(3)?,
         })
     }
 
@@ -639,30 +2841,17 @@ impl Model for Item {
     }
 }
 
-pub fn get_pool(path: &Path) -> Result<r2d2::Pool<SqliteConnectionManager>> {
-    let manager = SqliteConnectionManager::file(path).with_init(|c| {
-        rusqlite::vtab::array::load_module(&c)?;
-        Ok(())
-    });
-    let pool = r2d2::Pool::new(manager)?;
-
-    {
-        let conn = pool.get()?;
-
-        Group::create_table(&conn)?;
-        Feed::create_table(&conn)?;
-        FeedGroup::create_table(&conn)?;
-        Favicon::create_table(&conn)?;
-        Item::create_table(&conn)?;
-    }
-
-    Ok(pool)
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn open_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        rusqlite::vtab::array::load_module(&conn).unwrap();
+        crate::migration::migrate(&conn).unwrap();
+        conn
+    }
+
     fn make_test_feed(i: u32) -> Feed {
         Feed::new(
             format!("feed {}", i),
@@ -675,12 +2864,27 @@ mod test {
         Group::new(format!("group {}", i))
     }
 
+    fn make_test_item(feed_id: u32, i: u32) -> Item {
+        Item {
+            id: 0,
+            feed_id,
+            title: format!("item {}", i),
+            author: "author".to_owned(),
+            html: format!("<p>item {}</p>", i),
+            url: format!("http://{}.example.com/{}", feed_id, i),
+            is_saved: 0,
+            is_read: 0,
+            created_on_time: Utc.timestamp(i as i64, 0),
+            guid: Some(format!("item-{}", i)),
+            content_hash: Some(format!("hash-{}", i)),
+            date_source: DateSource::Published,
+            summary: None,
+        }
+    }
+
     #[test]
     fn test_group() -> Result<()> {
-        let conn = Connection::open_in_memory().unwrap();
-        Group::create_table(&conn).unwrap();
-        Feed::create_table(&conn).unwrap();
-        FeedGroup::create_table(&conn).unwrap();
+        let conn = open_test_db();
 
         // prepare
         let group = make_test_group(1).insert(&conn).unwrap();
@@ -707,11 +2911,7 @@ mod test {
 
     #[test]
     fn test_feed_group() {
-        let conn = Connection::open_in_memory().unwrap();
-        rusqlite::vtab::array::load_module(&conn).unwrap();
-        Group::create_table(&conn).unwrap();
-        Feed::create_table(&conn).unwrap();
-        FeedGroup::create_table(&conn).unwrap();
+        let conn = open_test_db();
 
         for group_id in 1..3 {
             let group = make_test_group(group_id).insert(&conn).unwrap();
@@ -736,4 +2936,800 @@ mod test {
         let feed = Feed::get(&conn, feed_id).unwrap();
         assert_eq!(feed.is_spark, 1);
     }
+
+    #[test]
+    fn test_remove_feed() {
+        let conn = open_test_db();
+
+        let first = make_test_group(1).insert(&conn).unwrap();
+        let second = make_test_group(2).insert(&conn).unwrap();
+        let feed = make_test_feed(1).insert(&conn).unwrap();
+        let feed = first.add_feed(&conn, feed).unwrap();
+        let feed = second.add_feed(&conn, feed).unwrap();
+        Item::insert_multi(&conn, vec![make_test_item(feed.id, 1)]).unwrap();
+
+        let feed = first.remove_feed(&conn, feed).unwrap();
+        assert_eq!(feed.is_spark, 0);
+        let feed = second.remove_feed(&conn, feed).unwrap();
+        assert_eq!(feed.is_spark, 1);
+        assert!(Group::get_by_feed(&conn, feed.id).unwrap().is_empty());
+
+        // deleting a group keeps feeds that belong to another group out of sparks
+        let third = make_test_group(3).insert(&conn).unwrap();
+        let feed = first.add_feed(&conn, feed).unwrap();
+        let feed = third.add_feed(&conn, feed).unwrap();
+        let only_third = third
+            .add_feed(&conn, make_test_feed(2).insert(&conn).unwrap())
+            .unwrap();
+        third.remove(&conn).unwrap();
+        assert_eq!(Feed::get(&conn, feed.id).unwrap().is_spark, 0);
+        assert_eq!(Feed::get(&conn, only_third.id).unwrap().is_spark, 1);
+        let feed = first.remove_feed(&conn, feed).unwrap();
+        Feed::get(&conn, only_third.id)
+            .unwrap()
+            .remove(&conn)
+            .unwrap();
+
+        second
+            .add_feed(&conn, Feed::get(&conn, feed.id).unwrap())
+            .unwrap();
+        feed.remove(&conn).unwrap();
+        assert_eq!(Feed::count(&conn).unwrap(), 0);
+        assert_eq!(Item::count(&conn).unwrap(), 0);
+        assert!(FeedGroup::all(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_item_query() {
+        let conn = open_test_db();
+        Item::insert_multi(&conn, (1..=120).map(|i| make_test_item(1, i)).collect()).unwrap();
+
+        let ids = |items: Vec<Item>| items.into_iter().map(|x| x.id).collect::<Vec<_>>();
+
+        let items = ids(ItemQuery::new().fetch(&conn).unwrap());
+        assert_eq!(items, (1..=50).collect::<Vec<_>>());
+
+        let items = ids(ItemQuery::new().since_id(100).fetch(&conn).unwrap());
+        assert_eq!(items, (101..=120).collect::<Vec<_>>());
+
+        let items = ids(ItemQuery::new().max_id(60).fetch(&conn).unwrap());
+        assert_eq!(items, (10..60).rev().collect::<Vec<_>>());
+
+        let items = ids(ItemQuery::new().max_id(0).fetch(&conn).unwrap());
+        assert_eq!(items, (71..=120).rev().collect::<Vec<_>>());
+
+        let items = ids(ItemQuery::new()
+            .since_id(100)
+            .with_ids(vec![3, 1, 2, 500])
+            .fetch(&conn)
+            .unwrap());
+        assert_eq!(items, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_stream_query() {
+        let conn = open_test_db();
+
+        let group = make_test_group(1).insert(&conn).unwrap();
+        let feed = make_test_feed(1).insert(&conn).unwrap();
+        group.add_feed(&conn, feed).unwrap();
+        let items = (1..=10)
+            .map(|i| make_test_item(1, i))
+            .chain((11..=20).map(|i| make_test_item(2, i)))
+            .collect();
+        let items = Item::insert_multi(&conn, items).unwrap();
+        for item in items.into_iter().filter(|item| item.id % 2 == 0) {
+            item.read(&conn).unwrap();
+        }
+
+        let query = StreamQuery::new(ItemStream::All).limit(5);
+        let ids = query.fetch_ids(&conn).unwrap();
+        assert_eq!(ids, vec![20, 19, 18, 17, 16]);
+        assert_eq!(query.next_continuation(&ids), Some(16));
+
+        let query = StreamQuery::new(ItemStream::Group(group.id))
+            .exclude(ItemStream::Read)
+            .oldest_first(true)
+            .continuation(3);
+        let ids = query.fetch_ids(&conn).unwrap();
+        assert_eq!(ids, vec![5, 7, 9]);
+        assert_eq!(query.next_continuation(&ids), None);
+
+        let ids = StreamQuery::new(ItemStream::Feed(2))
+            .newer_than(Utc.timestamp(12, 0))
+            .older_than(Utc.timestamp(15, 0))
+            .fetch_ids(&conn)
+            .unwrap();
+        assert_eq!(ids, vec![14, 13, 12]);
+
+        Item::read_all(&conn, Some(20)).unwrap();
+        assert_eq!(Item::unread_ids(&conn).unwrap(), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_item_search() {
+        let conn = open_test_db();
+
+        let group = make_test_group(1).insert(&conn).unwrap();
+        let feed = make_test_feed(1).insert(&conn).unwrap();
+        group.add_feed(&conn, feed).unwrap();
+
+        let mut items = (1..=4).map(|i| make_test_item(1, i)).collect::<Vec<_>>();
+        items[0].title = "Announcing Rust 1.0".to_owned();
+        items[1].html = "<p>Rust &amp; <b>WebAssembly</b></p><script>hidden()</script>".to_owned();
+        items[2].author = "Rusty".to_owned();
+        items.push(make_test_item(2, 5));
+        items[4].title = "Rust elsewhere".to_owned();
+        Item::insert_multi(&conn, items).unwrap();
+
+        let ids = |search: ItemSearch| {
+            let mut ids = search
+                .fetch(&conn)
+                .unwrap()
+                .into_iter()
+                .map(|hit| hit.item.id)
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(ids(ItemSearch::new("rust")), vec![1, 2, 5]);
+        assert_eq!(ids(ItemSearch::new("rust*")), vec![1, 2, 3, 5]);
+        assert_eq!(ids(ItemSearch::new("rust webassembly")), vec![2]);
+        assert_eq!(ids(ItemSearch::new("hidden")), Vec::<u32>::new());
+        assert_eq!(ids(ItemSearch::new("rust").feed_id(2)), vec![5]);
+        assert_eq!(ids(ItemSearch::new("rust").group_id(group.id)), vec![1, 2]);
+        assert_eq!(
+            ids(ItemSearch::new("rust")
+                .since(Utc.timestamp(2, 0))
+                .until(Utc.timestamp(5, 0))),
+            vec![2]
+        );
+
+        let hits = ItemSearch::new("webassembly").fetch(&conn).unwrap();
+        assert_eq!(hits[0].snippet, "Rust & [WebAssembly]");
+
+        Item::delete_by_feed(&conn, 1).unwrap();
+        assert_eq!(ids(ItemSearch::new("rust")), vec![5]);
+    }
+
+    #[test]
+    fn test_item_dates() -> Result<()> {
+        let conn = open_test_db();
+        let feed = make_test_feed(1).insert(&conn)?;
+
+        let xml = r#"
+        <feed xmlns="http://www.w3.org/2005/Atom">
+            <entry>
+                <id>published</id>
+                <published>2020-01-01T00:00:00Z</published>
+                <updated>2020-01-02T00:00:00Z</updated>
+            </entry>
+            <entry><id>updated</id><updated>2020-01-02T00:00:00Z</updated></entry>
+            <entry><id>undated</id></entry>
+        </feed>"#;
+        let first_seen = Utc.timestamp(1_600_000_000, 0);
+        let items = feed_rs::parser::parse(xml.as_bytes())?
+            .entries
+            .into_iter()
+            .map(|entry| Item::from_entry(&feed, entry, first_seen))
+            .collect::<Vec<_>>();
+
+        let items = Item::insert_multi(&conn, items)?
+            .into_iter()
+            .map(|item| Item::get(&conn, item.id))
+            .collect::<Result<Vec<_>>>()?;
+        let dates = items
+            .iter()
+            .map(|item| (item.created_on_time, item.date_source))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            dates,
+            vec![
+                (Utc.ymd(2020, 1, 1).and_hms(0, 0, 0), DateSource::Published),
+                (Utc.ymd(2020, 1, 2).and_hms(0, 0, 0), DateSource::Updated),
+                (first_seen, DateSource::FirstSeen),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_item_content() -> Result<()> {
+        let feed = make_test_feed(1);
+        let xml = r#"
+        <rss version="2.0" xmlns:content="http://purl.org/rss/1.0/modules/content/"><channel>
+            <item>
+                <link>http://example.com/posts/1</link>
+                <description><![CDATA[<p onclick="steal()">Hi <a href="../about" target="_blank">me</a><script>alert("<p>")</script><img src="/a.png" srcset="/a.png 1x, /a@2x.png 2x" style="border: 0"><img src="http://tracker.example.com/p.gif" width="1" height="1"><a href="javascript:alert(1)">js</a><iframe src="/embed">fallback</iframe><!-- comment --><em>open &amp; 1 < 2]]></description>
+            </item>
+            <item>
+                <content:encoded xml:base="http://example.com/base/"><![CDATA[<IMG SRC='b.png' ALT="a &quot;b&quot;">]]></content:encoded>
+            </item>
+            <item><description><![CDATA[<img src="c.png"></p>]]></description></item>
+            <item>
+                <link>http://example.com/posts/4</link>
+                <content:encoded xml:base="http://cdn.example.com/4/"><![CDATA[<img src="d.png">]]></content:encoded>
+            </item>
+        </channel></rss>"#;
+
+        let html = feed_rs::parser::parse(xml.as_bytes())?
+            .entries
+            .into_iter()
+            .map(|entry| Item::from_entry(&feed, entry, Utc::now()).html)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            html,
+            vec![
+                r#"<p>Hi <a href="http://example.com/about">me</a><img src="http://example.com/a.png" srcset="http://example.com/a.png 1x, http://example.com/a@2x.png 2x"><a>js</a><em>open &amp; 1 &lt; 2</em></p>"#,
+                r#"<img src="http://example.com/base/b.png" alt="a &quot;b&quot;">"#,
+                r#"<img src="http://1.example.com/c.png">"#,
+                r#"<img src="http://cdn.example.com/4/d.png">"#,
+            ]
+        );
+
+        let xml = r#"
+        <feed xmlns="http://www.w3.org/2005/Atom">
+            <entry>
+                <id>urn:1</id>
+                <link href="http://example.com/posts/1"/>
+                <content type="html" xml:base="http://cdn.example.com/1/">&lt;img src="e.png"&gt;</content>
+            </entry>
+            <entry>
+                <id>urn:2</id>
+                <link href="http://example.com/posts/2"/>
+                <content type="html">&lt;img src="f.png"&gt;</content>
+            </entry>
+        </feed>"#;
+        let html = feed_rs::parser::parse(xml.as_bytes())?
+            .entries
+            .into_iter()
+            .map(|entry| Item::from_entry(&feed, entry, Utc::now()).html)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            html,
+            vec![
+                r#"<img src="http://cdn.example.com/1/e.png">"#,
+                r#"<img src="http://example.com/posts/f.png">"#,
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedupe() -> Result<()> {
+        let conn = open_test_db();
+        let mut feed = make_test_feed(1).insert(&conn)?;
+
+        let parse = |feed: &Feed, entries: &str| {
+            let xml = format!(r#"<rss version="2.0"><channel>{}</channel></rss>"#, entries);
+            parse_feed(xml.as_bytes(), None)
+                .unwrap()
+                .entries
+                .into_iter()
+                .map(|entry| Item::from_entry(feed, entry, Utc::now()))
+                .collect::<Vec<_>>()
+        };
+        const DATE: &'static str = "<pubDate>Tue, 01 Sep 2020 00:00:00 GMT</pubDate>";
+
+        let (added, changed) = feed.dedupe(
+            &conn,
+            parse(
+                &feed,
+                &format!(
+                    r"
+            <item><guid>a</guid><link>http://example.com/a?utm_source=rss</link>
+                <title>A</title><description>one</description>{0}</item>
+            <item><link>http://example.com/b?id=1&amp;fbclid=x</link><title>B</title>{0}</item>
+            <item><title>C</title><description>three</description>{0}</item>",
+                    DATE
+                ),
+            ),
+        )?;
+        assert!(changed.is_empty());
+        let stored = Item::insert_multi(&conn, added)?;
+        let urls = stored
+            .iter()
+            .map(|item| item.url.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            vec!["http://example.com/a", "http://example.com/b?id=1", ""]
+        );
+
+        // the GUID, the normalized URL and the content identify the stored items
+        let entries = format!(
+            r"
+            <item><guid>a</guid><link>http://example.com/a?utm_source=email</link>
+                <title>A</title><description>one, edited</description>{0}</item>
+            <item><link>http://example.com/b?utm_medium=x&amp;id=1</link><title>B</title>{0}</item>
+            <item><title>C</title><description>three</description>{0}</item>
+            <item><title>C</title><description>three</description>{0}</item>
+            <item><guid>d</guid><title>D</title>{0}</item>",
+            DATE
+        );
+        let (added, changed) = feed.dedupe(&conn, parse(&feed, &entries))?;
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].title, "D");
+        assert!(changed.is_empty());
+
+        feed.update_items = true;
+        let (added, changed) = feed.dedupe(&conn, parse(&feed, &entries))?;
+        assert_eq!(added.len(), 1);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].id, stored[0].id);
+
+        changed[0].update_content(&conn)?;
+        let item = Item::get(&conn, stored[0].id)?;
+        assert_eq!(item.html, "one, edited");
+        assert_eq!(item.content_hash, changed[0].content_hash);
+        let hits = ItemSearch::new("edited").fetch(&conn)?;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].item.id, stored[0].id);
+
+        // recurring posts with their own GUID are new even if their content repeats
+        let weekly = |guid: &str| {
+            format!(
+                "<item><guid>{}</guid><title>Weekly thread</title>{}</item>",
+                guid, DATE
+            )
+        };
+        let (added, _) = feed.dedupe(&conn, parse(&feed, &weekly("weekly-1")))?;
+        Item::insert_multi(&conn, added)?;
+        let (added, _) = feed.dedupe(&conn, parse(&feed, &weekly("weekly-2")))?;
+        assert_eq!(added.len(), 1);
+        let (added, _) = feed.dedupe(&conn, parse(&feed, &weekly("weekly-1")))?;
+        assert!(added.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retention() {
+        let conn = open_test_db();
+
+        let day = |days: i64| Utc.timestamp(days * 24 * 60 * 60, 0);
+        let items = (1..=10)
+            .map(|i| {
+                let mut item = make_test_item(1, i);
+                item.created_on_time = day(i as i64);
+                item.html = format!("<a href=\"http://example.com/{}\">link</a>", i);
+                item
+            })
+            .chain((1..=3).map(|i| make_test_item(2, i)))
+            .collect();
+        let items = Item::insert_multi(&conn, items).unwrap();
+        for item in items.iter() {
+            Link::insert_for_item(&conn, item).unwrap();
+        }
+
+        let mut items = items.into_iter();
+        for item in items.by_ref().take(4) {
+            item.read(&conn).unwrap();
+        }
+        items
+            .next()
+            .unwrap()
+            .read(&conn)
+            .unwrap()
+            .save(&conn)
+            .unwrap();
+
+        let now = day(12);
+        assert!(Retention::new().expired(&conn, now).unwrap().is_empty());
+
+        // items 1 - 4 are read and older than 5 days, item 5 is saved
+        let expired = Retention::new().read_days(5).expired(&conn, now).unwrap();
+        assert_eq!(expired, vec![(1, 1), (1, 2), (1, 3), (1, 4)]);
+
+        // items 6 - 10 are the most recent, item 5 is saved
+        let expired = Retention::new().per_feed(5).expired(&conn, now).unwrap();
+        assert_eq!(expired, vec![(1, 1), (1, 2), (1, 3), (1, 4)]);
+
+        let retention = Retention::new().read_days(5).per_feed(2);
+        let expired = retention.expired(&conn, now).unwrap();
+        assert_eq!(
+            expired,
+            vec![
+                (1, 1),
+                (1, 2),
+                (1, 3),
+                (1, 4),
+                (1, 6),
+                (1, 7),
+                (1, 8),
+                (2, 11)
+            ]
+        );
+
+        // items stay unread for subscribers until they read them
+        let user = User::new("alice".to_owned(), "secret")
+            .insert(&conn)
+            .unwrap();
+        user.subscribe(&conn, 1).unwrap();
+        let expired = Retention::new().read_days(5).expired(&conn, now).unwrap();
+        assert!(expired.is_empty());
+        user.set_item_read(&conn, 1, true).unwrap();
+        let expired = Retention::new().read_days(5).expired(&conn, now).unwrap();
+        assert_eq!(expired, vec![(1, 1)]);
+        for id in 2..=4 {
+            user.set_item_read(&conn, id, true).unwrap();
+        }
+
+        // reads of subscribers count even when the item is unread globally
+        user.set_item_read(&conn, 6, true).unwrap();
+        let expired = Retention::new().read_days(5).expired(&conn, now).unwrap();
+        assert_eq!(expired, vec![(1, 1), (1, 2), (1, 3), (1, 4), (1, 6)]);
+
+        assert_eq!(retention.prune(&conn, now).unwrap(), 8);
+        assert_eq!(Item::count(&conn).unwrap(), 5);
+        assert!(retention.expired(&conn, now).unwrap().is_empty());
+        let links: u32 = conn
+            .query_row("SELECT COUNT(*) FROM `link`", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(links, 3);
+    }
+
+    #[test]
+    fn test_read_by_spark() {
+        let conn = open_test_db();
+
+        let group = make_test_group(1).insert(&conn).unwrap();
+        let kindling = make_test_feed(1).insert(&conn).unwrap();
+        let kindling = group.add_feed(&conn, kindling).unwrap();
+        let spark = make_test_feed(2).insert(&conn).unwrap();
+
+        let items = (1..=10)
+            .map(|i| make_test_item(kindling.id, i))
+            .chain((1..=10).map(|i| make_test_item(spark.id, i)))
+            .collect();
+        Item::insert_multi(&conn, items).unwrap();
+
+        Feed::read_by_spark(&conn, false, Some(6)).unwrap();
+        let unread = Item::unread_ids(&conn).unwrap();
+        assert_eq!(unread.len(), 15);
+
+        Feed::read_by_spark(&conn, true, None).unwrap();
+        let unread = Item::unread_ids(&conn).unwrap();
+        assert_eq!(unread, (6..=10).collect::<Vec<_>>());
+
+        Feed::unread_by_spark(&conn, true, None).unwrap();
+        let unread = Item::unread_ids(&conn).unwrap();
+        assert_eq!(unread.len(), 15);
+    }
+
+    #[test]
+    fn test_hot_links() {
+        let conn = open_test_db();
+
+        let now = Utc::now();
+        let items = (1..=3)
+            .map(|i| {
+                let mut item = make_test_item(i, i);
+                item.html = format!(
+                    r#"<p><a href="https://hot.example.com/#top">Hot</a>
+                    <a href="/local">local</a>
+                    <a href="https://cold.example.com/{}">Cold</a></p>"#,
+                    i
+                );
+                item.created_on_time = now - chrono::Duration::hours(i as i64);
+                item
+            })
+            .collect();
+        for item in Item::insert_multi(&conn, items).unwrap() {
+            Link::insert_for_item(&conn, &item).unwrap();
+        }
+        assert_eq!(Link::count(&conn).unwrap(), 4);
+
+        let links = Link::hot(&conn, 0, 7, 1).unwrap();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].url, "https://hot.example.com/");
+        assert_eq!(links[0].title, "Hot");
+        assert_eq!(links[0].item_ids, vec![1, 2, 3]);
+        assert!(links[0].temperature > 29.0);
+
+        assert!(Link::hot(&conn, 1, 7, 1).unwrap().is_empty());
+        assert!(Link::hot(&conn, 0, 7, 2).unwrap().is_empty());
+
+        // links only referenced by items of a deleted feed go with them
+        Item::delete_by_feed(&conn, 3).unwrap();
+        assert_eq!(Link::count(&conn).unwrap(), 3);
+        assert_eq!(Link::hot(&conn, 0, 7, 1).unwrap()[0].item_ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_crawl_errors() {
+        let conn = open_test_db();
+        let feed1 = make_test_feed(1).insert(&conn).unwrap();
+        let feed2 = make_test_feed(2).insert(&conn).unwrap();
+
+        let now = Utc::now();
+        assert_eq!(Feed::all_due(&conn, now).unwrap().len(), 2);
+
+        assert_eq!(Feed::record_failure(&conn, feed1.id, "oops").unwrap(), 1);
+        assert_eq!(Feed::record_failure(&conn, feed1.id, "oops").unwrap(), 2);
+        Feed::set_next_crawl_at(&conn, feed1.id, now + chrono::Duration::hours(1)).unwrap();
+
+        let due = Feed::all_due(&conn, now).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, feed2.id);
+
+        let failing = Feed::all_failing(&conn).unwrap();
+        assert_eq!(failing.len(), 1);
+        assert_eq!(failing[0].last_error.as_deref(), Some("oops"));
+        assert_eq!(failing[0].error_count, 2);
+
+        Feed::record_success(&conn, feed1.id, None).unwrap();
+        assert!(Feed::all_failing(&conn).unwrap().is_empty());
+        assert_eq!(Feed::all_due(&conn, now).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_favicon_retry() -> Result<()> {
+        let conn = open_test_db();
+        let mut feed = make_test_feed(1).insert(&conn)?;
+
+        let now = Utc::now();
+        assert!(feed.favicon_due(now));
+        feed.record_favicon_failure(&conn, now)?;
+        let mut feed = Feed::get(&conn, feed.id)?;
+        assert_eq!(feed.favicon_checked_at, Some(now));
+        assert!(!feed.favicon_due(now + chrono::Duration::days(1)));
+        assert!(feed.favicon_due(now + chrono::Duration::days(FAVICON_RETRY_DAYS)));
+
+        // renaming keeps the failure, a new site is checked right away
+        feed.title = "renamed".to_owned();
+        feed.update(&conn)?;
+        assert!(Feed::get(&conn, feed.id)?.favicon_checked_at.is_some());
+        feed.site_url = "http://other.example.com/".to_owned();
+        feed.update(&conn)?;
+        assert!(Feed::get(&conn, feed.id)?.favicon_due(now));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_headers() -> Result<()> {
+        let conn = open_test_db();
+        let feed = make_test_feed(1).insert(&conn)?;
+        let headers = feed.request_headers()?;
+        assert!(headers.user_agent.is_none());
+        assert!(headers.extra.is_empty());
+
+        Feed::set_user_agent(&conn, feed.id, Some("Mozilla/5.0"))?;
+        Feed::set_headers(&conn, feed.id, Some("Cookie: a=b\nX-Token:  secret "))?;
+        assert!(Feed::set_headers(&conn, feed.id, Some("no colon")).is_err());
+
+        let headers = Feed::get(&conn, feed.id)?.request_headers()?;
+        assert_eq!(headers.user_agent.as_deref(), Some("Mozilla/5.0"));
+        assert_eq!(
+            headers.extra,
+            vec![
+                ("Cookie".to_owned(), "a=b".to_owned()),
+                ("X-Token".to_owned(), "secret".to_owned()),
+            ]
+        );
+
+        Feed::set_headers(&conn, feed.id, None)?;
+        assert!(Feed::get(&conn, feed.id)?
+            .request_headers()?
+            .extra
+            .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_observed_interval() {
+        let conn = open_test_db();
+        let feed = make_test_feed(1).insert(&conn).unwrap();
+        assert_eq!(feed.observed_interval(&conn).unwrap(), None);
+
+        let items = (1..=20)
+            .map(|i| {
+                let mut item = make_test_item(feed.id, i);
+                item.created_on_time = Utc.timestamp(i as i64 * 3600, 0);
+                item
+            })
+            .collect();
+        Item::insert_multi(&conn, items).unwrap();
+        assert_eq!(
+            feed.observed_interval(&conn).unwrap(),
+            Some(chrono::Duration::hours(1))
+        );
+    }
+
+    #[test]
+    fn test_rules() -> Result<()> {
+        let conn = open_test_db();
+        let feed1 = make_test_feed(1).insert(&conn)?;
+        let feed2 = make_test_feed(2).insert(&conn)?;
+        let group = make_test_group(1).insert(&conn)?;
+        let feed2 = group.add_feed(&conn, feed2)?;
+
+        Rule::new(
+            RuleField::Title,
+            "SPONSORED".to_owned(),
+            false,
+            RuleAction::Drop,
+        )
+        .insert(&conn)?;
+        Rule::new(
+            RuleField::Content,
+            r"\brust\b".to_owned(),
+            true,
+            RuleAction::Tag("rust".to_owned()),
+        )
+        .feed_id(feed1.id)
+        .insert(&conn)?;
+        Rule::new(RuleField::Author, "bot".to_owned(), false, RuleAction::Read)
+            .group_id(group.id)
+            .insert(&conn)?;
+        assert!(
+            Rule::new(RuleField::Any, "(".to_owned(), true, RuleAction::Save)
+                .insert(&conn)
+                .is_err()
+        );
+        assert_eq!(Rule::for_feed(&conn, feed1.id)?.len(), 2);
+        assert_eq!(Rule::for_feed(&conn, feed2.id)?.len(), 2);
+
+        let rules = RuleSet::for_feed(&conn, feed1.id)?;
+        let mut item = make_test_item(feed1.id, 1);
+        item.title = "Sponsored: a product".to_owned();
+        assert_eq!(rules.apply(&mut item), None);
+
+        let mut item = make_test_item(feed1.id, 2);
+        item.html = "<p>Learning <b>Rust</b> and rust</p>".to_owned();
+        item.author = "bot".to_owned();
+        assert_eq!(rules.apply(&mut item), Some(vec!["rust".to_owned()]));
+        assert_eq!(item.is_read, 0);
+
+        let rules = RuleSet::for_feed(&conn, feed2.id)?;
+        let mut item = make_test_item(feed2.id, 3);
+        item.author = "Some Bot".to_owned();
+        assert_eq!(rules.apply(&mut item), Some(vec![]));
+        assert_eq!(item.is_read, 1);
+
+        // tags are listed, addressable as streams, and deleted along with items
+        let items = Item::insert_multi(
+            &conn,
+            vec![make_test_item(feed1.id, 4), make_test_item(feed1.id, 5)],
+        )?;
+        Item::add_tags(&conn, items[0].id, &["rust".to_owned(), "rust".to_owned()])?;
+        assert_eq!(Item::all_tags(&conn)?, vec!["rust"]);
+        assert_eq!(
+            Item::tags_multi(&conn, &[items[0].id])?.get(&items[0].id),
+            Some(&vec!["rust".to_owned()])
+        );
+        let ids = StreamQuery::new(ItemStream::Tag("rust".to_owned())).fetch_ids(&conn)?;
+        assert_eq!(ids, vec![items[0].id]);
+        let ids =
+            StreamQuery::new(ItemStream::Tag("rust' OR 1=1 --".to_owned())).fetch_ids(&conn)?;
+        assert!(ids.is_empty());
+        let ids = StreamQuery::new(ItemStream::All)
+            .exclude(ItemStream::Tag("rust".to_owned()))
+            .newer_than(Utc.timestamp(0, 0))
+            .fetch_ids(&conn)?;
+        assert_eq!(ids, vec![items[1].id]);
+
+        feed1.remove(&conn)?;
+        assert!(Item::all_tags(&conn)?.is_empty());
+        assert_eq!(Rule::for_feed(&conn, feed2.id)?.len(), 2);
+        group.remove(&conn)?;
+        assert_eq!(Rule::all(&conn)?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_users() -> Result<()> {
+        let conn = open_test_db();
+        let feed1 = make_test_feed(1).insert(&conn)?;
+        let feed2 = make_test_feed(2).insert(&conn)?;
+        let items = Item::insert_multi(
+            &conn,
+            (1..=3)
+                .map(|i| make_test_item(feed1.id, i))
+                .chain((4..=5).map(|i| make_test_item(feed2.id, i)))
+                .collect(),
+        )?;
+        let ids = items.iter().map(|item| item.id).collect::<Vec<_>>();
+
+        let alice = User::new("alice".to_owned(), "secret").insert(&conn)?;
+        let bob = User::new("bob".to_owned(), "hunter2").insert(&conn)?;
+        assert!(User::new("alice".to_owned(), "other")
+            .insert(&conn)
+            .is_err());
+        let key = md5_hex("alice:secret");
+        assert_eq!(User::get_by_api_key(&conn, &key)?.unwrap().id, alice.id);
+        assert!(User::get_by_api_key(&conn, "invalid")?.is_none());
+
+        alice.subscribe(&conn, feed1.id)?;
+        alice.subscribe(&conn, feed1.id)?;
+        bob.subscribe(&conn, feed1.id)?;
+        bob.subscribe(&conn, feed2.id)?;
+        assert_eq!(alice.feeds(&conn)?.len(), 1);
+        assert_eq!(alice.item_count(&conn)?, 3);
+        assert_eq!(bob.item_count(&conn)?, 5);
+
+        // state is per user, and separate from the global state
+        alice.set_item_read(&conn, ids[0], true)?;
+        alice.set_item_saved(&conn, ids[1], true)?;
+        alice.set_item_read(&conn, ids[3], true)?;
+        assert_eq!(alice.unread_ids(&conn)?, vec![ids[1], ids[2]]);
+        assert_eq!(alice.saved_ids(&conn)?, vec![ids[1]]);
+        assert_eq!(bob.unread_ids(&conn)?, ids);
+        assert_eq!(Item::unread_ids(&conn)?, ids);
+
+        let fetched = ItemQuery::new().user(&alice).fetch(&conn)?;
+        assert_eq!(fetched.len(), 3);
+        assert_eq!((fetched[0].is_read, fetched[0].is_saved), (1, 0));
+        assert_eq!((fetched[1].is_read, fetched[1].is_saved), (0, 1));
+        let fetched = ItemQuery::new()
+            .with_ids(vec![ids[0], ids[3]])
+            .user(&alice)
+            .fetch(&conn)?;
+        assert_eq!(fetched.len(), 1);
+
+        // streams, searches and lookups by id only see subscriptions and the user's state
+        let stream = StreamQuery::new(ItemStream::All).user(&alice);
+        assert_eq!(stream.fetch_ids(&conn)?, vec![ids[2], ids[1], ids[0]]);
+        let fetched = stream.fetch(&conn)?;
+        assert_eq!((fetched[2].is_read, fetched[1].is_saved), (1, 1));
+        let stream = StreamQuery::new(ItemStream::All)
+            .exclude(ItemStream::Read)
+            .user(&alice);
+        assert_eq!(stream.fetch_ids(&conn)?, vec![ids[2], ids[1]]);
+        let stream = StreamQuery::new(ItemStream::Saved).user(&alice);
+        assert_eq!(stream.fetch_ids(&conn)?, vec![ids[1]]);
+        assert!(StreamQuery::new(ItemStream::Read)
+            .user(&bob)
+            .fetch_ids(&conn)?
+            .is_empty());
+        let hits = ItemSearch::new("item").user(&alice).fetch(&conn)?;
+        assert_eq!(hits.len(), 3);
+        assert!(hits.iter().any(|hit| hit.item.is_saved == 1));
+        let fetched = alice.items(&conn, &[ids[1], ids[3]])?;
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].is_saved, 1);
+
+        bob.set_feed_read(&conn, feed1.id, true, Some(2))?;
+        assert_eq!(bob.unread_ids(&conn)?, vec![ids[1], ids[2], ids[3], ids[4]]);
+        bob.set_spark_read(&conn, true, true, None)?;
+        assert!(bob.unread_ids(&conn)?.is_empty());
+        bob.set_all_read(&conn, false, None)?;
+        assert_eq!(bob.unread_ids(&conn)?, ids);
+        bob.set_all_read(&conn, true, None)?;
+        assert!(bob.unread_ids(&conn)?.is_empty());
+        alice.set_item_read(&conn, ids[0], false)?;
+        assert_eq!(alice.unread_ids(&conn)?.len(), 3);
+
+        // state set by rules is given to subscribers
+        let mut item = make_test_item(feed1.id, 6);
+        item.is_saved = 1;
+        let item = Item::insert_multi(&conn, vec![item])?.remove(0);
+        User::share_item_state(&conn, &item)?;
+        assert_eq!(alice.saved_ids(&conn)?, vec![ids[1], item.id]);
+
+        // items saved by a user are kept
+        let expired = Retention::new().per_feed(0).expired(&conn, Utc::now())?;
+        assert!(!expired.contains(&(feed1.id, ids[1])));
+        assert!(expired.contains(&(feed1.id, ids[2])));
+
+        feed2.remove(&conn)?;
+        assert_eq!(bob.feeds(&conn)?.len(), 1);
+        alice.remove(&conn)?;
+        assert_eq!(User::count(&conn)?, 1);
+        assert_eq!(
+            conn.query_row(
+                "SELECT COUNT(*) FROM `item_state` WHERE `user_id` = ?1",
+                params![bob.id],
+                |row| row.get::<_, u32>(0)
+            )?,
+            4
+        );
+
+        Ok(())
+    }
 }
@@ -1,11 +1,15 @@
+use futures::stream::{self, StreamExt};
 use log::{debug, info, warn};
-use quick_xml::events::Event;
-use quick_xml::Reader;
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::io::Write;
 use std::path::Path;
 use std::{collections::HashMap, io::BufRead};
 
 use crate::error::Result;
-use crate::model::Feed;
+use crate::model::{Feed, Group, ModelExt};
 use crate::remote::RemoteFeed;
 
 #[derive(Debug)]
@@ -13,6 +17,7 @@ pub struct ImportedFeed {
     rss_url: String,
     title: Option<String>,
     site_url: Option<String>,
+    groups: Vec<String>,
 }
 
 impl ImportedFeed {
@@ -21,9 +26,23 @@ impl ImportedFeed {
             rss_url,
             title,
             site_url,
+            groups: Vec::new(),
         }
     }
 
+    pub fn url(&self) -> &str {
+        &self.rss_url
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// Names of groups this feed belongs to
+    pub fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
     /// Update title and site_url from rss_url when they are unspecified
     pub async fn update(&mut self) -> Result<()> {
         if self.title.is_some() && self.site_url.is_some() {
@@ -91,125 +110,441 @@ impl From<ImportedFeed> for Feed {
     }
 }
 
-type ImportResult = Result<Vec<(Option<String>, Vec<ImportedFeed>)>>;
+/// How nested outlines are mapped to groups, since groups can not be nested.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Nesting {
+    /// Joins names of all enclosing outlines, e.g. `Tech/Rust`
+    Flatten,
+    /// Uses the outermost outline, e.g. `Tech`
+    Top,
+    /// Uses the innermost outline, e.g. `Rust`
+    Leaf,
+}
 
-pub fn from_file(path: &Path) -> ImportResult {
+impl Nesting {
+    fn group_name(self, path: &[String]) -> Option<String> {
+        match self {
+            Nesting::Flatten if !path.is_empty() => Some(path.join("/")),
+            Nesting::Flatten => None,
+            Nesting::Top => path.first().cloned(),
+            Nesting::Leaf => path.last().cloned(),
+        }
+    }
+}
+
+impl std::str::FromStr for Nesting {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "flatten" => Ok(Nesting::Flatten),
+            "top" => Ok(Nesting::Top),
+            "leaf" => Ok(Nesting::Leaf),
+            _ => Err(crate::error::Error::message(format!(
+                "unknown nesting strategy '{}' (expected flatten, top or leaf)",
+                s
+            ))),
+        }
+    }
+}
+
+type ImportResult = Result<Vec<ImportedFeed>>;
+
+pub fn from_file(path: &Path, nesting: Nesting) -> ImportResult {
     let reader = Reader::from_file(&path)?;
-    from_reader(reader)
+    from_reader(reader, nesting)
+}
+
+pub fn from_bytes(bytes: &[u8], nesting: Nesting) -> ImportResult {
+    from_reader(Reader::from_reader(bytes), nesting)
+}
+
+fn outline_attributes(e: &BytesStart) -> HashMap<Vec<u8>, String> {
+    e.attributes()
+        .filter_map(|attr| {
+            if let Ok(attr) = attr {
+                let value = match attr.unescaped_value() {
+                    Ok(value) => value.into_owned(),
+                    Err(_) => attr.value.into_owned(),
+                };
+                Some((
+                    attr.key.to_owned(),
+                    String::from_utf8_lossy(&value).into_owned(),
+                ))
+            } else {
+                None
+            }
+        })
+        .collect()
 }
 
-fn from_reader<B: BufRead>(mut reader: Reader<B>) -> ImportResult {
+/// Parses outlines into feeds. Feeds are deduplicated by their URLs, and each feed carries all
+/// groups it appears in.
+fn from_reader<B: BufRead>(mut reader: Reader<B>, nesting: Nesting) -> ImportResult {
     reader.trim_text(true);
 
     let mut buf = Vec::new();
-    let mut feeds: Option<Vec<ImportedFeed>> = None;
-    let mut group: Option<String> = None;
-    let mut result: Vec<(Option<String>, Vec<ImportedFeed>)> = Vec::new();
+    // enclosing outlines; `None` for feed outlines with children
+    let mut path: Vec<Option<String>> = Vec::new();
+    let mut result: Vec<ImportedFeed> = Vec::new();
+    let mut indices: HashMap<String, usize> = HashMap::new();
 
     loop {
-        match reader.read_event(&mut buf) {
+        let (e, is_empty) = match reader.read_event(&mut buf) {
             Ok(Event::Start(ref e)) => {
                 debug!("start tag <{}>", String::from_utf8_lossy(e.name()));
-
-                if e.name() != b"outline" {
-                    continue;
-                }
-
-                let attrs = e
-                    .attributes()
-                    .filter_map(|attr| {
-                        if let Ok(attr) = attr {
-                            Some((attr.key, attr.value))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<HashMap<_, _>>();
-
-                if group.is_some() {
-                    warn!("possible malformed OPML file: previous group tag is not closed.");
-                    continue;
-                }
-
-                if let Some(title) = attrs.get(&b"title"[..]).or_else(|| attrs.get(&b"text"[..])) {
-                    let name = String::from_utf8_lossy(title).into_owned();
-                    info!("processing group: {}", &name);
-                    group = Some(name);
-                }
+                (e.to_owned(), false)
+            }
+            Ok(Event::Empty(ref e)) => {
+                debug!("self-closing tag <{} />", String::from_utf8_lossy(e.name()));
+                (e.to_owned(), true)
             }
             Ok(Event::End(ref e)) => {
                 debug!("end tag </{}>", String::from_utf8_lossy(e.name()));
                 if e.name() == b"outline" {
-                    if feeds.as_ref().map(|x| !x.is_empty()).unwrap_or(false) {
-                        info!("processed group: {:?}", group.as_ref());
-                        result.push((group.take(), feeds.take().unwrap()));
+                    if let Some(Some(group)) = path.pop() {
+                        info!("processed group: {}", group);
                     }
                 }
+                buf.clear();
+                continue;
             }
-            Ok(Event::Empty(ref e)) => {
-                let tagname = String::from_utf8_lossy(e.name());
-                debug!("self-closing tag <{} />", tagname);
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                return Err((e, reader.buffer_position()).into());
+            }
+            _ => {
+                buf.clear();
+                continue;
+            }
+        };
+        buf.clear();
+
+        if e.name() != b"outline" {
+            continue;
+        }
+
+        let attrs = outline_attributes(&e);
+        let title = attrs
+            .get(&b"title"[..])
+            .or_else(|| attrs.get(&b"text"[..]))
+            .cloned();
+
+        let rss_url = match attrs.get(&b"xmlUrl"[..]) {
+            Some(url) => url.clone(),
+            None if is_empty => {
+                warn!(
+                    "outline item does not contain feed URL, skipping: <outline {} />",
+                    String::from_utf8_lossy(e.attributes_raw()),
+                );
+                continue;
+            }
+            None => {
+                let name = title.unwrap_or_default();
+                info!("processing group: {}", &name);
+                path.push(Some(name));
+                continue;
+            }
+        };
+
+        if !is_empty {
+            path.push(None);
+        }
+
+        let site_url = attrs.get(&b"htmlUrl"[..]).cloned();
+        let groups = path.iter().flatten().cloned().collect::<Vec<_>>();
+        let group = nesting.group_name(&groups);
+
+        info!(
+            "importing feed {} (\"{:?}\", site: {:?}, group: {:?})",
+            rss_url, title, site_url, group
+        );
+
+        let feed = match indices.get(&rss_url) {
+            Some(&idx) => &mut result[idx],
+            None => {
+                indices.insert(rss_url.clone(), result.len());
+                result.push(ImportedFeed::new(rss_url, title.clone(), site_url.clone()));
+                // unwrap(): just pushed
+                result.last_mut().unwrap()
+            }
+        };
+
+        if feed.title.is_none() {
+            feed.title = title;
+        }
+        if feed.site_url.is_none() {
+            feed.site_url = site_url;
+        }
+        if let Some(group) = group {
+            if !feed.groups.contains(&group) {
+                feed.groups.push(group);
+            }
+        }
+    }
 
-                if e.name() != b"outline" {
+    Ok(result)
+}
+
+/// Fetches missing titles and site URLs of imported feeds, and drops invalid feeds.
+pub async fn normalize(imports: Vec<ImportedFeed>) -> Vec<ImportedFeed> {
+    stream::iter(imports)
+        .filter_map(|mut feed| async move {
+            if let Err(e) = feed.update().await {
+                warn!("failed to update feed {}: {:?}", feed, e);
+            }
+
+            if let Err(e) = feed.validate() {
+                warn!("invalid feed ({}): {:?}", feed, e);
+                None
+            } else {
+                Some(feed)
+            }
+        })
+        .collect()
+        .await
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportSummary {
+    /// Number of feeds created
+    pub added: u32,
+    /// Number of feeds that already existed
+    pub existing: u32,
+}
+
+/// Adds imported feeds to the database. Feeds that already exist are only added to the groups
+/// they are not in yet. Missing groups are created.
+pub fn import(conn: &Connection, imports: Vec<ImportedFeed>) -> Result<ImportSummary> {
+    let mut summary = ImportSummary::default();
+    let mut groups: HashMap<String, Option<Group>> = HashMap::new();
+    for imported in imports.into_iter() {
+        let titles = imported.groups().to_vec();
+        let mut feed = match Feed::get_by_url(conn, imported.url())? {
+            Some(feed) => {
+                info!("feed {} already exists", feed.url);
+                summary.existing += 1;
+                feed
+            }
+            None => match Feed::from(imported).insert(conn) {
+                Err(e) => {
+                    warn!("unable to create feed: {:?}", e);
                     continue;
                 }
-
-                let attrs = e
-                    .attributes()
-                    .filter_map(|attr| {
-                        if let Ok(attr) = attr {
-                            Some((attr.key, attr.value))
-                        } else {
+                Ok(feed) => {
+                    summary.added += 1;
+                    feed
+                }
+            },
+        };
+
+        for title in titles.into_iter() {
+            let group = groups.entry(title.clone()).or_insert_with(|| {
+                if let Ok(group) = Group::get_by_name(conn, &title) {
+                    Some(group)
+                } else {
+                    let group = Group::new(title.clone());
+                    match group.insert(conn) {
+                        Ok(group) => Some(group),
+                        Err(e) => {
+                            warn!("unable to create group {}: {:?}", title, e);
                             None
                         }
-                    })
-                    .collect::<HashMap<_, _>>();
-
-                if feeds.is_none() {
-                    feeds = Some(Vec::new());
+                    }
                 }
-
-                let url = match attrs.get(&b"xmlUrl"[..]) {
-                    Some(url) => url,
-                    None => {
-                        warn!(
-                            "outline item does not contain feed URL, skipping: <{} {} />",
-                            tagname,
-                            String::from_utf8_lossy(e.attributes_raw()),
-                        );
-                        continue;
+            });
+
+            if let Some(group) = group.as_ref() {
+                feed = match group.add_feed(conn, feed) {
+                    Ok(feed) => feed,
+                    Err(e) => {
+                        warn!("unable to add feed to group {:?}: {:?}", group, e);
+                        break;
                     }
                 };
+            }
+        }
+    }
 
-                let title = attrs.get(&b"title"[..]).or_else(|| attrs.get(&b"text"[..]));
-                let site_url = attrs.get(&b"htmlUrl"[..]);
+    info!("import completed.");
 
-                let rss_url = String::from_utf8_lossy(url).into_owned();
-                let title = title.map(|x| String::from_utf8_lossy(x).into_owned());
-                let site_url = site_url.map(|x| String::from_utf8_lossy(x).into_owned());
+    Ok(summary)
+}
 
-                info!(
-                    "importing feed {} (\"{:?}\", site: {:?})",
-                    rss_url, title, site_url
-                );
-                feeds
-                    .as_mut()
-                    .unwrap()
-                    .push(ImportedFeed::new(rss_url, title, site_url));
-            }
-            Ok(Event::Eof) => break,
-            Err(e) => {
-                return Err((e, reader.buffer_position()).into());
-            }
-            _ => (),
+/// Writes all groups and feeds in the database as an OPML document. Feeds without a group are
+/// placed at the top level.
+pub fn export<W: Write>(conn: &Connection, writer: W) -> Result<()> {
+    let groups = Group::all(conn)?
+        .into_iter()
+        .map(|group| {
+            let feeds = group.get_feeds(conn)?;
+            Ok((group, feeds))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let ungrouped = Feed::all(conn)?
+        .into_iter()
+        .filter(|feed| feed.is_spark != 0)
+        .collect::<Vec<_>>();
+
+    to_writer(writer, &groups, &ungrouped)
+}
+
+/// Writes an OPML 2.0 document. Each group becomes an outline containing its feeds, and
+/// `ungrouped` feeds are placed at the top level.
+fn to_writer<W: Write>(writer: W, groups: &[(Group, Vec<Feed>)], ungrouped: &[Feed]) -> Result<()> {
+    let mut writer = Writer::new_with_indent(writer, b' ', 4);
+
+    writer.write_event(Event::Decl(BytesDecl::new(b"1.0", Some(b"UTF-8"), None)))?;
+
+    let mut opml = BytesStart::borrowed_name(b"opml");
+    opml.push_attribute(("version", "2.0"));
+    writer.write_event(Event::Start(opml))?;
+
+    writer.write_event(Event::Start(BytesStart::borrowed_name(b"head")))?;
+    write_text_element(&mut writer, b"title", "Lares subscriptions")?;
+    write_text_element(
+        &mut writer,
+        b"dateCreated",
+        &chrono::Utc::now().to_rfc2822(),
+    )?;
+    writer.write_event(Event::End(BytesEnd::borrowed(b"head")))?;
+
+    writer.write_event(Event::Start(BytesStart::borrowed_name(b"body")))?;
+    for (group, feeds) in groups.iter() {
+        let mut outline = BytesStart::borrowed_name(b"outline");
+        outline.push_attribute(("text", group.title.as_str()));
+        outline.push_attribute(("title", group.title.as_str()));
+
+        if feeds.is_empty() {
+            writer.write_event(Event::Empty(outline))?;
+            continue;
         }
+
+        writer.write_event(Event::Start(outline))?;
+        for feed in feeds.iter() {
+            write_feed(&mut writer, feed)?;
+        }
+        writer.write_event(Event::End(BytesEnd::borrowed(b"outline")))?;
+    }
+    for feed in ungrouped.iter() {
+        write_feed(&mut writer, feed)?;
     }
+    writer.write_event(Event::End(BytesEnd::borrowed(b"body")))?;
 
-    // no group catch
-    if feeds.as_ref().map(|x| !x.is_empty()).unwrap_or(false) {
-        info!("processed group: {:?}", group.as_ref());
-        result.push((group.take(), feeds.take().unwrap()));
+    writer.write_event(Event::End(BytesEnd::borrowed(b"opml")))?;
+    writer.inner().write_all(b"\n")?;
+
+    Ok(())
+}
+
+fn write_text_element<W: Write>(writer: &mut Writer<W>, name: &[u8], text: &str) -> Result<()> {
+    writer.write_event(Event::Start(BytesStart::borrowed_name(name)))?;
+    writer.write_event(Event::Text(BytesText::from_plain_str(text)))?;
+    writer.write_event(Event::End(BytesEnd::borrowed(name)))?;
+    Ok(())
+}
+
+fn write_feed<W: Write>(writer: &mut Writer<W>, feed: &Feed) -> Result<()> {
+    let mut outline = BytesStart::borrowed_name(b"outline");
+    outline.push_attribute(("type", "rss"));
+    outline.push_attribute(("text", feed.title.as_str()));
+    outline.push_attribute(("title", feed.title.as_str()));
+    outline.push_attribute(("xmlUrl", feed.url.as_str()));
+    if !feed.site_url.is_empty() {
+        outline.push_attribute(("htmlUrl", feed.site_url.as_str()));
+    }
+    writer.write_event(Event::Empty(outline))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NESTED: &str = r#"
+    <opml version="2.0">
+        <body>
+            <outline text="Tech">
+                <outline xmlUrl="https://example.com/feed1" title="Feed 1" />
+                <outline text="Rust">
+                    <outline xmlUrl="https://example.com/feed2" />
+                </outline>
+            </outline>
+            <outline text="News">
+                <outline xmlUrl="https://example.com/feed2" title="Feed 2"
+                    htmlUrl="https://example.com/site2" />
+                <outline text="no feed URL" />
+            </outline>
+            <outline xmlUrl="https://example.com/feed3" title="Feed 3" />
+        </body>
+    </opml>"#;
+
+    fn feed_groups(feeds: &[ImportedFeed]) -> Vec<(&str, Vec<&str>)> {
+        feeds
+            .iter()
+            .map(|feed| {
+                let groups = feed.groups().iter().map(String::as_str).collect();
+                (feed.url(), groups)
+            })
+            .collect()
     }
 
-    Ok(result)
+    #[test]
+    fn test_nesting() {
+        let feeds = from_bytes(NESTED.as_bytes(), Nesting::Flatten).unwrap();
+        assert_eq!(
+            feed_groups(&feeds),
+            vec![
+                ("https://example.com/feed1", vec!["Tech"]),
+                ("https://example.com/feed2", vec!["Tech/Rust", "News"]),
+                ("https://example.com/feed3", vec![]),
+            ]
+        );
+        // duplicates fill in what the first outline of the feed is missing
+        assert_eq!(feeds[1].title(), Some("Feed 2"));
+        assert_eq!(
+            feeds[1].site_url.as_deref(),
+            Some("https://example.com/site2")
+        );
+
+        let feeds = from_bytes(NESTED.as_bytes(), Nesting::Top).unwrap();
+        assert_eq!(feeds[1].groups(), &["Tech", "News"]);
+
+        let feeds = from_bytes(NESTED.as_bytes(), Nesting::Leaf).unwrap();
+        assert_eq!(feeds[1].groups(), &["Rust", "News"]);
+
+        assert_eq!("top".parse::<Nesting>().unwrap(), Nesting::Top);
+        assert!("deepest".parse::<Nesting>().is_err());
+    }
+
+    #[test]
+    fn test_export() {
+        let site = "https://example.com/".to_owned();
+        let feed = |url: &str| Feed::new(format!("{} & co", url), url.to_owned(), site.clone());
+        let groups = vec![
+            (
+                Group::new("Tech".to_owned()),
+                vec![feed("https://example.com/1"), feed("https://example.com/2")],
+            ),
+            (Group::new("Empty".to_owned()), Vec::new()),
+        ];
+        let ungrouped = vec![feed("https://example.com/3")];
+
+        let mut output = Vec::new();
+        to_writer(&mut output, &groups, &ungrouped).unwrap();
+        let xml = String::from_utf8(output).unwrap();
+        assert!(xml.contains(r#"<outline text="Empty" title="Empty"/>"#));
+
+        let feeds = from_bytes(xml.as_bytes(), Nesting::Flatten).unwrap();
+        assert_eq!(
+            feed_groups(&feeds),
+            vec![
+                ("https://example.com/1", vec!["Tech"]),
+                ("https://example.com/2", vec!["Tech"]),
+                ("https://example.com/3", vec![]),
+            ]
+        );
+        assert_eq!(feeds[0].title(), Some("https://example.com/1 & co"));
+        assert_eq!(feeds[2].site_url.as_deref(), Some(site.as_str()));
+    }
 }
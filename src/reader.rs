@@ -0,0 +1,573 @@
+/// Google Reader API, as implemented by FreshRSS and Miniflux.
+///
+/// Clients log in with `ClientLogin` at `/accounts/ClientLogin` and then authenticate every
+/// request under `/reader/api/0` with an `Authorization: GoogleLogin auth=<token>` header. Users
+/// see their own subscriptions and read and saved state, as in the Fever API.
+use chrono::{TimeZone, Utc};
+use percent_encoding::percent_decode_str;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::future::Future;
+use std::pin::Pin;
+use tide::{log, Request, StatusCode};
+use url::form_urlencoded;
+
+use crate::model::{Feed, FeedGroup, Group, Item, ItemStream, ModelExt, StreamQuery, User};
+use crate::state::State;
+
+const READING_LIST: &'static str = "user/-/state/com.google/reading-list";
+const READ: &'static str = "user/-/state/com.google/read";
+const STARRED: &'static str = "user/-/state/com.google/starred";
+const KEPT_UNREAD: &'static str = "user/-/state/com.google/kept-unread";
+const LABEL_PREFIX: &'static str = "user/-/label/";
+const FEED_PREFIX: &'static str = "feed/";
+const ITEM_PREFIX: &'static str = "tag:google.com,2005:reader/item/";
+
+const API_PREFIX: &'static str = "/reader/api/0";
+
+/// Token returned by `ClientLogin` when authentication is disabled.
+const ANONYMOUS_TOKEN: &'static str = "lares";
+
+/// The user authenticated by the token, or `None` for the administrator, who sees all feeds and
+/// the global read and saved state.
+fn current_user(request: &Request<State>) -> Option<User> {
+    request.ext::<User>().cloned()
+}
+
+/// Parameters of a request, collected from both the query string and a form-encoded body. The
+/// Google Reader API accepts either, and repeats keys to pass lists.
+struct Params(Vec<(String, String)>);
+
+impl Params {
+    async fn from_request(request: &mut Request<State>) -> Self {
+        let mut params: Vec<_> = request.url().query_pairs().into_owned().collect();
+        if let Ok(body) = request.body_string().await {
+            params.extend(form_urlencoded::parse(body.as_bytes()).into_owned());
+        }
+        Params(params)
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Replaces the user id in stream ids like `user/1234/label/Tech` with `-`.
+fn normalize_stream_id(id: &str) -> String {
+    let mut parts = id.splitn(3, '/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("user"), Some(_), Some(rest)) => format!("user/-/{}", rest),
+        _ => id.to_owned(),
+    }
+}
+
+fn parse_stream(conn: &rusqlite::Connection, id: &str) -> Result<ItemStream, tide::Error> {
+    let id = normalize_stream_id(id);
+
+    let stream = match id.as_str() {
+        READING_LIST => ItemStream::All,
+        READ => ItemStream::Read,
+        STARRED => ItemStream::Saved,
+        _ => {
+            if let Some(feed_id) = id.strip_prefix(FEED_PREFIX) {
+                match feed_id.parse() {
+                    Ok(feed_id) => ItemStream::Feed(feed_id),
+                    Err(_) => bail!(400, "invalid stream id: {}", id),
+                }
+            } else if let Some(label) = id.strip_prefix(LABEL_PREFIX) {
+                // labels name either a group, or a tag given by filter rules
+                match Group::get_by_name(conn, label) {
+                    Ok(group) => ItemStream::Group(group.id),
+                    Err(_) if Item::all_tags(conn)?.iter().any(|tag| tag == label) => {
+                        ItemStream::Tag(label.to_owned())
+                    }
+                    Err(_) => bail!(404, "unknown label: {}", label),
+                }
+            } else {
+                bail!(400, "unsupported stream id: {}", id)
+            }
+        }
+    };
+
+    Ok(stream)
+}
+
+/// Parses item ids in either the long form (`tag:google.com,2005:reader/item/<hex>`) or the short
+/// form (decimal).
+fn parse_item_id(id: &str) -> Option<u32> {
+    match id.strip_prefix(ITEM_PREFIX) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?.try_into().ok(),
+        None => id.parse::<i64>().ok()?.try_into().ok(),
+    }
+}
+
+fn long_item_id(id: u32) -> String {
+    format!("{}{:016x}", ITEM_PREFIX, id)
+}
+
+fn label_id(group: &Group) -> String {
+    format!("{}{}", LABEL_PREFIX, group.title)
+}
+
+/// Maps feed ids to the groups containing them. Only feeds `user` subscribed to are mapped.
+fn groups_by_feed(
+    conn: &rusqlite::Connection,
+    user: Option<&User>,
+) -> crate::error::Result<HashMap<u32, Vec<Group>>> {
+    let groups = Group::all(conn)?
+        .into_iter()
+        .map(|group| (group.id, group))
+        .collect::<HashMap<_, _>>();
+    let feed_groups = match user {
+        Some(user) => user.feed_groups(conn)?,
+        None => FeedGroup::all(conn)?,
+    };
+
+    let mut result: HashMap<u32, Vec<Group>> = HashMap::new();
+    for feed_group in feed_groups {
+        if let Some(group) = groups.get(&feed_group.group_id) {
+            for feed_id in feed_group.feed_ids {
+                result.entry(feed_id).or_default().push(group.clone());
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn stream_query(
+    conn: &rusqlite::Connection,
+    user: Option<&User>,
+    stream_id: &str,
+    params: &Params,
+) -> Result<StreamQuery, tide::Error> {
+    let mut query = StreamQuery::new(parse_stream(conn, stream_id)?)
+        .limit(params.get("n").and_then(|n| n.parse().ok()).unwrap_or(20))
+        .oldest_first(params.get("r") == Some("o"));
+    if let Some(user) = user {
+        query = query.user(user);
+    }
+
+    if let Some(continuation) = params.get("c").and_then(|c| c.parse().ok()) {
+        query = query.continuation(continuation);
+    }
+    if let Some(ot) = params.get("ot").and_then(|ot| ot.parse().ok()) {
+        query = query.newer_than(Utc.timestamp(ot, 0));
+    }
+    if let Some(nt) = params.get("nt").and_then(|nt| nt.parse().ok()) {
+        query = query.older_than(Utc.timestamp(nt, 0));
+    }
+    for id in params.get_all("it") {
+        query = query.include(parse_stream(conn, id)?);
+    }
+    for id in params.get_all("xt") {
+        query = query.exclude(parse_stream(conn, id)?);
+    }
+
+    Ok(query)
+}
+
+fn item_json(item: &Item, feed: Option<&Feed>, groups: &[Group], tags: &[String]) -> Value {
+    let mut categories = vec![READING_LIST.to_owned()];
+    categories.extend(groups.iter().map(label_id));
+    categories.extend(tags.iter().map(|tag| format!("{}{}", LABEL_PREFIX, tag)));
+    if item.is_read != 0 {
+        categories.push(READ.to_owned());
+    }
+    if item.is_saved != 0 {
+        categories.push(STARRED.to_owned());
+    }
+
+    let timestamp = item.created_on_time.timestamp();
+    json!({
+        "id": long_item_id(item.id),
+        "crawlTimeMsec": (timestamp * 1000).to_string(),
+        "timestampUsec": (timestamp * 1_000_000).to_string(),
+        "published": timestamp,
+        "updated": timestamp,
+        "title": item.title,
+        "author": item.author,
+        "canonical": [{ "href": item.url }],
+        "alternate": [{ "href": item.url, "type": "text/html" }],
+        "categories": categories,
+        "origin": {
+            "streamId": format!("{}{}", FEED_PREFIX, item.feed_id),
+            "title": feed.map(|feed| feed.title.as_str()).unwrap_or_default(),
+            "htmlUrl": feed.map(|feed| feed.site_url.as_str()).unwrap_or_default(),
+        },
+        "summary": { "direction": "ltr", "content": item.html },
+    })
+}
+
+fn items_json(
+    conn: &rusqlite::Connection,
+    user: Option<&User>,
+    items: &[Item],
+) -> crate::error::Result<Vec<Value>> {
+    let feeds = Feed::all(conn)?
+        .into_iter()
+        .map(|feed| (feed.id, feed))
+        .collect::<HashMap<_, _>>();
+    let groups = groups_by_feed(conn, user)?;
+    let ids = items.iter().map(|item| item.id).collect::<Vec<_>>();
+    let tags = Item::tags_multi(conn, &ids)?;
+
+    Ok(items
+        .iter()
+        .map(|item| {
+            item_json(
+                item,
+                feeds.get(&item.feed_id),
+                groups.get(&item.feed_id).map(Vec::as_slice).unwrap_or(&[]),
+                tags.get(&item.id).map(Vec::as_slice).unwrap_or(&[]),
+            )
+        })
+        .collect())
+}
+
+async fn handle_client_login(mut request: Request<State>) -> tide::Result {
+    let params = Params::from_request(&mut request).await;
+    let email = params.get("Email").unwrap_or_default();
+    let password = params.get("Passwd").unwrap_or_default();
+    log::info!("logging in as {}", email);
+
+    let state = request.state();
+    let token = if !state.requires_auth()? {
+        ANONYMOUS_TOKEN.to_owned()
+    } else if state.verify_credential(email, password) {
+        // unwrap(): the credential was just verified
+        state.reader_token().unwrap()
+    } else {
+        let conn = state.db.get()?;
+        match User::get_by_api_key(&conn, &User::api_key(email, password))? {
+            Some(user) => user.reader_token(),
+            None => {
+                return Ok(tide::Response::builder(StatusCode::Unauthorized)
+                    .body("Error=BadAuthentication\n")
+                    .build())
+            }
+        }
+    };
+    Ok(format!("SID={0}\nLSID={0}\nAuth={0}\n", token).into())
+}
+
+async fn handle_token(request: Request<State>) -> tide::Result {
+    let token = match current_user(&request) {
+        Some(user) => user.reader_token(),
+        None => request
+            .state()
+            .reader_token()
+            .unwrap_or_else(|| ANONYMOUS_TOKEN.to_owned()),
+    };
+    Ok(token.into())
+}
+
+async fn handle_subscription_list(request: Request<State>) -> tide::Result {
+    log::info!("requesting subscriptions");
+    let user = current_user(&request);
+    let (feeds, groups) = {
+        let conn = request.state().db.get()?;
+        let feeds = match user.as_ref() {
+            Some(user) => user.feeds(&conn)?,
+            None => Feed::all(&conn)?,
+        };
+        (feeds, groups_by_feed(&conn, user.as_ref())?)
+    };
+
+    let subscriptions = feeds
+        .iter()
+        .map(|feed| {
+            let categories = groups
+                .get(&feed.id)
+                .map(Vec::as_slice)
+                .unwrap_or(&[])
+                .iter()
+                .map(|group| json!({ "id": label_id(group), "label": group.title }))
+                .collect::<Vec<_>>();
+            json!({
+                "id": format!("{}{}", FEED_PREFIX, feed.id),
+                "title": feed.title,
+                "categories": categories,
+                "url": feed.url,
+                "htmlUrl": feed.site_url,
+                "iconUrl": "",
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(json!({ "subscriptions": subscriptions }).into())
+}
+
+async fn handle_tag_list(request: Request<State>) -> tide::Result {
+    log::info!("requesting tags");
+    let (groups, item_tags) = {
+        let conn = request.state().db.get()?;
+        let groups = match current_user(&request) {
+            Some(user) => user.groups(&conn)?,
+            None => Group::all(&conn)?,
+        };
+        (groups, Item::all_tags(&conn)?)
+    };
+
+    let mut tags = vec![json!({ "id": STARRED })];
+    tags.extend(
+        groups
+            .iter()
+            .map(|group| json!({ "id": label_id(group), "type": "folder" })),
+    );
+    tags.extend(
+        item_tags
+            .iter()
+            .map(|tag| json!({ "id": format!("{}{}", LABEL_PREFIX, tag), "type": "tag" })),
+    );
+
+    Ok(json!({ "tags": tags }).into())
+}
+
+async fn handle_stream_contents(mut request: Request<State>) -> tide::Result {
+    let params = Params::from_request(&mut request).await;
+    // The stream id is either appended to the path or passed as `s`.
+    let stream_id = match request
+        .url()
+        .path()
+        .strip_prefix(API_PREFIX)
+        .and_then(|path| path.strip_prefix("/stream/contents/"))
+    {
+        Some(id) if !id.is_empty() => percent_decode_str(id).decode_utf8_lossy().into_owned(),
+        _ => params.get("s").unwrap_or(READING_LIST).to_owned(),
+    };
+    log::info!("requesting stream contents of {}", stream_id);
+
+    let user = current_user(&request);
+    let conn = request.state().db.get()?;
+    let query = stream_query(&conn, user.as_ref(), &stream_id, &params)?;
+    let items = query.fetch(&conn)?;
+    let ids = items.iter().map(|item| item.id).collect::<Vec<_>>();
+
+    let mut result = json!({
+        "id": stream_id,
+        "updated": Utc::now().timestamp(),
+        "items": items_json(&conn, user.as_ref(), &items)?,
+    });
+    if let Some(continuation) = query.next_continuation(&ids) {
+        result["continuation"] = continuation.to_string().into();
+    }
+
+    Ok(result.into())
+}
+
+async fn handle_stream_item_ids(mut request: Request<State>) -> tide::Result {
+    let params = Params::from_request(&mut request).await;
+    let stream_id = params.get("s").unwrap_or(READING_LIST).to_owned();
+    log::info!("requesting stream item ids of {}", stream_id);
+
+    let user = current_user(&request);
+    let conn = request.state().db.get()?;
+    let query = stream_query(&conn, user.as_ref(), &stream_id, &params)?;
+    let ids = query.fetch_ids(&conn)?;
+
+    let mut result = json!({
+        "itemRefs": ids
+            .iter()
+            .map(|id| json!({ "id": id.to_string() }))
+            .collect::<Vec<_>>(),
+    });
+    if let Some(continuation) = query.next_continuation(&ids) {
+        result["continuation"] = continuation.to_string().into();
+    }
+
+    Ok(result.into())
+}
+
+async fn handle_stream_item_contents(mut request: Request<State>) -> tide::Result {
+    let params = Params::from_request(&mut request).await;
+    let ids = params
+        .get_all("i")
+        .filter_map(parse_item_id)
+        .collect::<Vec<_>>();
+    log::info!("requesting contents of {} items", ids.len());
+
+    let user = current_user(&request);
+    let conn = request.state().db.get()?;
+    let items = match user.as_ref() {
+        Some(user) => user.items(&conn, &ids)?,
+        None => Item::get_multi(&conn, &ids)?,
+    };
+
+    Ok(json!({
+        "id": READING_LIST,
+        "updated": Utc::now().timestamp(),
+        "items": items_json(&conn, user.as_ref(), &items)?,
+    })
+    .into())
+}
+
+async fn handle_edit_tag(mut request: Request<State>) -> tide::Result {
+    let params = Params::from_request(&mut request).await;
+    let add = params
+        .get_all("a")
+        .map(normalize_stream_id)
+        .collect::<Vec<_>>();
+    let remove = params
+        .get_all("r")
+        .map(normalize_stream_id)
+        .collect::<Vec<_>>();
+    log::info!("editing tags (add = {:?}, remove = {:?})", add, remove);
+
+    let conn = request.state().db.get()?;
+    for id in params.get_all("i") {
+        let id = match parse_item_id(id) {
+            Some(id) => id,
+            None => bail!(400, "invalid item id: {}", id),
+        };
+
+        if let Some(user) = current_user(&request) {
+            for tag in add.iter() {
+                match tag.as_str() {
+                    READ => user.set_item_read(&conn, id, true)?,
+                    KEPT_UNREAD => user.set_item_read(&conn, id, false)?,
+                    STARRED => user.set_item_saved(&conn, id, true)?,
+                    _ => (),
+                }
+            }
+            for tag in remove.iter() {
+                match tag.as_str() {
+                    READ => user.set_item_read(&conn, id, false)?,
+                    STARRED => user.set_item_saved(&conn, id, false)?,
+                    _ => (),
+                }
+            }
+            continue;
+        }
+
+        let mut item = Item::get(&conn, id)?;
+
+        for tag in add.iter() {
+            item = match tag.as_str() {
+                READ => item.read(&conn)?,
+                KEPT_UNREAD => item.unread(&conn)?,
+                STARRED => item.save(&conn)?,
+                _ => item,
+            };
+        }
+        for tag in remove.iter() {
+            item = match tag.as_str() {
+                READ => item.unread(&conn)?,
+                STARRED => item.unsave(&conn)?,
+                _ => item,
+            };
+        }
+    }
+
+    Ok("OK".into())
+}
+
+async fn handle_mark_all_as_read(mut request: Request<State>) -> tide::Result {
+    let params = Params::from_request(&mut request).await;
+    let stream_id = match params.get("s") {
+        Some(id) => id,
+        None => bail!(400, "missing stream id"),
+    };
+    // `ts` is in microseconds
+    let before = params
+        .get("ts")
+        .and_then(|ts| ts.parse::<u64>().ok())
+        .map(|ts| (ts / 1_000_000) as u32);
+    log::info!("marking {} as read (before = {:?})", stream_id, before);
+
+    let conn = request.state().db.get()?;
+    let stream = parse_stream(&conn, stream_id)?;
+    if let Some(user) = current_user(&request) {
+        match stream {
+            ItemStream::All => user.set_all_read(&conn, true, before)?,
+            ItemStream::Feed(id) => user.set_feed_read(&conn, id, true, before)?,
+            ItemStream::Group(id) => user.set_group_read(&conn, id, true, before)?,
+            ItemStream::Read | ItemStream::Saved | ItemStream::Tag(_) => {
+                bail!(400, "unsupported stream id: {}", stream_id)
+            }
+        }
+        return Ok("OK".into());
+    }
+
+    match stream {
+        ItemStream::All => Item::read_all(&conn, before)?,
+        ItemStream::Feed(id) => Feed::get(&conn, id)?.read(&conn, before)?,
+        ItemStream::Group(id) => Group::get(&conn, id)?.read(&conn, before)?,
+        ItemStream::Read | ItemStream::Saved | ItemStream::Tag(_) => {
+            bail!(400, "unsupported stream id: {}", stream_id)
+        }
+    }
+
+    Ok("OK".into())
+}
+
+fn auth(
+    mut request: Request<State>,
+    next: tide::Next<'_, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + '_>> {
+    Box::pin(async move {
+        if !request.state().requires_auth()? {
+            return Ok(next.run(request).await);
+        }
+
+        let token = match request
+            .header("Authorization")
+            .and_then(|values| values.as_str().trim().strip_prefix("GoogleLogin auth="))
+        {
+            Some(token) => token.to_owned(),
+            None => return Ok(tide::Response::new(StatusCode::Unauthorized)),
+        };
+        if request.state().reader_token().as_ref() == Some(&token) {
+            return Ok(next.run(request).await);
+        }
+
+        let user = {
+            let conn = request.state().db.get()?;
+            User::get_by_reader_token(&conn, &token)?
+        };
+        match user {
+            Some(user) => {
+                request.set_ext(user);
+                Ok(next.run(request).await)
+            }
+            None => Ok(tide::Response::new(StatusCode::Unauthorized)),
+        }
+    })
+}
+
+/// Adds the Google Reader API routes to `app`.
+pub fn mount(app: &mut tide::Server<State>) {
+    app.at("/accounts/ClientLogin")
+        .get(handle_client_login)
+        .post(handle_client_login);
+
+    let mut api = app.at(API_PREFIX);
+    api.with(auth);
+    api.at("/token").get(handle_token).post(handle_token);
+    api.at("/subscription/list").get(handle_subscription_list);
+    api.at("/tag/list").get(handle_tag_list);
+    api.at("/stream/contents")
+        .get(handle_stream_contents)
+        .post(handle_stream_contents);
+    api.at("/stream/contents/*")
+        .get(handle_stream_contents)
+        .post(handle_stream_contents);
+    api.at("/stream/items/ids")
+        .get(handle_stream_item_ids)
+        .post(handle_stream_item_ids);
+    api.at("/stream/items/contents")
+        .get(handle_stream_item_contents)
+        .post(handle_stream_item_contents);
+    api.at("/edit-tag").post(handle_edit_tag);
+    api.at("/mark-all-as-read").post(handle_mark_all_as_read);
+}
@@ -0,0 +1,562 @@
+/// JSON API under `/api/v1` for managing feeds and groups of a running server.
+///
+/// Requests are authenticated with HTTP basic authentication using the server's username and
+/// password. Users may authenticate too, but can only search the items of their own
+/// subscriptions. Errors are returned as `{"error": "..."}`.
+use chrono::{DateTime, Utc};
+use either::Either;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::TryInto;
+use std::future::Future;
+use std::pin::Pin;
+use tide::{log, Request, StatusCode};
+
+use crate::crawler::Crawler;
+use crate::error::Error;
+use crate::model::{Feed, Group, ItemSearch, ModelExt, User};
+use crate::opml;
+use crate::remote::RemoteFeed;
+use crate::state::State;
+use crate::utils::parse_date;
+
+const API_PREFIX: &'static str = "/api/v1";
+
+/// Converts "not found" database errors into 404 responses.
+fn or_not_found<T>(result: crate::error::Result<T>, what: &str) -> tide::Result<T> {
+    match result {
+        Ok(value) => Ok(value),
+        Err(Error::DatabaseError(rusqlite::Error::QueryReturnedNoRows)) => {
+            bail!(404, "{} not found", what)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn param_id(request: &Request<State>, key: &str) -> tide::Result<u32> {
+    match request.param(key).ok().and_then(|id| id.parse().ok()) {
+        Some(id) => Ok(id),
+        None => bail!(400, "invalid {}", key),
+    }
+}
+
+fn feed_json(conn: &rusqlite::Connection, feed: &Feed) -> crate::error::Result<Value> {
+    let group_ids = Group::get_by_feed(conn, feed.id)?
+        .into_iter()
+        .map(|group| group.id)
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+        "id": feed.id,
+        "title": feed.title,
+        "url": feed.url,
+        "site_url": feed.site_url,
+        "group_ids": group_ids,
+        "last_updated": feed.last_updated_on_time.to_rfc3339(),
+        "crawl_interval": feed.crawl_interval,
+        "update_items": feed.update_items,
+        "full_content": feed.full_content,
+        "next_crawl_at": feed.next_crawl_at.map(|at| at.to_rfc3339()),
+        "error_count": feed.error_count,
+        "last_error": feed.last_error,
+    }))
+}
+
+fn group_json(conn: &rusqlite::Connection, group: &Group) -> crate::error::Result<Value> {
+    let feed_ids = group
+        .get_feeds(conn)?
+        .into_iter()
+        .map(|feed| feed.id)
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+        "id": group.id,
+        "title": group.title,
+        "feed_ids": feed_ids,
+    }))
+}
+
+async fn handle_list_feeds(request: Request<State>) -> tide::Result {
+    let conn = request.state().db.get()?;
+    let feeds = Feed::all(&conn)?
+        .iter()
+        .map(|feed| feed_json(&conn, feed))
+        .collect::<crate::error::Result<Vec<_>>>()?;
+    Ok(json!({ "feeds": feeds }).into())
+}
+
+#[derive(Deserialize, Debug)]
+struct NewFeed {
+    url: String,
+    #[serde(default)]
+    group_ids: Vec<u32>,
+}
+
+/// Adds a feed. When `url` is a web page, its only advertised feed is added instead. Pages
+/// advertising several feeds are answered with `300 Multiple Choice` and the candidates.
+async fn handle_add_feed(mut request: Request<State>) -> tide::Result {
+    let body: NewFeed = request.body_json().await?;
+    log::info!("adding feed {:?}", body);
+
+    let groups = {
+        let conn = request.state().db.get()?;
+        if Feed::get_by_url(&conn, &body.url)?.is_some() {
+            bail!(409, "feed {} already exists", body.url);
+        }
+        body.group_ids
+            .iter()
+            .map(|&id| or_not_found(Group::get(&conn, id), "group"))
+            .collect::<tide::Result<Vec<_>>>()?
+    };
+
+    let remote = match RemoteFeed::try_new(&body.url).await {
+        Ok(Either::Left(remote)) => Ok(remote),
+        Ok(Either::Right(candidates)) if candidates.len() == 1 => {
+            RemoteFeed::new(&candidates[0]).await
+        }
+        Ok(Either::Right(candidates)) if candidates.is_empty() => {
+            bail!(422, "{} is not a feed", body.url)
+        }
+        Ok(Either::Right(candidates)) => {
+            return Ok(tide::Response::builder(StatusCode::MultipleChoice)
+                .body(json!({
+                    "error": format!("{} is not a feed", body.url),
+                    "candidates": candidates,
+                }))
+                .build());
+        }
+        Err(e) => Err(e),
+    };
+    let remote = match remote {
+        Ok(remote) => remote,
+        Err(e) => bail!(502, "unable to fetch {}: {}", body.url, e.describe()),
+    };
+
+    let url = remote.get_url().to_owned();
+    let title = match remote.get_title() {
+        Some(title) => title,
+        None => bail!(422, "feed {} doesn't have a title", url),
+    };
+
+    let conn = request.state().db.get()?;
+    if Feed::get_by_url(&conn, &url)?.is_some() {
+        bail!(409, "feed {} already exists", url);
+    }
+    let mut feed =
+        Feed::new(title, url.clone(), remote.get_site_url().unwrap_or(url)).insert(&conn)?;
+    for group in groups.iter() {
+        feed = group.add_feed(&conn, feed)?;
+    }
+
+    Ok(tide::Response::builder(StatusCode::Created)
+        .body(feed_json(&conn, &feed)?)
+        .build())
+}
+
+async fn handle_get_feed(request: Request<State>) -> tide::Result {
+    let id = param_id(&request, "id")?;
+    let conn = request.state().db.get()?;
+    let feed = or_not_found(Feed::get(&conn, id), "feed")?;
+    Ok(feed_json(&conn, &feed)?.into())
+}
+
+#[derive(Deserialize, Debug)]
+struct FeedUpdate {
+    title: Option<String>,
+    site_url: Option<String>,
+    /// Crawl interval (unit: minutes); `null` to follow the server
+    #[serde(default, deserialize_with = "deserialize_some")]
+    crawl_interval: Option<Option<u32>>,
+    update_items: Option<bool>,
+    full_content: Option<bool>,
+}
+
+/// Distinguishes a field set to `null` (`Some(None)`) from a missing field (`None`).
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    Deserialize::deserialize(deserializer).map(Some)
+}
+
+async fn handle_update_feed(mut request: Request<State>) -> tide::Result {
+    let id = param_id(&request, "id")?;
+    let body: FeedUpdate = request.body_json().await?;
+    log::info!("updating feed {}: {:?}", id, body);
+
+    if body.crawl_interval == Some(Some(0)) {
+        bail!(422, "crawl interval must be positive");
+    }
+
+    let conn = request.state().db.get()?;
+    let mut feed = or_not_found(Feed::get(&conn, id), "feed")?;
+    if let Some(title) = body.title {
+        feed.title = title;
+    }
+    if let Some(site_url) = body.site_url {
+        if url::Url::parse(&site_url).is_err() {
+            bail!(422, "invalid site url: {}", site_url);
+        }
+        feed.site_url = site_url;
+    }
+    feed.update(&conn)?;
+
+    if let Some(interval) = body.crawl_interval {
+        Feed::set_crawl_interval(&conn, feed.id, interval)?;
+        feed.crawl_interval = interval;
+    }
+
+    if let Some(update_items) = body.update_items {
+        Feed::set_update_items(&conn, feed.id, update_items)?;
+        feed.update_items = update_items;
+    }
+
+    if let Some(full_content) = body.full_content {
+        Feed::set_full_content(&conn, feed.id, full_content)?;
+        feed.full_content = full_content;
+    }
+
+    Ok(feed_json(&conn, &feed)?.into())
+}
+
+async fn handle_delete_feed(request: Request<State>) -> tide::Result {
+    let id = param_id(&request, "id")?;
+    log::info!("deleting feed {}", id);
+
+    let conn = request.state().db.get()?;
+    or_not_found(Feed::get(&conn, id), "feed")?.remove(&conn)?;
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
+
+async fn handle_crawl_feed(request: Request<State>) -> tide::Result {
+    let id = param_id(&request, "id")?;
+    log::info!("crawling feed {}", id);
+
+    let feed = {
+        let conn = request.state().db.get()?;
+        or_not_found(Feed::get(&conn, id), "feed")?
+    };
+
+    let crawler = Crawler::new(request.state().clone());
+    if let Err(e) = crawler.crawl_feed(feed).await {
+        bail!(502, "unable to crawl feed {}: {}", id, e.describe());
+    }
+
+    let conn = request.state().db.get()?;
+    Ok(feed_json(&conn, &Feed::get(&conn, id)?)?.into())
+}
+
+async fn handle_list_groups(request: Request<State>) -> tide::Result {
+    let conn = request.state().db.get()?;
+    let groups = Group::all(&conn)?
+        .iter()
+        .map(|group| group_json(&conn, group))
+        .collect::<crate::error::Result<Vec<_>>>()?;
+    Ok(json!({ "groups": groups }).into())
+}
+
+#[derive(Deserialize, Debug)]
+struct GroupBody {
+    title: String,
+}
+
+async fn handle_add_group(mut request: Request<State>) -> tide::Result {
+    let body: GroupBody = request.body_json().await?;
+    log::info!("adding group {}", body.title);
+
+    let conn = request.state().db.get()?;
+    if Group::get_by_name(&conn, &body.title).is_ok() {
+        bail!(409, "group {} already exists", body.title);
+    }
+    let group = Group::new(body.title).insert(&conn)?;
+
+    Ok(tide::Response::builder(StatusCode::Created)
+        .body(group_json(&conn, &group)?)
+        .build())
+}
+
+async fn handle_get_group(request: Request<State>) -> tide::Result {
+    let id = param_id(&request, "id")?;
+    let conn = request.state().db.get()?;
+    let group = or_not_found(Group::get(&conn, id), "group")?;
+    Ok(group_json(&conn, &group)?.into())
+}
+
+async fn handle_update_group(mut request: Request<State>) -> tide::Result {
+    let id = param_id(&request, "id")?;
+    let body: GroupBody = request.body_json().await?;
+    log::info!("renaming group {} to {}", id, body.title);
+
+    let conn = request.state().db.get()?;
+    let group = or_not_found(Group::get(&conn, id), "group")?;
+    match Group::get_by_name(&conn, &body.title) {
+        Ok(other) if other.id != group.id => bail!(409, "group {} already exists", body.title),
+        _ => (),
+    }
+    let group = group.rename(&conn, body.title)?;
+
+    Ok(group_json(&conn, &group)?.into())
+}
+
+async fn handle_delete_group(request: Request<State>) -> tide::Result {
+    let id = param_id(&request, "id")?;
+    log::info!("deleting group {}", id);
+
+    let conn = request.state().db.get()?;
+    or_not_found(Group::get(&conn, id), "group")?.remove(&conn)?;
+    Ok(tide::Response::new(StatusCode::NoContent))
+}
+
+async fn handle_add_group_feed(request: Request<State>) -> tide::Result {
+    let id = param_id(&request, "id")?;
+    let feed_id = param_id(&request, "feed_id")?;
+    log::info!("adding feed {} to group {}", feed_id, id);
+
+    let conn = request.state().db.get()?;
+    let group = or_not_found(Group::get(&conn, id), "group")?;
+    let feed = or_not_found(Feed::get(&conn, feed_id), "feed")?;
+    group.add_feed(&conn, feed)?;
+
+    Ok(group_json(&conn, &group)?.into())
+}
+
+async fn handle_remove_group_feed(request: Request<State>) -> tide::Result {
+    let id = param_id(&request, "id")?;
+    let feed_id = param_id(&request, "feed_id")?;
+    log::info!("removing feed {} from group {}", feed_id, id);
+
+    let conn = request.state().db.get()?;
+    let group = or_not_found(Group::get(&conn, id), "group")?;
+    let feed = or_not_found(Feed::get(&conn, feed_id), "feed")?;
+    group.remove_feed(&conn, feed)?;
+
+    Ok(group_json(&conn, &group)?.into())
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchQuery {
+    q: String,
+    feed_id: Option<u32>,
+    group_id: Option<u32>,
+    since: Option<String>,
+    until: Option<String>,
+    limit: Option<u32>,
+    offset: Option<u32>,
+}
+
+fn query_date(value: Option<&str>) -> tide::Result<Option<DateTime<Utc>>> {
+    match value {
+        Some(value) => match parse_date(value) {
+            Some(date) => Ok(Some(date)),
+            None => bail!(400, "invalid date: {}", value),
+        },
+        None => Ok(None),
+    }
+}
+
+async fn handle_search(request: Request<State>) -> tide::Result {
+    let query: SearchQuery = request.query()?;
+    log::info!("searching items: {:?}", query);
+
+    let mut search = ItemSearch::new(&query.q)
+        .limit(query.limit.unwrap_or(20).min(100))
+        .offset(query.offset.unwrap_or(0));
+    if let Some(feed_id) = query.feed_id {
+        search = search.feed_id(feed_id);
+    }
+    if let Some(group_id) = query.group_id {
+        search = search.group_id(group_id);
+    }
+    if let Some(since) = query_date(query.since.as_deref())? {
+        search = search.since(since);
+    }
+    if let Some(until) = query_date(query.until.as_deref())? {
+        search = search.until(until);
+    }
+    if let Some(user) = request.ext::<User>() {
+        search = search.user(user);
+    }
+
+    let hits = {
+        let conn = request.state().db.get()?;
+        search.fetch(&conn)?
+    };
+    let items = hits
+        .into_iter()
+        .map(|hit| {
+            json!({
+                "id": hit.item.id,
+                "feed_id": hit.item.feed_id,
+                "title": hit.item.title,
+                "author": hit.item.author,
+                "url": hit.item.url,
+                "created": hit.item.created_on_time.to_rfc3339(),
+                "is_read": hit.item.is_read != 0,
+                "is_saved": hit.item.is_saved != 0,
+                "snippet": hit.snippet,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(json!({ "items": items }).into())
+}
+
+async fn handle_export(request: Request<State>) -> tide::Result {
+    let mut output = Vec::new();
+    {
+        let conn = request.state().db.get()?;
+        opml::export(&conn, &mut output)?;
+    }
+
+    Ok(tide::Response::builder(StatusCode::Ok)
+        .body(output)
+        .content_type("text/x-opml; charset=utf-8")
+        .build())
+}
+
+#[derive(Deserialize, Debug)]
+struct ImportQuery {
+    nesting: Option<String>,
+}
+
+/// Imports the OPML document in the request body. Nested outlines are mapped to groups according
+/// to `?nesting=`, as in `lares feed import`.
+async fn handle_import(mut request: Request<State>) -> tide::Result {
+    let query: ImportQuery = request.query()?;
+    let nesting = match query.nesting.as_deref().unwrap_or("flatten").parse() {
+        Ok(nesting) => nesting,
+        Err(e) => bail!(400, "{}", e),
+    };
+
+    let body = request.body_bytes().await?;
+    let imports = match opml::from_bytes(&body, nesting) {
+        Ok(imports) => imports,
+        Err(e) => bail!(422, "invalid OPML: {}", e.describe()),
+    };
+    log::info!("importing {} feeds", imports.len());
+
+    let total = imports.len();
+    let imports = opml::normalize(imports).await;
+    let invalid = total - imports.len();
+
+    let conn = request.state().db.get()?;
+    let summary = opml::import(&conn, imports)?;
+
+    Ok(json!({
+        "added": summary.added,
+        "existing": summary.existing,
+        "invalid": invalid,
+    })
+    .into())
+}
+
+fn auth(
+    mut request: Request<State>,
+    next: tide::Next<'_, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + '_>> {
+    Box::pin(async move {
+        if !request.state().requires_auth()? {
+            return Ok(next.run(request).await);
+        }
+
+        let credential = request
+            .header("Authorization")
+            .and_then(|values| values.as_str().trim().strip_prefix("Basic "))
+            .and_then(|encoded| base64::decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        let (username, password) = match credential.as_ref().and_then(|c| c.split_once(':')) {
+            Some(credential) => credential,
+            None => return Ok(unauthorized()),
+        };
+        if request.state().verify_credential(username, password) {
+            return Ok(next.run(request).await);
+        }
+
+        let user = {
+            let conn = request.state().db.get()?;
+            User::get_by_api_key(&conn, &User::api_key(username, password))?
+        };
+        match user {
+            Some(user) => {
+                request.set_ext(user);
+                Ok(next.run(request).await)
+            }
+            None => Ok(unauthorized()),
+        }
+    })
+}
+
+fn unauthorized() -> tide::Response {
+    let mut response = tide::Response::new(StatusCode::Unauthorized);
+    response.insert_header("WWW-Authenticate", "Basic realm=\"lares\"");
+    response.set_body(json!({ "error": "unauthorized" }));
+    response
+}
+
+/// Refuses users, who share feeds and groups with everyone else and so may not change them.
+fn admin_only(
+    request: Request<State>,
+    next: tide::Next<'_, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + '_>> {
+    Box::pin(async move {
+        if request.ext::<User>().is_some() {
+            bail!(403, "only the administrator can manage feeds and groups");
+        }
+        Ok(next.run(request).await)
+    })
+}
+
+/// Renders errors as JSON bodies.
+fn json_errors(
+    request: Request<State>,
+    next: tide::Next<'_, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + '_>> {
+    Box::pin(async move {
+        let mut response = next.run(request).await;
+        if let Some(message) = response.error().map(|e| e.to_string()) {
+            response.set_body(json!({ "error": message }));
+        }
+        Ok(response)
+    })
+}
+
+/// Adds the JSON API routes to `app`.
+pub fn mount(app: &mut tide::Server<State>) {
+    let mut api = app.at(API_PREFIX);
+    api.with(json_errors).with(auth);
+
+    api.at("/feeds")
+        .with(admin_only)
+        .get(handle_list_feeds)
+        .post(handle_add_feed);
+    api.at("/feeds/:id")
+        .with(admin_only)
+        .get(handle_get_feed)
+        .patch(handle_update_feed)
+        .delete(handle_delete_feed);
+    api.at("/feeds/:id/crawl")
+        .with(admin_only)
+        .post(handle_crawl_feed);
+
+    api.at("/groups")
+        .with(admin_only)
+        .get(handle_list_groups)
+        .post(handle_add_group);
+    api.at("/groups/:id")
+        .with(admin_only)
+        .get(handle_get_group)
+        .patch(handle_update_group)
+        .delete(handle_delete_group);
+    api.at("/groups/:id/feeds/:feed_id")
+        .with(admin_only)
+        .put(handle_add_group_feed)
+        .delete(handle_remove_group_feed);
+
+    api.at("/items/search").get(handle_search);
+
+    api.at("/opml")
+        .with(admin_only)
+        .get(handle_export)
+        .post(handle_import);
+}
@@ -0,0 +1,324 @@
+/// Sanitizes HTML of items before they are stored.
+///
+/// Only an allowlist of tags and attributes is kept. Scripts and other embedded documents are
+/// dropped along with their content, other tags are unwrapped. URLs are resolved against a base
+/// URL, and dropped unless they are `http`, `https` or `mailto` URLs.
+use std::fmt::Write;
+use url::Url;
+
+use crate::utils::decode_entities;
+
+/// Tags that are kept, along with their allowed attributes.
+const ALLOWED_TAGS: &[(&'static str, &[&'static str])] = &[
+    ("a", &["href", "title"]),
+    ("abbr", &["title"]),
+    ("audio", &["src", "controls"]),
+    ("b", &[]),
+    ("blockquote", &["cite"]),
+    ("br", &[]),
+    ("caption", &[]),
+    ("cite", &[]),
+    ("code", &[]),
+    ("dd", &[]),
+    ("del", &[]),
+    ("details", &[]),
+    ("div", &[]),
+    ("dl", &[]),
+    ("dt", &[]),
+    ("em", &[]),
+    ("figcaption", &[]),
+    ("figure", &[]),
+    ("h1", &[]),
+    ("h2", &[]),
+    ("h3", &[]),
+    ("h4", &[]),
+    ("h5", &[]),
+    ("h6", &[]),
+    ("hr", &[]),
+    ("i", &[]),
+    ("img", &["src", "srcset", "alt", "title", "width", "height"]),
+    ("ins", &[]),
+    ("kbd", &[]),
+    ("li", &[]),
+    ("mark", &[]),
+    ("ol", &["start"]),
+    ("p", &[]),
+    ("picture", &[]),
+    ("pre", &[]),
+    ("q", &["cite"]),
+    ("s", &[]),
+    ("small", &[]),
+    ("source", &["src", "srcset", "type", "media"]),
+    ("span", &[]),
+    ("strong", &[]),
+    ("sub", &[]),
+    ("summary", &[]),
+    ("sup", &[]),
+    ("table", &[]),
+    ("tbody", &[]),
+    ("td", &["colspan", "rowspan"]),
+    ("tfoot", &[]),
+    ("th", &["colspan", "rowspan"]),
+    ("thead", &[]),
+    ("time", &["datetime"]),
+    ("tr", &[]),
+    ("u", &[]),
+    ("ul", &[]),
+    ("video", &["src", "poster", "controls", "width", "height"]),
+    ("wbr", &[]),
+];
+
+/// Tags that have no content and no end tag.
+const VOID_TAGS: &[&'static str] = &["br", "hr", "img", "source", "wbr"];
+
+/// Tags that are dropped along with their content.
+const DROPPED_TAGS: &[&'static str] = &[
+    "script", "style", "template", "iframe", "object", "svg", "math", "head", "title",
+];
+
+/// Attributes that contain a URL.
+const URL_ATTRIBUTES: &[&'static str] = &["href", "src", "cite", "poster"];
+
+pub struct Tag {
+    pub name: String,
+    pub is_end: bool,
+    pub attributes: Vec<(String, String)>,
+}
+
+impl Tag {
+    /// Parses the tag at the start of `s`, returning it with the number of bytes consumed.
+    /// Returns `None` if `s` does not start with a tag.
+    pub fn parse(s: &str) -> Option<(Self, usize)> {
+        let bytes = s.as_bytes();
+        let is_end = bytes.get(1) == Some(&b'/');
+        let mut pos = if is_end { 2 } else { 1 };
+
+        if !bytes.get(pos)?.is_ascii_alphabetic() {
+            return None;
+        }
+        let name_len = s[pos..]
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(s.len() - pos);
+        let name = s[pos..pos + name_len].to_ascii_lowercase();
+        pos += name_len;
+
+        let skip_whitespace = |mut pos: usize| {
+            while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            pos
+        };
+
+        let mut attributes = Vec::new();
+        loop {
+            pos = skip_whitespace(pos);
+            match bytes.get(pos) {
+                None => break,
+                Some(b'>') => {
+                    pos += 1;
+                    break;
+                }
+                Some(b'/') | Some(b'=') => {
+                    pos += 1;
+                    continue;
+                }
+                _ => (),
+            }
+
+            let key_len = s[pos..]
+                .find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '>' || c == '/')
+                .unwrap_or(s.len() - pos);
+            let key = s[pos..pos + key_len].to_ascii_lowercase();
+            pos = skip_whitespace(pos + key_len);
+
+            let mut value = String::new();
+            if bytes.get(pos) == Some(&b'=') {
+                pos = skip_whitespace(pos + 1);
+                match bytes.get(pos) {
+                    Some(&quote) if quote == b'"' || quote == b'\'' => {
+                        let end = s[pos + 1..]
+                            .find(quote as char)
+                            .map(|end| pos + 1 + end)
+                            .unwrap_or(s.len());
+                        value = decode_entities(&s[pos + 1..end]);
+                        pos = (end + 1).min(s.len());
+                    }
+                    _ => {
+                        let len = s[pos..]
+                            .find(|c: char| c.is_ascii_whitespace() || c == '>')
+                            .unwrap_or(s.len() - pos);
+                        value = decode_entities(&s[pos..pos + len]);
+                        pos += len;
+                    }
+                }
+            }
+            attributes.push((key, value));
+        }
+
+        Some((
+            Tag {
+                name,
+                is_end,
+                attributes,
+            },
+            pos,
+        ))
+    }
+
+    pub fn attribute(&self, key: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Images of at most 1x1 pixels are used to track readers.
+    fn is_tracking_pixel(&self) -> bool {
+        let size = |key| {
+            self.attribute(key)
+                .and_then(|value| value.trim().trim_end_matches("px").parse::<u32>().ok())
+        };
+        match (size("width"), size("height")) {
+            (Some(width), Some(height)) => width <= 1 && height <= 1,
+            _ => false,
+        }
+    }
+}
+
+/// Resolves `url` against `base`, and checks that its scheme is safe to follow.
+fn resolve_url(url: &str, base: Option<&Url>) -> Option<String> {
+    let url = match base {
+        Some(base) => base.join(url.trim()).ok()?,
+        None => Url::parse(url.trim()).ok()?,
+    };
+    match url.scheme() {
+        "http" | "https" | "mailto" => Some(url.into()),
+        _ => None,
+    }
+}
+
+/// Resolves the URLs of a `srcset` attribute (`image.png 1x, image@2x.png 2x`).
+fn resolve_srcset(srcset: &str, base: Option<&Url>) -> Option<String> {
+    let candidates = srcset
+        .split(',')
+        .filter_map(|candidate| {
+            let mut parts = candidate.split_whitespace();
+            let url = resolve_url(parts.next()?, base)?;
+            Some(
+                std::iter::once(url.as_str())
+                    .chain(parts)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if candidates.is_empty() {
+        None
+    } else {
+        Some(candidates.join(", "))
+    }
+}
+
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+}
+
+/// Sanitizes an HTML fragment, resolving relative URLs against `base`. Unclosed tags are closed
+/// at the end of the fragment.
+pub fn sanitize(html: &str, base: Option<&Url>) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut open: Vec<&'static str> = Vec::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        // comments, doctypes and processing instructions
+        if rest.starts_with("<!--") {
+            rest = rest.find("-->").map(|end| &rest[end + 3..]).unwrap_or("");
+            continue;
+        } else if rest.starts_with("<!") || rest.starts_with("<?") {
+            rest = rest.find('>').map(|end| &rest[end + 1..]).unwrap_or("");
+            continue;
+        }
+
+        let tag = match Tag::parse(rest) {
+            Some((tag, len)) => {
+                rest = &rest[len..];
+                tag
+            }
+            None => {
+                output.push_str("&lt;");
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        if !tag.is_end && DROPPED_TAGS.contains(&tag.name.as_str()) {
+            let close = format!("</{}", tag.name);
+            rest = match rest.to_ascii_lowercase().find(&close) {
+                Some(pos) => &rest[pos..],
+                None => "",
+            };
+            continue;
+        }
+
+        let (name, allowed) = match ALLOWED_TAGS.iter().find(|(name, _)| *name == tag.name) {
+            Some(&(name, allowed)) => (name, allowed),
+            None => continue,
+        };
+
+        if tag.is_end {
+            if let Some(pos) = open.iter().rposition(|open| *open == name) {
+                for name in open.drain(pos..).rev() {
+                    let _ = write!(output, "</{}>", name);
+                }
+            }
+            continue;
+        }
+
+        let attributes = tag
+            .attributes
+            .iter()
+            .filter(|(key, _)| allowed.contains(&key.as_str()))
+            .filter_map(|(key, value)| {
+                let value = if key == "srcset" {
+                    resolve_srcset(value, base)
+                } else if URL_ATTRIBUTES.contains(&key.as_str()) {
+                    resolve_url(value, base)
+                } else {
+                    Some(value.to_owned())
+                };
+                value.map(|value| (key.as_str(), value))
+            })
+            .collect::<Vec<_>>();
+
+        if name == "img"
+            && (!attributes.iter().any(|(key, _)| *key == "src") || tag.is_tracking_pixel())
+        {
+            continue;
+        }
+
+        output.push('<');
+        output.push_str(name);
+        for (key, value) in attributes.iter() {
+            let _ = write!(output, " {}=\"{}\"", key, escape_attribute(value));
+        }
+        output.push('>');
+
+        if !VOID_TAGS.contains(&name) {
+            open.push(name);
+        }
+    }
+    output.push_str(rest);
+
+    for name in open.into_iter().rev() {
+        let _ = write!(output, "</{}>", name);
+    }
+    output
+}
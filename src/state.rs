@@ -1,11 +1,18 @@
-use md5::{Digest, Md5};
 use r2d2_sqlite::SqliteConnectionManager;
 use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config;
+use crate::error::Result;
+use crate::model::{ModelExt, User};
+use crate::utils::md5_hex;
 
 #[derive(Clone, Debug)]
 pub struct State {
     pub db: Arc<r2d2::Pool<SqliteConnectionManager>>,
     pub credential: Option<String>,
+    /// Settings of the crawler, also followed by crawls requested through the CLI or the APIs
+    pub crawler: config::Crawler,
 }
 
 impl State {
@@ -13,13 +20,48 @@ impl State {
         State {
             db: Arc::new(db),
             credential: None,
+            crawler: config::Crawler::default(),
         }
     }
 
+    pub fn set_crawler(mut self, crawler: config::Crawler) -> Self {
+        self.crawler = crawler;
+        self
+    }
+
+    /// Delay between two requests to the same host.
+    pub fn host_delay(&self) -> Duration {
+        Duration::from_millis(self.crawler.per_host_delay)
+    }
+
     pub fn set_credential(mut self, username: String, password: String) -> Self {
-        let mut hasher = Md5::new();
-        hasher.update(format!("{}:{}", username, password));
-        self.credential = Some(format!("{:2x}", hasher.finalize()));
+        self.credential = Some(md5_hex(&format!("{}:{}", username, password)));
         self
     }
+
+    /// Checks a username and password against the configured credential. Always fails when no
+    /// credential is configured.
+    pub fn verify_credential(&self, username: &str, password: &str) -> bool {
+        match &self.credential {
+            Some(credential) => *credential == md5_hex(&format!("{}:{}", username, password)),
+            None => false,
+        }
+    }
+
+    /// Whether clients must authenticate, which is the case once a credential is configured or a
+    /// user exists.
+    pub fn requires_auth(&self) -> Result<bool> {
+        if self.credential.is_some() {
+            return Ok(true);
+        }
+        let conn = self.db.get()?;
+        Ok(User::count(&conn)? > 0)
+    }
+
+    /// Token handed out by the Google Reader API's `ClientLogin`, derived from the credential.
+    pub fn reader_token(&self) -> Option<String> {
+        self.credential
+            .as_ref()
+            .map(|credential| md5_hex(&format!("reader:{}", credential)))
+    }
 }
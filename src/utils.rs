@@ -1,5 +1,7 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
+use md5::{Digest, Md5};
 use serde::Serializer;
+use url::Url;
 
 pub fn comma_join_vec<T: IntoIterator<Item = U>, U: ToString>(items: T) -> String {
     items
@@ -9,9 +11,147 @@ pub fn comma_join_vec<T: IntoIterator<Item = U>, U: ToString>(items: T) -> Strin
         .join(",")
 }
 
+pub fn md5_hex(input: &str) -> String {
+    let mut hasher = Md5::new();
+    hasher.update(input);
+    format!("{:2x}", hasher.finalize())
+}
+
+/// Hash of the title and content of an item, used to detect duplicates and changes.
+pub fn content_hash(title: &str, html: &str) -> String {
+    md5_hex(&format!("{}\0{}", title, html))
+}
+
+/// Query parameters added for tracking visitors, besides `utm_*`.
+const TRACKING_PARAMS: &[&'static str] = &[
+    "fbclid", "gclid", "dclid", "msclkid", "yclid", "igshid", "mc_cid", "mc_eid", "_hsenc", "_hsmi",
+];
+
+/// Normalizes a URL for comparison by dropping tracking parameters (`utm_source`, `fbclid`, ...).
+/// Other parameters are kept as is. Strings that are not absolute URLs are returned unchanged.
+pub fn normalize_url(s: &str) -> String {
+    let mut url = match Url::parse(s.trim()) {
+        Ok(url) => url,
+        Err(_) => return s.to_owned(),
+    };
+
+    if let Some(query) = url.query() {
+        let kept = query
+            .split('&')
+            .filter(|pair| {
+                let key = pair.split('=').next().unwrap_or_default();
+                !(key.is_empty() || key.starts_with("utm_") || TRACKING_PARAMS.contains(&key))
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        if kept.is_empty() {
+            url.set_query(None);
+        } else {
+            url.set_query(Some(&kept));
+        }
+    }
+    if url.fragment() == Some("") {
+        url.set_fragment(None);
+    }
+
+    url.into()
+}
+
 pub fn serialize_timestamp<S: Serializer>(
     val: &DateTime<Utc>,
     serializer: S,
 ) -> Result<S::Ok, S::Error> {
     serializer.serialize_i64(val.timestamp())
 }
+
+/// Parses a date (`2020-01-31`, midnight UTC) or an RFC 3339 timestamp.
+pub fn parse_date(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Some(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+    Some(DateTime::from_utc(date.and_hms(0, 0, 0), Utc))
+}
+
+/// Extracts the text of an HTML fragment by dropping tags, comments, scripts and styles, and
+/// decoding character references. Whitespace is collapsed.
+pub fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = rest.find(['<', '&']) {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if rest.starts_with('&') {
+            let (decoded, len) = decode_entity(rest);
+            text.push_str(&decoded);
+            rest = &rest[len..];
+        } else if rest.starts_with("<!--") {
+            rest = rest.find("-->").map(|end| &rest[end + 3..]).unwrap_or("");
+        } else {
+            let end = rest.find('>').map(|end| end + 1).unwrap_or(rest.len());
+            let tag = rest[1..end].trim_end_matches('>').to_ascii_lowercase();
+            rest = &rest[end..];
+
+            let name = tag.split(|c: char| c.is_whitespace() || c == '/').next();
+            if let Some(name @ "script") | Some(name @ "style") = name {
+                let close = format!("</{}", name);
+                rest = match rest.to_ascii_lowercase().find(&close) {
+                    Some(pos) => &rest[pos..],
+                    None => "",
+                };
+            }
+            // tags separate words
+            text.push(' ');
+        }
+    }
+    text.push_str(rest);
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Decodes character references, e.g. in attribute values.
+pub fn decode_entities(s: &str) -> String {
+    let mut decoded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let (entity, len) = decode_entity(&rest[start..]);
+        decoded.push_str(&entity);
+        rest = &rest[start + len..];
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Decodes the character reference at the start of `s`, returning the text and the number of
+/// bytes consumed. Unknown references are kept as is.
+fn decode_entity(s: &str) -> (String, usize) {
+    let end = match s[1..].find(|c: char| !c.is_ascii_alphanumeric() && c != '#') {
+        Some(end) if s[1 + end..].starts_with(';') => end + 1,
+        _ => return ("&".to_owned(), 1),
+    };
+
+    let name = &s[1..end];
+    let decoded = match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => name
+            .strip_prefix("#x")
+            .or_else(|| name.strip_prefix("#X"))
+            .map(|hex| u32::from_str_radix(hex, 16))
+            .or_else(|| name.strip_prefix('#').map(|dec| dec.parse()))
+            .and_then(|code| code.ok())
+            .and_then(std::char::from_u32),
+    };
+
+    match decoded {
+        Some(c) => (c.to_string(), end + 1),
+        None => (s[..end + 1].to_owned(), end + 1),
+    }
+}
@@ -1,4 +1,5 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use assert_cmd::cargo::CommandCargoExt;
 use assert_cmd::Command;
 use async_std::task::{self, JoinHandle};
 use chrono::TimeZone;
@@ -6,9 +7,13 @@ use lares::model::ModelExt;
 use r2d2_sqlite::SqliteConnectionManager;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use serde_json::{json, Value};
 use std::io::Write;
-use std::net::TcpListener;
+use std::net::{TcpListener, TcpStream};
 use std::path::PathBuf;
+use std::process::{Child, Stdio};
+use std::time::Duration;
+use surf::http::Method;
 use tempfile::NamedTempFile;
 
 fn get_available_port() -> Result<u16> {
@@ -50,7 +55,7 @@ impl Lares {
         let addr = format!("127.0.0.1:{}", port);
 
         let mut app = tide::new();
-        app.at("/").serve_dir(get_fixtures_dir())?;
+        app.at("/*").serve_dir(get_fixtures_dir())?;
 
         let web = task::spawn({
             let addr = addr.clone();
@@ -61,6 +66,103 @@ impl Lares {
 
         Ok((format!("http://{}", addr), web))
     }
+
+    fn run_server(&self, args: &[&str]) -> Result<Server> {
+        let port = get_available_port()?;
+        let child = std::process::Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+            .env("LARES_DATABASE", self.db.path())
+            .args(&["server", "--port", &port.to_string()])
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        let addr = format!("127.0.0.1:{}", port);
+        for _ in 0..100 {
+            if TcpStream::connect(&addr).is_ok() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        Ok(Server {
+            child,
+            url: format!("http://{}", addr),
+        })
+    }
+}
+
+/// A `lares server` running in the background, stopped when dropped.
+struct Server {
+    child: Child,
+    url: String,
+}
+
+impl Server {
+    /// Sends a request to the Google Reader API, authenticated with `token`. Requests without a
+    /// body are sent with `GET`.
+    fn reader(&self, token: &str, path: &str, body: &str) -> Result<(u16, String)> {
+        let url = format!("{}/reader/api/0{}", self.url, path);
+        let request = if body.is_empty() {
+            surf::get(url)
+        } else {
+            surf::post(url).body_string(body.to_owned())
+        };
+        send(request.set_header("Authorization", format!("GoogleLogin auth={}", token)))
+    }
+
+    /// Builds a request to the REST API, authenticated with `credential` (`username:password`).
+    fn rest(
+        &self,
+        credential: &str,
+        method: surf::http::Method,
+        path: &str,
+    ) -> Result<surf::Request<impl surf::middleware::HttpClient>> {
+        let url = format!("{}/api/v1{}", self.url, path).parse()?;
+        Ok(surf::Request::new(method, url).set_header(
+            "Authorization",
+            format!("Basic {}", base64::encode(credential)),
+        ))
+    }
+
+    /// Logs in to the Google Reader API, and returns the token.
+    fn reader_login(&self, email: &str, password: &str) -> Result<String> {
+        let url = format!("{}/accounts/ClientLogin", self.url);
+        let body = format!("Email={}&Passwd={}", email, password);
+        let (status, body) = send(surf::post(url).body_string(body))?;
+        assert_eq!(status, 200);
+        body.lines()
+            .find_map(|line| line.strip_prefix("Auth="))
+            .map(|token| token.to_owned())
+            .ok_or_else(|| anyhow!("no token in {}", body))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Sends a request, and returns the status and body of the response.
+fn send<C: surf::middleware::HttpClient>(request: surf::Request<C>) -> Result<(u16, String)> {
+    task::block_on(async {
+        let mut response = request.await.map_err(|e| anyhow!(e))?;
+        let body = response.body_string().await.map_err(|e| anyhow!(e))?;
+        Ok((response.status().as_u16(), body))
+    })
+}
+
+/// Short ids of the items returned by `stream/items/ids`.
+fn item_refs(body: &str) -> Result<Vec<String>> {
+    let result: Value = serde_json::from_str(body)?;
+    Ok(result["itemRefs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["id"].as_str().unwrap().to_owned())
+        .collect())
 }
 
 #[test]
@@ -148,8 +250,8 @@ fn test_crawl() -> Result<()> {
         items[9].url,
         "https://blog.rust-lang.org/2020/08/03/Rust-1.45.2.html"
     );
-    assert_eq!(items[9].is_read, false);
-    assert_eq!(items[9].is_saved, false);
+    assert_eq!(items[9].is_read, 0);
+    assert_eq!(items[9].is_saved, 0);
     assert_eq!(items[9].author, "The Rust Release Team");
     assert_eq!(
         items[9].created_on_time,
@@ -159,6 +261,38 @@ fn test_crawl() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_crawl_failure() -> Result<()> {
+    let lares = Lares::new()?;
+    let (addr, _server) = lares.run_fixture_server()?;
+
+    let rust = format!("{}/rust.xml", addr);
+    let _ = lares.cmd()?.args(&["feed", "add", &rust]).output()?;
+
+    // the feed disappears from the server
+    let conn = lares.pool.get()?;
+    conn.execute(
+        "UPDATE `feed` SET `url` = ?1 WHERE `id` = 1",
+        &[&format!("{}/missing.xml", addr)],
+    )?;
+
+    lares.cmd()?.args(&["feed", "crawl", "1"]).unwrap_err();
+    lares.cmd()?.args(&["feed", "crawl", "1"]).unwrap_err();
+
+    let failing = lares::model::Feed::all_failing(&conn)?;
+    assert_eq!(failing.len(), 1);
+    assert_eq!(failing[0].error_count, 2);
+    assert!(failing[0].last_error.is_some());
+    assert!(failing[0].next_crawl_at.unwrap() > chrono::Utc::now());
+
+    // a successful crawl clears the error state
+    conn.execute("UPDATE `feed` SET `url` = ?1 WHERE `id` = 1", &[&rust])?;
+    lares.cmd()?.args(&["feed", "crawl", "1"]).unwrap();
+    assert!(lares::model::Feed::all_failing(&conn)?.is_empty());
+
+    Ok(())
+}
+
 #[test]
 fn test_import() -> Result<()> {
     let lares = Lares::new()?;
@@ -223,3 +357,548 @@ fn test_import_flat() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_export() -> Result<()> {
+    let lares = Lares::new()?;
+    let opml = get_fixtures_dir().join("normal.opml");
+    lares.cmd()?.args(&["feed", "import"]).arg(&opml).unwrap();
+    lares::model::Feed::new(
+        "Feed 5 Title".to_owned(),
+        "https://example.com/feed5".to_owned(),
+        "http://example.com/site5".to_owned(),
+    )
+    .insert(&*lares.pool.get()?)?;
+
+    let exported = tempfile::Builder::new().suffix(".opml").tempfile()?;
+    lares
+        .cmd()?
+        .args(&["feed", "export"])
+        .arg(exported.path())
+        .unwrap();
+
+    // round trip
+    let imported = Lares::new()?;
+    imported
+        .cmd()?
+        .args(&["feed", "import"])
+        .arg(exported.path())
+        .unwrap();
+
+    let conn = imported.pool.get()?;
+    let groups = lares::model::Group::all(&conn)?;
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].title, "Group 1 Title");
+    assert_eq!(groups[1].title, "Group 2 Text");
+    assert_eq!(groups[0].get_feeds(&conn)?.len(), 2);
+
+    let feeds = lares::model::Feed::all(&conn)?;
+    assert_eq!(feeds.len(), 5);
+    assert_eq!(feeds[0].title, "Feed 1 Title");
+    assert_eq!(feeds[0].url, "https://example.com/feed1");
+    assert_eq!(feeds[0].site_url, "http://example.com/site1");
+    assert_eq!(feeds[4].title, "Feed 5 Title");
+    assert_eq!(feeds[4].is_spark, 1);
+
+    // stdout
+    let result = lares.cmd()?.args(&["feed", "export"]).output()?;
+    let stdout = String::from_utf8(result.stdout)?;
+    assert!(stdout.contains(r#"xmlUrl="https://example.com/feed4""#));
+
+    Ok(())
+}
+
+#[test]
+fn test_import_nested() -> Result<()> {
+    let lares = Lares::new()?;
+    let opml = get_fixtures_dir().join("nested.opml");
+    lares.cmd()?.args(&["feed", "import"]).arg(&opml).unwrap();
+
+    let conn = lares.pool.get()?;
+    let groups = lares::model::Group::all(&conn)?;
+    assert_eq!(groups.len(), 3);
+    assert_eq!(groups[0].title, "Tech");
+    assert_eq!(groups[1].title, "Tech/Rust");
+    assert_eq!(groups[2].title, "News");
+
+    let feeds = lares::model::Feed::all(&conn)?;
+    assert_eq!(feeds.len(), 3);
+    assert_eq!(feeds[1].title, "Feed 2 Title");
+    assert_eq!(feeds[2].is_spark, 1);
+    assert_eq!(groups[1].get_feeds(&conn)?[0].id, feeds[1].id);
+    assert_eq!(groups[2].get_feeds(&conn)?[0].id, feeds[1].id);
+
+    // importing again does not duplicate feeds
+    lares
+        .cmd()?
+        .args(&["feed", "import", "--nesting", "leaf"])
+        .arg(&opml)
+        .unwrap();
+    let groups = lares::model::Group::all(&conn)?;
+    assert_eq!(groups.len(), 4);
+    assert_eq!(groups[3].title, "Rust");
+    assert_eq!(lares::model::Feed::all(&conn)?.len(), 3);
+
+    Ok(())
+}
+
+#[test]
+fn test_import_dry_run() -> Result<()> {
+    let lares = Lares::new()?;
+    let normal = get_fixtures_dir().join("normal.opml");
+    lares.cmd()?.args(&["feed", "import"]).arg(&normal).unwrap();
+
+    let nested = get_fixtures_dir().join("nested.opml");
+    let result = lares
+        .cmd()?
+        .args(&["feed", "import", "--dry-run"])
+        .arg(&nested)
+        .output()?;
+    let stdout = String::from_utf8(result.stdout)?;
+    assert!(stdout.contains("0 to add, 1 to skip, 2 to regroup, 0 invalid."));
+
+    let conn = lares.pool.get()?;
+    assert_eq!(lares::model::Group::all(&conn)?.len(), 2);
+    assert_eq!(lares::model::Feed::all(&conn)?.len(), 4);
+
+    // a missing database is not created
+    let dir = tempfile::tempdir()?;
+    let missing = dir.path().join("missing.db");
+    let result = Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+        .env("LARES_DATABASE", &missing)
+        .args(&["feed", "import", "--dry-run"])
+        .arg(&nested)
+        .output()?;
+    let stdout = String::from_utf8(result.stdout)?;
+    assert!(stdout.contains("3 to add, 0 to skip, 0 to regroup, 0 invalid."));
+    assert!(!missing.exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_reader_api() -> Result<()> {
+    let lares = Lares::new()?;
+    let (addr, _fixtures) = lares.run_fixture_server()?;
+    let rust = format!("{}/rust.xml", addr);
+    lares.cmd()?.args(&["group", "add", "Rust"]).unwrap();
+    lares
+        .cmd()?
+        .args(&["feed", "add", &rust, "--group", "Rust"])
+        .unwrap();
+    lares.cmd()?.args(&["feed", "crawl", "1"]).unwrap();
+
+    let server = lares.run_server(&["--username", "admin", "--password", "secret"])?;
+
+    // ClientLogin
+    let login = format!("{}/accounts/ClientLogin", server.url);
+    let (status, body) =
+        send(surf::post(&login).body_string("Email=admin&Passwd=wrong".to_owned()))?;
+    assert_eq!(status, 401);
+    assert_eq!(body, "Error=BadAuthentication\n");
+    let token = server.reader_login("admin", "secret")?;
+
+    // token auth
+    let (status, _) = send(surf::get(format!(
+        "{}/reader/api/0/subscription/list",
+        server.url
+    )))?;
+    assert_eq!(status, 401);
+    assert_eq!(server.reader("invalid", "/subscription/list", "")?.0, 401);
+    assert_eq!(server.reader(&token, "/token", "")?, (200, token.clone()));
+
+    let (status, body) = server.reader(&token, "/subscription/list", "")?;
+    assert_eq!(status, 200);
+    let subscriptions: Value = serde_json::from_str(&body)?;
+    assert_eq!(subscriptions["subscriptions"][0]["id"], "feed/1");
+    assert_eq!(subscriptions["subscriptions"][0]["url"], rust.as_str());
+    assert_eq!(
+        subscriptions["subscriptions"][0]["categories"][0]["id"],
+        "user/-/label/Rust"
+    );
+
+    // stream/contents, continued page by page
+    let mut ids = Vec::new();
+    let mut continuation = None;
+    for page in 0..3 {
+        let mut path = "/stream/contents/feed%2F1?n=4".to_owned();
+        if let Some(continuation) = continuation.take() {
+            path = format!("{}&c={}", path, continuation);
+        }
+        let (status, body) = server.reader(&token, &path, "")?;
+        assert_eq!(status, 200);
+        let result: Value = serde_json::from_str(&body)?;
+        assert_eq!(result["id"], "feed/1");
+        let items = result["items"].as_array().unwrap();
+        assert_eq!(items.len(), if page < 2 { 4 } else { 2 });
+        for item in items {
+            assert_eq!(item["origin"]["streamId"], "feed/1");
+            ids.push(item["id"].as_str().unwrap().to_owned());
+        }
+        continuation = result["continuation"].as_str().map(|c| c.to_owned());
+        assert_eq!(continuation.is_some(), page < 2);
+    }
+    let expected = (1..=10)
+        .rev()
+        .map(|id| format!("tag:google.com,2005:reader/item/{:016x}", id))
+        .collect::<Vec<_>>();
+    assert_eq!(ids, expected);
+
+    // edit-tag, and it/xt filters
+    let reading_list = "/stream/items/ids?n=100&s=user/-/state/com.google/reading-list";
+    let (status, body) = server.reader(
+        &token,
+        "/edit-tag",
+        "i=10&a=user/-/state/com.google/read&a=user/-/state/com.google/starred",
+    )?;
+    assert_eq!((status, body.as_str()), (200, "OK"));
+    let (_, body) = server.reader(
+        &token,
+        &format!("{}&xt=user/-/state/com.google/read", reading_list),
+        "",
+    )?;
+    let unread = item_refs(&body)?;
+    assert_eq!(unread.len(), 9);
+    assert!(!unread.contains(&"10".to_owned()));
+    let (_, body) = server.reader(
+        &token,
+        &format!("{}&it=user/-/state/com.google/starred", reading_list),
+        "",
+    )?;
+    assert_eq!(item_refs(&body)?, vec!["10"]);
+
+    let (_, body) = server.reader(
+        &token,
+        "/stream/contents?s=user/-/state/com.google/starred",
+        "",
+    )?;
+    let result: Value = serde_json::from_str(&body)?;
+    let categories = result["items"][0]["categories"].as_array().unwrap();
+    assert!(categories.contains(&Value::from("user/-/state/com.google/read")));
+    assert!(categories.contains(&Value::from("user/-/label/Rust")));
+
+    let (status, _) = server.reader(
+        &token,
+        "/edit-tag",
+        "i=tag:google.com,2005:reader/item/000000000000000a&r=user/-/state/com.google/read",
+    )?;
+    assert_eq!(status, 200);
+    let (_, body) = server.reader(
+        &token,
+        &format!("{}&it=user/-/state/com.google/read", reading_list),
+        "",
+    )?;
+    assert!(item_refs(&body)?.is_empty());
+
+    // mark-all-as-read
+    let (status, body) = server.reader(&token, "/mark-all-as-read", "s=user/-/label/Rust")?;
+    assert_eq!((status, body.as_str()), (200, "OK"));
+    let (_, body) = server.reader(
+        &token,
+        &format!("{}&xt=user/-/state/com.google/read", reading_list),
+        "",
+    )?;
+    assert!(item_refs(&body)?.is_empty());
+    let (status, _) = server.reader(&token, "/mark-all-as-read", "s=feed/invalid")?;
+    assert_eq!(status, 400);
+
+    Ok(())
+}
+
+#[test]
+fn test_rest_api() -> Result<()> {
+    let lares = Lares::new()?;
+    let (addr, _fixtures) = lares.run_fixture_server()?;
+    let rust = format!("{}/rust.xml", addr);
+    let server = lares.run_server(&["--username", "admin", "--password", "secret"])?;
+    let admin = "admin:secret";
+
+    // authentication
+    let mut response = task::block_on(surf::get(format!("{}/api/v1/feeds", server.url)))
+        .map_err(|e| anyhow!(e))?;
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        response.header("WWW-Authenticate"),
+        Some("Basic realm=\"lares\"")
+    );
+    let body: Value = task::block_on(response.body_json())?;
+    assert_eq!(body["error"], "unauthorized");
+    assert_eq!(
+        send(server.rest("admin:wrong", Method::GET, "/feeds")?)?.0,
+        401
+    );
+
+    // groups
+    let (status, body) = send(
+        server
+            .rest(admin, Method::POST, "/groups")?
+            .body_json(&json!({ "title": "News" }))?,
+    )?;
+    assert_eq!(status, 201);
+    let group: Value = serde_json::from_str(&body)?;
+    assert_eq!(group["id"], 1);
+    let (status, body) = send(
+        server
+            .rest(admin, Method::POST, "/groups")?
+            .body_json(&json!({ "title": "News" }))?,
+    )?;
+    assert_eq!(status, 409);
+    assert!(body.contains("already exists"));
+    assert_eq!(send(server.rest(admin, Method::GET, "/groups/2")?)?.0, 404);
+    send(
+        server
+            .rest(admin, Method::POST, "/groups")?
+            .body_json(&json!({ "title": "Other" }))?,
+    )?;
+    let (status, body) = send(
+        server
+            .rest(admin, Method::PATCH, "/groups/1")?
+            .body_json(&json!({ "title": "World" }))?,
+    )?;
+    assert_eq!(status, 200);
+    assert_eq!(serde_json::from_str::<Value>(&body)?["title"], "World");
+    let (status, _) = send(
+        server
+            .rest(admin, Method::PATCH, "/groups/2")?
+            .body_json(&json!({ "title": "World" }))?,
+    )?;
+    assert_eq!(status, 409);
+
+    // feeds
+    let (status, body) = send(
+        server
+            .rest(admin, Method::POST, "/feeds")?
+            .body_json(&json!({ "url": format!("{}/feeds.html", addr) }))?,
+    )?;
+    assert_eq!(status, 300);
+    let candidates = serde_json::from_str::<Value>(&body)?["candidates"].clone();
+    assert_eq!(
+        candidates,
+        json!(["http://example.com/rss.xml", "http://example.com/atom.xml"])
+    );
+    let (status, _) = send(
+        server
+            .rest(admin, Method::POST, "/feeds")?
+            .body_json(&json!({ "url": format!("{}/page.html", addr) }))?,
+    )?;
+    assert_eq!(status, 422);
+    let (status, _) = send(
+        server
+            .rest(admin, Method::POST, "/feeds")?
+            .body_json(&json!({ "url": rust, "group_ids": [3] }))?,
+    )?;
+    assert_eq!(status, 404);
+    let (status, body) = send(
+        server
+            .rest(admin, Method::POST, "/feeds")?
+            .body_json(&json!({ "url": rust, "group_ids": [1] }))?,
+    )?;
+    assert_eq!(status, 201);
+    let feed: Value = serde_json::from_str(&body)?;
+    assert_eq!(feed["id"], 1);
+    assert_eq!(feed["group_ids"], json!([1]));
+    let (status, _) = send(
+        server
+            .rest(admin, Method::POST, "/feeds")?
+            .body_json(&json!({ "url": rust }))?,
+    )?;
+    assert_eq!(status, 409);
+    assert_eq!(send(server.rest(admin, Method::GET, "/feeds/2")?)?.0, 404);
+    assert_eq!(send(server.rest(admin, Method::GET, "/feeds/abc")?)?.0, 400);
+
+    let (status, _) = send(
+        server
+            .rest(admin, Method::PATCH, "/feeds/1")?
+            .body_json(&json!({ "crawl_interval": 0 }))?,
+    )?;
+    assert_eq!(status, 422);
+    let (_, body) = send(
+        server
+            .rest(admin, Method::PATCH, "/feeds/1")?
+            .body_json(&json!({ "crawl_interval": 60 }))?,
+    )?;
+    assert_eq!(serde_json::from_str::<Value>(&body)?["crawl_interval"], 60);
+    // missing fields are left alone, `null` resets the crawl interval
+    let (_, body) = send(
+        server
+            .rest(admin, Method::PATCH, "/feeds/1")?
+            .body_json(&json!({ "title": "Rust" }))?,
+    )?;
+    let feed: Value = serde_json::from_str(&body)?;
+    assert_eq!(
+        (&feed["title"], &feed["crawl_interval"]),
+        (&json!("Rust"), &json!(60))
+    );
+    let (status, body) = send(
+        server
+            .rest(admin, Method::PATCH, "/feeds/1")?
+            .body_json(&json!({ "crawl_interval": null }))?,
+    )?;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?["crawl_interval"],
+        Value::Null
+    );
+
+    // crawling schedules the next crawl
+    let (status, body) = send(server.rest(admin, Method::POST, "/feeds/1/crawl")?)?;
+    assert_eq!(status, 200);
+    let feed: Value = serde_json::from_str(&body)?;
+    assert_eq!(feed["error_count"], 0);
+    assert!(feed["next_crawl_at"].is_string());
+    let (status, body) = send(server.rest(admin, Method::GET, "/items/search?q=rust")?)?;
+    assert_eq!(status, 200);
+    assert!(!serde_json::from_str::<Value>(&body)?["items"]
+        .as_array()
+        .unwrap()
+        .is_empty());
+
+    let (_, body) = send(server.rest(admin, Method::PUT, "/groups/2/feeds/1")?)?;
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?["feed_ids"],
+        json!([1])
+    );
+    let (_, body) = send(server.rest(admin, Method::DELETE, "/groups/2/feeds/1")?)?;
+    assert_eq!(serde_json::from_str::<Value>(&body)?["feed_ids"], json!([]));
+
+    // OPML round trip
+    let (status, opml) = send(server.rest(admin, Method::GET, "/opml")?)?;
+    assert_eq!(status, 200);
+    assert!(opml.contains(&format!(r#"xmlUrl="{}""#, rust)));
+    let imported = Lares::new()?;
+    let other = imported.run_server(&[])?;
+    let (status, body) = send(
+        other
+            .rest(admin, Method::POST, "/opml?nesting=flatten")?
+            .body_string(opml),
+    )?;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?,
+        json!({ "added": 1, "existing": 0, "invalid": 0 })
+    );
+    let (_, body) = send(other.rest(admin, Method::GET, "/feeds")?)?;
+    let feeds = serde_json::from_str::<Value>(&body)?["feeds"].clone();
+    assert_eq!(feeds[0]["url"], rust.as_str());
+    assert_eq!(feeds[0]["title"], "Rust");
+    let (_, body) = send(other.rest(admin, Method::GET, "/groups")?)?;
+    let groups = serde_json::from_str::<Value>(&body)?["groups"].clone();
+    assert_eq!(groups[0]["title"], "World");
+    assert_eq!(groups[0]["feed_ids"], json!([1]));
+
+    // deleting
+    assert_eq!(
+        send(server.rest(admin, Method::DELETE, "/feeds/1")?)?.0,
+        204
+    );
+    assert_eq!(send(server.rest(admin, Method::GET, "/feeds/1")?)?.0, 404);
+    assert_eq!(
+        send(server.rest(admin, Method::DELETE, "/groups/1")?)?.0,
+        204
+    );
+    assert_eq!(
+        send(server.rest(admin, Method::DELETE, "/groups/1")?)?.0,
+        404
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_users_api() -> Result<()> {
+    let lares = Lares::new()?;
+    let (addr, _fixtures) = lares.run_fixture_server()?;
+    let rust = format!("{}/rust.xml", addr);
+    lares.cmd()?.args(&["feed", "add", &rust]).unwrap();
+    lares.cmd()?.args(&["feed", "crawl", "1"]).unwrap();
+    for (username, password) in &[("alice", "x"), ("bob", "y")] {
+        lares
+            .cmd()?
+            .args(&["user", "add", username, "--password", password])
+            .unwrap();
+        lares
+            .cmd()?
+            .args(&["user", "subscribe", username, "1"])
+            .unwrap();
+    }
+    lares
+        .cmd()?
+        .args(&["user", "add", "carol", "--password", "z"])
+        .unwrap();
+
+    // users require authentication even without an administrator
+    let server = lares.run_server(&[])?;
+    let (status, _) = send(surf::get(format!(
+        "{}/reader/api/0/subscription/list",
+        server.url
+    )))?;
+    assert_eq!(status, 401);
+    assert_eq!(
+        send(surf::get(format!("{}/api/v1/feeds", server.url)))?.0,
+        401
+    );
+    let login = format!("{}/accounts/ClientLogin", server.url);
+    let (status, _) = send(surf::post(&login).body_string("Email=alice&Passwd=y".to_owned()))?;
+    assert_eq!(status, 401);
+
+    let alice = server.reader_login("alice", "x")?;
+    let bob = server.reader_login("bob", "y")?;
+    let carol = server.reader_login("carol", "z")?;
+    assert_ne!(alice, bob);
+
+    // subscriptions
+    let (_, body) = server.reader(&alice, "/subscription/list", "")?;
+    let subscriptions: Value = serde_json::from_str(&body)?;
+    assert_eq!(subscriptions["subscriptions"][0]["url"], rust.as_str());
+    let (_, body) = server.reader(&carol, "/subscription/list", "")?;
+    let subscriptions: Value = serde_json::from_str(&body)?;
+    assert_eq!(subscriptions["subscriptions"], json!([]));
+    let reading_list = "/stream/items/ids?n=100&s=user/-/state/com.google/reading-list";
+    let (_, body) = server.reader(&carol, reading_list, "")?;
+    assert!(item_refs(&body)?.is_empty());
+
+    // read and starred state is kept per user
+    let unread = format!("{}&xt=user/-/state/com.google/read", reading_list);
+    let starred = format!("{}&it=user/-/state/com.google/starred", reading_list);
+    server.reader(
+        &alice,
+        "/edit-tag",
+        "i=10&a=user/-/state/com.google/read&a=user/-/state/com.google/starred",
+    )?;
+    let (_, body) = server.reader(&alice, &unread, "")?;
+    assert_eq!(item_refs(&body)?.len(), 9);
+    let (_, body) = server.reader(&alice, &starred, "")?;
+    assert_eq!(item_refs(&body)?, vec!["10"]);
+    let (_, body) = server.reader(&bob, &unread, "")?;
+    assert_eq!(item_refs(&body)?.len(), 10);
+    let (_, body) = server.reader(&bob, &starred, "")?;
+    assert!(item_refs(&body)?.is_empty());
+
+    let (status, _) = server.reader(&bob, "/mark-all-as-read", "s=feed/1")?;
+    assert_eq!(status, 200);
+    let (_, body) = server.reader(&bob, &unread, "")?;
+    assert!(item_refs(&body)?.is_empty());
+    let (_, body) = server.reader(&alice, &unread, "")?;
+    assert_eq!(item_refs(&body)?.len(), 9);
+
+    // users may search, but not manage feeds
+    let (status, body) = send(server.rest("alice:x", Method::GET, "/feeds")?)?;
+    assert_eq!(status, 403);
+    assert!(body.contains("only the administrator"));
+    assert_eq!(
+        send(server.rest("alice:y", Method::GET, "/items/search?q=rust")?)?.0,
+        401
+    );
+    let (status, body) = send(server.rest("alice:x", Method::GET, "/items/search?q=rust")?)?;
+    assert_eq!(status, 200);
+    let items = serde_json::from_str::<Value>(&body)?["items"].clone();
+    let items = items.as_array().unwrap();
+    assert!(items.iter().any(|item| item["id"] == 10));
+    for item in items {
+        assert_eq!(item["is_read"], item["id"] == 10);
+    }
+    let (_, body) = send(server.rest("carol:z", Method::GET, "/items/search?q=rust")?)?;
+    assert_eq!(serde_json::from_str::<Value>(&body)?["items"], json!([]));
+
+    Ok(())
+}